@@ -0,0 +1,46 @@
+//! Records the commit the server is built from, and when, for `/version`,
+//! and generates the gRPC service's code if it is built with it.
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    let built = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", built);
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+
+/// Generate the gRPC service and its messages. The results are read from
+/// the REST API's JSON, so they can be deserialised.
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    let protoc = protoc_bin_vendored::protoc_bin_path()
+        .expect("No protoc is available for this platform.");
+    std::env::set_var("PROTOC", protoc);
+    let results = [
+        "BattleResult", "Attack", "DefendingUnit", "FollowUps",
+        "OptimiseResult", "Order", "NamedAttacker", "SearchStats", "UnitType"
+    ];
+    let mut config = tonic_prost_build::configure().build_client(false);
+    for message in results.iter() {
+        config = config.type_attribute(
+            format!("polycalc.v1.{}", message),
+            "#[derive(serde::Deserialize)] #[serde(default)]"
+        );
+    }
+    config.compile_protos(&["proto/polycalc.proto"], &["proto"])
+        .expect("Could not compile the gRPC service.");
+}
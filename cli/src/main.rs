@@ -0,0 +1,287 @@
+//! A command line tool for the calculator, for working out a battle without
+//! running the server, such as:
+//!
+//! ```text
+//! polycalc battle --attacker warrior --attacker archer:7 --defender rider
+//! ```
+//!
+//! Results are printed as a table, or as JSON as the API would give them.
+use std::{env, fs, iter, process};
+
+use polycalc_core::{calc, units};
+use serde_json::json;
+
+
+const USAGE: &str = "\
+Usage: polycalc battle [options]
+       polycalc optim [options] [--minimise]
+
+Options:
+  -a, --attacker UNIT  An attacker, in the order they attack. May be given
+                       more than once.
+  -d, --defender UNIT  The unit being attacked.
+      --adjacent UNIT  A unit next to the defender, hit by splash damage.
+                       May be given more than once.
+      --minimise       Find the fewest attackers which kill the defender,
+                       rather than the best order for all of them.
+      --units FILE     Read the unit data from FILE, rather than the file
+                       in UNITS_FILE, units.json or the copy built in.
+      --json           Print the result as JSON, as the API gives it.
+  -h, --help           Show this message.
+
+Units are written as in links: a unit ID, then optionally its health and
+flags, separated by colons, such as archer:7 or rider:walled:veteran.";
+
+
+/// What to calculate.
+#[derive(PartialEq)]
+enum Mode {
+    Battle,
+    Optim
+}
+
+
+/// The options given on the command line.
+struct Command {
+    mode: Mode,
+    attackers: Vec<String>,
+    defender: Option<String>,
+    adjacent: Vec<String>,
+    minimise: bool,
+    units: Option<String>,
+    json: bool
+}
+
+impl Command {
+    /// Read the command line arguments, not including the program name.
+    fn parse(
+        mut args: impl Iterator<Item = String>
+    ) -> Result<Command, String> {
+        let mode = match args.next().as_deref() {
+            Option::Some("battle") => Mode::Battle,
+            Option::Some("optim") => Mode::Optim,
+            Option::Some("-h") | Option::Some("--help") => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            Option::Some(other) => {
+                return Err(format!("Unknown command '{}'.", other))
+            },
+            Option::None => return Err(String::from("No command given."))
+        };
+        let mut command = Command {
+            mode,
+            attackers: vec![],
+            defender: Option::None,
+            adjacent: vec![],
+            minimise: false,
+            units: Option::None,
+            json: false
+        };
+        while let Option::Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(
+                || format!("{} needs a value.", arg)
+            );
+            match arg.as_str() {
+                "-a" | "--attacker" => command.attackers.push(value()?),
+                "-d" | "--defender" => {
+                    command.defender = Option::Some(value()?)
+                },
+                "--adjacent" => command.adjacent.push(value()?),
+                "--units" => command.units = Option::Some(value()?),
+                "--minimise" if command.mode == Mode::Optim => {
+                    command.minimise = true
+                },
+                "--json" => command.json = true,
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                },
+                _ => return Err(format!("Unknown option '{}'.", arg))
+            }
+        }
+        if command.attackers.is_empty() {
+            return Err(String::from("No attackers given."));
+        }
+        if command.defender.is_none() {
+            return Err(String::from("No defender given."));
+        }
+        Ok(command)
+    }
+
+    /// The battle described by the options.
+    fn battle(&self) -> Result<calc::BattleInput, String> {
+        let read = |spec: &String| calc::UnitInput::from_spec(spec)
+            .map_err(|error| message(error.to_json()));
+        let defender = self.defender.as_ref()
+            .ok_or_else(|| String::from("No defender given."))?;
+        Ok(calc::BattleInput {
+            attackers: self.attackers.iter()
+                .map(read)
+                .collect::<Result<_, _>>()?,
+            defender: read(defender)?,
+            defenders: vec![],
+            adjacent: self.adjacent.iter()
+                .map(read)
+                .collect::<Result<_, _>>()?
+        })
+    }
+}
+
+
+/// The message from an error as the API gives it.
+fn message(error: serde_json::Value) -> String {
+    match error["error"].as_str() {
+        Option::Some(message) => String::from(message),
+        Option::None => error.to_string()
+    }
+}
+
+
+/// Give the calculator its unit data: from a file if one was given, or
+/// else from the file in `UNITS_FILE` or `units.json` if there is one, or
+/// else from the copy built in. If `UNITS_URL` is set, the data is
+/// fetched from there instead, as the server does.
+fn load_units(path: Option<&str>) -> Result<(), String> {
+    let raw = match path {
+        Option::Some(path) => fs::read_to_string(path).map_err(
+            |error| format!("Could not read {}: {}", path, error)
+        )?,
+        Option::None if env::var("UNITS_URL").is_ok() => return Ok(()),
+        Option::None if units::units_file().exists() => {
+            let path = units::units_file();
+            fs::read_to_string(&path).map_err(|error| format!(
+                "Could not read {}: {}", path.display(), error
+            ))?
+        },
+        Option::None => String::from(units::EMBEDDED_UNITS)
+    };
+    units::provide_units(&raw)
+}
+
+
+/// Check that a battle makes sense, and set it up.
+fn read_battle(
+    battle: &calc::BattleInput
+) -> Result<calc::BattleState, String> {
+    battle.check_flags().map_err(|error| message(error.to_json()))?;
+    battle.to_state().map_err(|error| message(error.to_json()))
+}
+
+
+/// A unit's health as `health/max`, or `dead`.
+fn health(unit: &units::Unit) -> String {
+    if unit.health <= 0 {
+        String::from("dead")
+    } else {
+        format!("{}/{}", unit.health, unit.max_health)
+    }
+}
+
+
+/// Print the outcome of a battle as a table of attackers and defenders.
+fn print_table(state: &calc::BattleState) {
+    println!(
+        "{:<20} {:>7} {:>6} {:>6}", "Attacker", "Health", "Dealt", "Taken"
+    );
+    for attacker in state.attackers.iter() {
+        println!(
+            "{:<20} {:>7} {:>6} {:>6}", attacker.display_name,
+            health(attacker), attacker.damage_dealt, attacker.retaliation_taken
+        );
+    }
+    println!();
+    println!("{:<20} {:>7} {:>6}", "Defender", "Health", "Taken");
+    let defenders = iter::once(&state.defender)
+        .chain(state.defenders.iter())
+        .chain(state.adjacent.iter());
+    for defender in defenders {
+        println!(
+            "{:<20} {:>7} {:>6}", defender.display_name, health(defender),
+            defender.damage_taken
+        );
+    }
+}
+
+
+/// Print a result as indented JSON.
+fn print_json(value: &serde_json::Value) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(_) => println!("{}", value)
+    }
+}
+
+
+/// Calculate a battle in the order given, as `POST /battle`.
+fn run_battle(command: &Command) -> Result<(), String> {
+    let mut state = read_battle(&command.battle()?)?;
+    calc::battle_many(&mut state);
+    if command.json {
+        print_json(&state.to_json());
+    } else {
+        print_table(&state);
+    }
+    Ok(())
+}
+
+
+/// Find the best order of attack, or the fewest attackers, as
+/// `POST /optim`.
+fn run_optim(command: &Command) -> Result<(), String> {
+    let battle = command.battle()?;
+    let state = read_battle(&battle)?;
+    let mut input = serde_json::to_value(&battle)
+        .map_err(|error| error.to_string())?;
+    input["minimise"] = json!(command.minimise);
+    let input: calc::OptimInput = serde_json::from_value(input)
+        .map_err(|error| error.to_string())?;
+    let result = input.optimise(state, Option::None, Option::None);
+    if command.json {
+        print_json(&result);
+        return Ok(());
+    }
+    let order: Vec<usize> = match result["order"].as_array() {
+        Option::Some(order) => order.iter()
+            .filter_map(|idx| idx.as_u64())
+            .map(|idx| idx as usize)
+            .collect(),
+        Option::None => {
+            println!("No attackers given can kill the defender.");
+            return Ok(());
+        }
+    };
+    // The result only has the attackers' health, so the battle is fought
+    // again in the best order to show it in full.
+    let best = calc::BattleInput {
+        attackers: order.iter()
+            .map(|idx| battle.attackers[*idx].clone())
+            .collect(),
+        ..battle
+    };
+    let mut state = read_battle(&best)?;
+    calc::battle_many(&mut state);
+    print_table(&state);
+    Ok(())
+}
+
+
+fn main() {
+    let command = match Command::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            process::exit(2);
+        }
+    };
+    let result = load_units(command.units.as_deref()).and_then(|_| {
+        match command.mode {
+            Mode::Battle => run_battle(&command),
+            Mode::Optim => run_optim(&command)
+        }
+    });
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
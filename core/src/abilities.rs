@@ -0,0 +1,209 @@
+//! The abilities unit types can have, and how the calculator interprets
+//! each of them. Units are given their abilities from this table, so it is
+//! always in line with how battles are calculated.
+use serde_json::Value;
+
+use crate::units::{self, Unit};
+
+
+/// The proportion by which a unit with the `attack_aura` ability increases
+/// the attack of the units that attack after it.
+const ATTACK_AURA: f32 = 0.5;
+
+
+/// An ability which changes how a unit fights.
+pub struct Ability {
+    pub name: &'static str,
+    pub description: &'static str,
+    // Short, stable names for what the ability does, for programs to read.
+    pub effects: &'static [&'static str],
+    // Give a unit the ability.
+    apply: fn(&mut Unit)
+}
+
+
+/// Every ability which matters in battle.
+pub const ABILITIES: &[Ability] = &[
+    Ability {
+        name: "attack_aura",
+        description: "Increases the attack of the units which attack after \
+            it.",
+        effects: &["boosts_later_attackers"],
+        apply: |unit| unit.attack_aura = ATTACK_AURA
+    },
+    Ability {
+        name: "boost",
+        description: "Boosts another attacker, when asked to, instead of \
+            attacking.",
+        effects: &["boosts_ally"],
+        apply: |unit| unit.can_boost = true
+    },
+    Ability {
+        name: "carry",
+        description: "Is a vessel, taking its health from the unit it \
+            carries. Can't be promoted.",
+        effects: &["carries_unit", "no_promotion"],
+        apply: |unit| unit.can_carry = true
+    },
+    Ability {
+        name: "convert",
+        description: "Converts the units it attacks to its side, so they \
+            don't retaliate.",
+        effects: &["converts_target", "suppresses_retaliation"],
+        apply: |unit| unit.can_convert = true
+    },
+    Ability {
+        name: "crush",
+        description: "Ignores the defence bonus from city walls.",
+        effects: &["ignores_walls"],
+        apply: |unit| unit.ignores_walls = true
+    },
+    Ability {
+        name: "drain",
+        description: "Heals by the damage it deals.",
+        effects: &["heals_by_damage"],
+        apply: |unit| unit.drain = true
+    },
+    Ability {
+        name: "explode",
+        description: "Explodes, when asked to, damaging the defender and the \
+            units next to it, instead of attacking.",
+        effects: &["explodes", "damages_adjacent"],
+        apply: |unit| unit.can_explode = true
+    },
+    Ability {
+        name: "fortify",
+        description: "Gets a defence bonus in a city, or a bigger one behind \
+            walls.",
+        effects: &["city_defence_bonus"],
+        apply: |unit| unit.can_fortify = true
+    },
+    Ability {
+        name: "freeze",
+        description: "Freezes the units it attacks, so they don't \
+            retaliate.",
+        effects: &["freezes_target", "suppresses_retaliation"],
+        apply: |unit| unit.can_freeze = true
+    },
+    Ability {
+        name: "freeze_area",
+        description: "Freezes the units it attacks and the units next to \
+            them, so they don't retaliate.",
+        effects: &[
+            "freezes_target", "freezes_adjacent", "suppresses_retaliation"
+        ],
+        apply: |unit| unit.freeze_area = true
+    },
+    Ability {
+        name: "heal",
+        description: "Heals another attacker, when asked to, instead of \
+            attacking.",
+        effects: &["heals_ally"],
+        apply: |unit| unit.can_heal = true
+    },
+    Ability {
+        name: "infiltrate",
+        description: "Ignores the defence bonus from city walls.",
+        effects: &["ignores_walls"],
+        apply: |unit| unit.ignores_walls = true
+    },
+    Ability {
+        name: "persist",
+        description: "Keeps attacking the next defender after each kill.",
+        effects: &["attacks_after_kill"],
+        apply: |unit| unit.persist = true
+    },
+    Ability {
+        name: "poison",
+        description: "Poisons the units it attacks, reducing their \
+            defence.",
+        effects: &["poisons_target", "reduces_defence"],
+        apply: |unit| unit.can_poison = true
+    },
+    Ability {
+        name: "splash",
+        description: "Also damages the units next to the defender.",
+        effects: &["damages_adjacent"],
+        apply: |unit| unit.splash = true
+    },
+    Ability {
+        name: "stiff",
+        description: "Never retaliates.",
+        effects: &["never_retaliates"],
+        apply: |unit| unit.stiff = true
+    },
+    Ability {
+        name: "surprise",
+        description: "Is never retaliated against.",
+        effects: &["suppresses_retaliation"],
+        apply: |unit| unit.surprise = true
+    },
+    Ability {
+        name: "tentacles",
+        description: "Hits melee attackers before they attack it.",
+        effects: &["hits_melee_attackers_first"],
+        apply: |unit| unit.tentacles = true
+    }
+];
+
+
+/// Look up an ability which matters in battle by name.
+pub fn find_ability(name: &str) -> Option<&'static Ability> {
+    ABILITIES.iter().find(|ability| ability.name == name)
+}
+
+
+/// Give a unit the abilities which matter in battle from a list of names.
+/// Other abilities are ignored.
+pub fn apply_abilities(unit: &mut Unit, names: &[String]) {
+    for name in names.iter() {
+        if let Option::Some(ability) = find_ability(name) {
+            (ability.apply)(unit);
+        }
+    }
+}
+
+
+/// Every ability used by a unit type or known to the calculator, in
+/// alphabetical order, with how the calculator interprets it and the unit
+/// types which have it.
+pub fn list_abilities(list: &units::UnitTypeList) -> Value {
+    let mut names: Vec<&str> = ABILITIES.iter()
+        .map(|ability| ability.name)
+        .collect();
+    for unit_type in list.units.iter() {
+        for name in unit_type.abilities() {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+    }
+    names.sort_unstable();
+    let mut abilities = vec![];
+    for name in names {
+        let units: Vec<&String> = list.units.iter()
+            .filter(|unit_type| unit_type.abilities().iter().any(
+                |ability| ability == name
+            ))
+            .map(|unit_type| unit_type.id())
+            .collect();
+        let mut ability = match find_ability(name) {
+            Option::Some(ability) => json!({
+                "name": ability.name,
+                "description": ability.description,
+                "effects": ability.effects,
+                "affects_battles": true
+            }),
+            Option::None => json!({
+                "name": name,
+                "description": "Doesn't affect battles, so is ignored by \
+                    the calculator.",
+                "effects": [],
+                "affects_battles": false
+            })
+        };
+        ability["units"] = json!(units);
+        abilities.push(ability);
+    }
+    json!(abilities)
+}
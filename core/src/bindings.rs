@@ -0,0 +1,76 @@
+//! The calculator as functions from JSON to JSON, for bindings to other
+//! languages, such as WebAssembly for browsers, which pass strings more
+//! easily than Rust types. Inputs are as in the API's request bodies, and
+//! results as in its responses. Errors are JSON too, as
+//! `{"error": message, ...details}`.
+use crate::calc;
+use crate::units;
+
+
+/// An error as JSON, with only a message.
+fn error(message: impl ToString) -> String {
+    json!({"error": message.to_string()}).to_string()
+}
+
+
+/// Read an input, checking that its flags and targets make sense.
+fn read_battle(
+    battle: &calc::BattleInput
+) -> Result<calc::BattleState, String> {
+    battle.check_flags().map_err(|error| error.to_json().to_string())?;
+    if let Option::Some(idx) = battle.invalid_target() {
+        return Err(json!({
+            "error": format!("No defender with index {}.", idx),
+            "target": idx
+        }).to_string());
+    }
+    battle.to_state().map_err(|error| error.to_json().to_string())
+}
+
+
+/// Calculate a battle, as `POST /battle`.
+pub fn battle(input: &str) -> Result<String, String> {
+    let input: calc::ExplainInput = serde_json::from_str(input)
+        .map_err(error)?;
+    let mut state = read_battle(&input.battle)?;
+    let events = if input.explain {
+        Option::Some(calc::explain_battle(&mut state))
+    } else {
+        calc::battle_many(&mut state);
+        Option::None
+    };
+    let mut response = state.to_json();
+    if let Option::Some(events) = events {
+        response["events"] = json!(events);
+    }
+    Ok(response.to_string())
+}
+
+
+/// Find the best order of attack, or the fewest attackers, as
+/// `POST /optim`. There is no limit on the number of attackers, so callers
+/// should set `max_ms` or use the heuristic mode for big battles.
+pub fn optimise(input: &str) -> Result<String, String> {
+    let input: calc::OptimInput = serde_json::from_str(input)
+        .map_err(error)?;
+    let state = read_battle(&input.battle)?;
+    if let Option::Some(idx) = input.invalid_attacker() {
+        return Err(json!({
+            "error": format!("No attacker with index {}.", idx),
+            "attacker": idx
+        }).to_string());
+    }
+    let result = input.optimise(state, Option::None, Option::None);
+    Ok(result.to_string())
+}
+
+
+/// Look up a unit type by ID or alias, as `GET /units/<id>`.
+pub fn unit(id: &str) -> Result<String, String> {
+    match units::UNIT_LIST.find_unit_type(id) {
+        Option::Some(unit_type) => Ok(json!(unit_type).to_string()),
+        Option::None => {
+            Err(calc::UnknownUnit(String::from(id)).to_json().to_string())
+        }
+    }
+}
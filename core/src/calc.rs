@@ -0,0 +1,2706 @@
+//! Calculations of battles between units.
+extern crate serde;
+
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+// `std::time::Instant` panics in browsers, so the page's clock is used.
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+use crate::rules::{Rounding, Ruleset};
+use crate::units;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+
+/// Flags for a unit, either as a bit field or as named booleans.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FlagsInput {
+    Bits(u8),
+    Named(units::UnitFlags)
+}
+
+impl Default for FlagsInput {
+    fn default() -> FlagsInput {
+        FlagsInput::Bits(0)
+    }
+}
+
+impl FlagsInput {
+    pub fn to_flags(&self) -> units::UnitFlags {
+        match self {
+            FlagsInput::Bits(flags) => units::UnitFlags::from_bits(*flags),
+            FlagsInput::Named(flags) => *flags
+        }
+    }
+}
+
+
+/// An error for a unit ID which does not match any unit type.
+#[derive(Debug)]
+pub struct UnknownUnit(pub String);
+
+impl UnknownUnit {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": format!("Unknown unit ID '{}'.", self.0),
+            "unit": self.0
+        })
+    }
+}
+
+
+/// An error for a flag used on a side of the battle it has no meaning for.
+#[derive(Debug)]
+pub struct MisplacedFlag {
+    pub flag: &'static str,
+    pub side: &'static str
+}
+
+impl MisplacedFlag {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": format!(
+                "The '{}' flag can't be used on {}.", self.flag, self.side
+            ),
+            "flag": self.flag
+        })
+    }
+}
+
+
+/// An error for a unit in the short form for links with a part which is
+/// neither a health nor a flag.
+#[derive(Debug)]
+pub struct InvalidSpec {
+    pub spec: String,
+    pub part: String
+}
+
+impl InvalidSpec {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": format!(
+                "'{}' in '{}' is neither a health nor a flag.",
+                self.part, self.spec
+            ),
+            "unit": self.spec
+        })
+    }
+}
+
+
+/// The lowest health a unit can be given, since it must be alive.
+pub const MIN_HEALTH: i32 = 1;
+
+
+/// The unit type used in place of unknown units, unless another is given.
+pub const DEFAULT_UNIT: &str = "warrior";
+
+
+/// The unit type carried by a vessel, unless another is given.
+pub const DEFAULT_CARRIED: &str = "warrior";
+
+
+/// The most orders of attack to list at once.
+pub const MAX_TOP_ORDERS: usize = 100;
+
+
+/// How many partial orders of attack the heuristic search keeps at each step.
+const BEAM_WIDTH: usize = 16;
+
+
+/// How much health a unit with heal restores.
+const HEAL_AMOUNT: i32 = 4;
+
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UnitInput {
+    pub unit: String,
+    // Rounded to whole HP, since that is all the game uses.
+    #[serde(default)]
+    pub health: Option<f32>,
+    #[serde(default)]
+    pub flags: FlagsInput,
+    // For an attacker with persist: the units to attack after each kill.
+    #[serde(default)]
+    pub follow_up: Vec<UnitInput>,
+    // For an attacker: the index of the defender it attacks.
+    #[serde(default)]
+    pub target: usize,
+    // For an attacker with heal: the index of the attacker to heal instead
+    // of attacking.
+    #[serde(default)]
+    pub heal: Option<usize>,
+    // For an attacker with explode: whether to explode instead of attacking.
+    #[serde(default)]
+    pub explode: bool,
+    // For an attacker with boost: the index of the attacker to boost instead
+    // of attacking.
+    #[serde(default)]
+    pub boost: Option<usize>,
+    // For a defender: the city it is in, if any.
+    #[serde(default)]
+    pub city: units::City,
+    // For a defender: the indices of the adjacent units next to it. The
+    // first defender is next to all of them unless this is given, and the
+    // others are next to none.
+    #[serde(default)]
+    pub adjacent: Option<Vec<usize>>,
+    // For a vessel: the ID of the land unit it carries.
+    #[serde(default)]
+    pub carrying: Option<String>,
+    // How many units the unit has already killed, towards promotion.
+    #[serde(default)]
+    pub kills: u8,
+    // For a defender: the terrain it is on, and the techs its tribe knows,
+    // which together decide its defence bonus.
+    #[serde(default)]
+    pub terrain: units::Terrain,
+    #[serde(default)]
+    pub techs: Vec<String>
+}
+
+impl UnitInput {
+    /// Read a unit from a short form for links, such as
+    /// `warrior:10:veteran`: a unit ID, followed optionally by its health
+    /// and the names of its flags, separated by colons.
+    pub fn from_spec(spec: &str) -> Result<UnitInput, InvalidSpec> {
+        let mut parts = spec.split(':');
+        let mut input = UnitInput {
+            unit: String::from(parts.next().unwrap_or_default()),
+            ..UnitInput::default()
+        };
+        let mut flags = units::UnitFlags::default();
+        for part in parts {
+            if let Ok(health) = part.parse() {
+                input.health = Option::Some(health);
+            } else if !flags.set(part) {
+                return Err(InvalidSpec {
+                    spec: String::from(spec),
+                    part: String::from(part)
+                });
+            }
+        }
+        input.flags = FlagsInput::Named(flags);
+        Ok(input)
+    }
+
+    pub fn to_unit(&self) -> Result<units::Unit, UnknownUnit> {
+        self.check_carrying()?;
+        self.to_unit_as(&self.unit).ok_or_else(|| {
+            UnknownUnit(self.unit.clone())
+        })
+    }
+
+    /// Create the unit, or a unit of another type if the ID is unknown.
+    pub fn to_unit_or(
+        &self, default_unit: &str
+    ) -> Result<units::Unit, UnknownUnit> {
+        self.check_carrying()?;
+        match self.to_unit_as(&self.unit) {
+            Option::Some(unit) => Ok(unit),
+            Option::None => self.to_unit_as(default_unit).ok_or_else(|| {
+                UnknownUnit(String::from(default_unit))
+            })
+        }
+    }
+
+    /// Check that the carried unit, if one is given, is a known type.
+    fn check_carrying(&self) -> Result<(), UnknownUnit> {
+        match &self.carrying {
+            Option::Some(carrying) => {
+                match units::UNIT_LIST.get_unit_by_id(carrying) {
+                    Option::Some(_) => Ok(()),
+                    Option::None => Err(UnknownUnit(carrying.clone()))
+                }
+            },
+            Option::None => Ok(())
+        }
+    }
+
+    /// Check if the unit ID matches a unit type.
+    pub fn is_known(&self) -> bool {
+        units::UNIT_LIST.get_unit_by_id(&self.unit).is_some()
+    }
+
+    /// Create a unit of a given type, with the health and flags from this
+    /// input. Returns `None` if the unit type does not exist.
+    ///
+    /// A vessel takes its health from the unit it carries. Flags are then
+    /// applied, since they may change the maximum health. The explicit
+    /// health, if any, is then clamped to between `MIN_HEALTH` and that
+    /// maximum, so a wounded veteran can't exceed its boosted max.
+    fn to_unit_as(&self, unit_id: &str) -> Option<units::Unit> {
+        let mut unit = units::UNIT_LIST.get_unit_by_id(
+            &String::from(unit_id)
+        )?;
+        if unit.can_carry {
+            let carrying = match &self.carrying {
+                Option::Some(carrying) => carrying.clone(),
+                Option::None => String::from(DEFAULT_CARRIED)
+            };
+            unit.carry(&units::UNIT_LIST.get_unit_by_id(&carrying)?);
+        }
+        match &self.flags {
+            FlagsInput::Bits(flags) => unit.apply_bit_flags(*flags),
+            FlagsInput::Named(flags) => unit.apply_flags(flags)
+        }
+        unit.health = match self.health {
+            Option::Some(health) => (health.round() as i32).min(
+                unit.max_health
+            ).max(MIN_HEALTH.min(unit.max_health)),
+            Option::None => unit.max_health
+        };
+        Option::Some(unit)
+    }
+}
+
+
+/// How to handle unit IDs which do not match any unit type.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+pub enum OnUnknown {
+    /// Reject the battle.
+    #[default]
+    #[cfg_attr(feature = "rocket", field(value = "error"))]
+    Reject,
+    /// Leave out unknown attackers and adjacent units. The defender cannot
+    /// be skipped, so an unknown defender is rejected as with `Reject`.
+    Skip,
+    /// Replace unknown units with a baseline unit type.
+    Default
+}
+
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BattleInput {
+    pub attackers: Vec<UnitInput>,
+    pub defender: UnitInput,
+    // Further defenders which attackers can target. The defender above has
+    // index 0, and these follow it.
+    #[serde(default)]
+    pub defenders: Vec<UnitInput>,
+    // Other defending units next to the defenders, hit by splash damage.
+    // Each defender says which it is next to.
+    #[serde(default)]
+    pub adjacent: Vec<UnitInput>
+}
+
+impl BattleInput {
+    pub fn to_state(&self) -> Result<BattleState, UnknownUnit> {
+        self.to_state_with(OnUnknown::Reject, DEFAULT_UNIT)
+    }
+
+    /// Get a defender by the index attackers target it with.
+    pub fn target(&self, idx: usize) -> Option<&UnitInput> {
+        if idx == 0 {
+            Option::Some(&self.defender)
+        } else {
+            self.defenders.get(idx - 1)
+        }
+    }
+
+    /// Check that each unit only uses flags which mean something for its
+    /// side. Defence bonuses are only for defenders, and boosts only affect
+    /// attackers.
+    pub fn check_flags(&self) -> Result<(), MisplacedFlag> {
+        for attacker in self.attackers.iter() {
+            let flags = attacker.flags.to_flags();
+            let misplaced = if flags.bonus {
+                "bonus"
+            } else if flags.walled {
+                "walled"
+            } else {
+                continue;
+            };
+            return Err(MisplacedFlag { flag: misplaced, side: "attackers" });
+        }
+        let defenders = std::iter::once(&self.defender).chain(
+            self.defenders.iter()
+        );
+        for defender in defenders {
+            if defender.flags.to_flags().boosted {
+                return Err(MisplacedFlag {
+                    flag: "boosted", side: "defenders"
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn about sides which mix units from different special tribes, since
+    /// they could not be fighting together in a real game.
+    pub fn tribe_warnings(&self) -> Vec<String> {
+        let attackers: Vec<&UnitInput> = self.attackers.iter().collect();
+        let defenders: Vec<&UnitInput> = std::iter::once(&self.defender)
+            .chain(self.defenders.iter())
+            .chain(self.adjacent.iter())
+            .collect();
+        let mut warnings = vec![];
+        let sides = [("Attackers", attackers), ("Defenders", defenders)];
+        for (side, inputs) in sides.iter() {
+            let tribes = special_tribes(inputs);
+            if tribes.len() > 1 {
+                warnings.push(format!(
+                    "{} mix units from the {} tribes.", side, tribes.join(", ")
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Find the first index of an adjacent unit, given as next to a
+    /// defender, which does not match an adjacent unit, if any.
+    pub fn invalid_adjacent(&self) -> Option<usize> {
+        let defenders = std::iter::once(&self.defender).chain(
+            self.defenders.iter()
+        );
+        defenders.flat_map(|defender| defender.adjacent.iter().flatten())
+            .find(|idx| **idx >= self.adjacent.len())
+            .copied()
+    }
+
+    /// Find the first target index which does not match a defender, if any.
+    pub fn invalid_target(&self) -> Option<usize> {
+        for attacker in self.attackers.iter() {
+            if self.target(attacker.target).is_none() {
+                return Option::Some(attacker.target);
+            }
+        }
+        Option::None
+    }
+
+    /// Create the battle state, handling unknown unit IDs as requested.
+    /// `default_unit` is only used with `OnUnknown::Default`.
+    pub fn to_state_with(
+        &self, on_unknown: OnUnknown, default_unit: &str
+    ) -> Result<BattleState, UnknownUnit> {
+        let attackers = resolve_units(
+            &self.attackers, on_unknown, default_unit
+        )?;
+        // Defenders cannot be skipped, since that would change the targets.
+        let defender_policy = match on_unknown {
+            OnUnknown::Skip => OnUnknown::Reject,
+            policy => policy
+        };
+        let mut defender = resolve_units(
+            std::slice::from_ref(&self.defender), defender_policy,
+            default_unit
+        )?.pop().ok_or_else(|| UnknownUnit(self.defender.unit.clone()))?;
+        let mut defenders = resolve_units(
+            &self.defenders, defender_policy, default_unit
+        )?;
+        let adjacent = resolve_units(
+            &self.adjacent, on_unknown, default_unit
+        )?;
+        // Adjacent units are given by their index in the request, which
+        // skipping unknown units may have changed.
+        let next_to = |input: &UnitInput, all: bool| match &input.adjacent {
+            Option::Some(indices) => adjacent.iter().enumerate().filter(
+                |(_, unit)| indices.contains(&unit.position)
+            ).map(|(idx, _)| idx).collect(),
+            Option::None if all => (0..adjacent.len()).collect(),
+            Option::None => vec![]
+        };
+        defender.adjacent = next_to(&self.defender, true);
+        for (unit, input) in defenders.iter_mut().zip(self.defenders.iter()) {
+            unit.adjacent = next_to(input, false);
+        }
+        Ok(BattleState {
+            attackers, defender, defenders, adjacent,
+            rules: Ruleset::default()
+        })
+    }
+}
+
+
+/// Get the distinct special tribes of the units in a list of inputs.
+fn special_tribes(inputs: &[&UnitInput]) -> Vec<String> {
+    let mut tribes: Vec<String> = vec![];
+    for input in inputs.iter() {
+        let unit_type = units::UNIT_LIST.get_unit_type(&input.unit);
+        if let Option::Some(tribe) = unit_type.and_then(|t| t.tribe()) {
+            if !tribes.contains(tribe) {
+                tribes.push(tribe.clone());
+            }
+        }
+    }
+    tribes
+}
+
+
+/// Create the units for a list of inputs, including any follow-up
+/// defenders, handling unknown unit IDs as requested.
+fn resolve_units(
+    inputs: &[UnitInput], on_unknown: OnUnknown, default_unit: &str
+) -> Result<Vec<units::Unit>, UnknownUnit> {
+    let mut units = vec![];
+    for (position, input) in inputs.iter().enumerate() {
+        let mut unit = match on_unknown {
+            OnUnknown::Reject => input.to_unit()?,
+            OnUnknown::Skip => if input.is_known() {
+                input.to_unit()?
+            } else {
+                continue;
+            },
+            OnUnknown::Default => input.to_unit_or(default_unit)?
+        };
+        unit.follow_up = resolve_units(
+            &input.follow_up, on_unknown, default_unit
+        )?;
+        unit.target = input.target;
+        unit.position = position;
+        unit.heal_target = input.heal;
+        unit.exploding = input.explode && unit.can_explode;
+        unit.boost_target = input.boost;
+        unit.city = input.city;
+        unit.kills = input.kills;
+        unit.apply_terrain(input.terrain, &input.techs);
+        units.push(unit);
+    }
+    Ok(units)
+}
+
+
+/// Which side of a battle an optimisation should favour.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Perspective {
+    #[default]
+    Attacker,
+    Defender
+}
+
+impl Perspective {
+    /// Compare two states, returning `Greater` if the first is better for
+    /// this side, as judged by an objective.
+    pub fn compare(
+        self, objective: Objective, this: &BattleState, other: &BattleState
+    ) -> Ordering {
+        let ordering = objective.compare(this, other);
+        match self {
+            Perspective::Attacker => ordering,
+            Perspective::Defender => ordering.reverse()
+        }
+    }
+}
+
+
+/// What the attackers most want from a battle. Whatever the objective,
+/// states it can't tell apart are compared by their overall outcome.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Objective {
+    /// The overall outcome, as given by `BattleState::score`.
+    #[default]
+    Overall,
+    /// Killing or converting the defender.
+    KillDefender,
+    /// Losing as few attackers as possible.
+    MinAttackerDeaths,
+    /// Dealing as much damage as possible, not counting overkill.
+    MaxDamage,
+    /// Keeping the attacker with this index alive and healthy.
+    PreserveUnit(usize)
+}
+
+impl Objective {
+    /// Compare two states, returning `Greater` if the first better meets
+    /// the objective.
+    pub fn compare(self, this: &BattleState, other: &BattleState) -> Ordering {
+        let ordering = match self {
+            Objective::Overall => Ordering::Equal,
+            Objective::KillDefender => this.defender_out().cmp(
+                &other.defender_out()
+            ),
+            Objective::MinAttackerDeaths => other.count_dead().cmp(
+                &this.count_dead()
+            ),
+            Objective::MaxDamage => this.damage_done().cmp(
+                &other.damage_done()
+            ),
+            Objective::PreserveUnit(idx) => {
+                let health = |state: &BattleState| {
+                    state.attackers.iter().find(
+                        |attacker| attacker.position == idx
+                    ).map(|attacker| attacker.health)
+                };
+                let (this, other) = (health(this), health(other));
+                this.map(|health| health > 0).cmp(
+                    &other.map(|health| health > 0)
+                ).then(this.cmp(&other))
+            }
+        };
+        ordering.then_with(|| this.score().cmp(&other.score()))
+    }
+
+    /// The same objective, with the attacker it is about given a new index.
+    fn reindexed(self, new_index: impl Fn(usize) -> usize) -> Objective {
+        match self {
+            Objective::PreserveUnit(idx) => {
+                Objective::PreserveUnit(new_index(idx))
+            },
+            objective => objective
+        }
+    }
+}
+
+
+/// How to search for the best order of attack.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Try every order which could be the best, so the result is optimal.
+    #[default]
+    Exhaustive,
+    /// Build orders an attacker at a time, keeping only the most promising
+    /// few. Much faster for many attackers, but may miss the best order.
+    Heuristic
+}
+
+
+/// A requirement an order of attack must meet. Attackers are given by
+/// their index in the request.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Constraint {
+    /// The attacker must attack first.
+    First(usize),
+    /// The attacker must attack last.
+    Last(usize),
+    /// The first attacker must act before the second.
+    Before(usize, usize),
+    /// The attacker must survive the battle.
+    Survives(usize)
+}
+
+impl Constraint {
+    /// The indices of the attackers the constraint is about.
+    pub fn attackers(self) -> Vec<usize> {
+        match self {
+            Constraint::First(idx) | Constraint::Last(idx)
+                | Constraint::Survives(idx) => vec![idx],
+            Constraint::Before(first, second) => vec![first, second]
+        }
+    }
+
+    /// The same constraint, with the attackers it is about given new
+    /// indices.
+    fn reindexed(self, new_index: impl Fn(usize) -> usize) -> Constraint {
+        match self {
+            Constraint::First(idx) => Constraint::First(new_index(idx)),
+            Constraint::Last(idx) => Constraint::Last(new_index(idx)),
+            Constraint::Before(first, second) => {
+                Constraint::Before(new_index(first), new_index(second))
+            },
+            Constraint::Survives(idx) => Constraint::Survives(new_index(idx))
+        }
+    }
+
+    /// Check if an attacker may act next, after `acted` attackers have
+    /// already acted, given the attackers yet to act (including it).
+    /// Constraints about attackers which are not in the battle are met.
+    fn allows_next(
+        self, acted: usize, next: &units::Unit, remaining: &[&units::Unit]
+    ) -> bool {
+        let waiting = |idx: usize| remaining.iter().any(
+            |attacker| attacker.position == idx
+        );
+        match self {
+            Constraint::First(idx) => if next.position == idx {
+                acted == 0
+            } else {
+                acted > 0 || !waiting(idx)
+            },
+            Constraint::Last(idx) => {
+                next.position != idx || remaining.len() == 1
+            },
+            Constraint::Before(first, second) => {
+                next.position != second || !waiting(first)
+            },
+            Constraint::Survives(_) => true
+        }
+    }
+
+    /// Check if the outcome of a battle meets the constraint.
+    fn allows_result(self, state: &BattleState) -> bool {
+        match self {
+            Constraint::Survives(idx) => state.attackers.iter().all(
+                |attacker| attacker.position != idx || attacker.health > 0
+            ),
+            _ => true
+        }
+    }
+}
+
+
+/// A battle, and whether to list everything which happens in it.
+#[derive(Deserialize)]
+pub struct ExplainInput {
+    #[serde(flatten)]
+    pub battle: BattleInput,
+    #[serde(default)]
+    pub explain: bool
+}
+
+
+/// A battle to optimise, along with options for the optimisation.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OptimInput {
+    #[serde(flatten)]
+    pub battle: BattleInput,
+    #[serde(default)]
+    pub perspective: Perspective,
+    #[serde(default)]
+    pub objective: Objective,
+    // Whether to find the fewest attackers which kill the defender, rather
+    // than the best order for all of them.
+    #[serde(default)]
+    pub minimise: bool,
+    // How many of the best orders to list, if more than just the best.
+    #[serde(default)]
+    pub top: Option<usize>,
+    #[serde(default)]
+    pub constraints: Vec<Constraint>,
+    #[serde(default)]
+    pub mode: SearchMode,
+    // How long, in milliseconds, the search may take before giving the best
+    // order found so far. Left out of cache keys, since a complete result
+    // doesn't depend on it.
+    #[serde(default, skip_serializing)]
+    pub max_ms: Option<u64>
+}
+
+impl OptimInput {
+    pub fn search_options(&self) -> SearchOptions {
+        let mut distinct = vec![];
+        if let Objective::PreserveUnit(idx) = self.objective {
+            distinct.push(idx);
+        }
+        SearchOptions {
+            count: self.top.unwrap_or(1).clamp(1, MAX_TOP_ORDERS),
+            perspective: self.perspective,
+            objective: self.objective,
+            constraints: self.constraints.clone(),
+            distinct,
+            mode: self.mode,
+            deadline: self.max_ms.map(
+                |ms| Instant::now() + Duration::from_millis(ms)
+            ),
+            cancelled: Option::None,
+            progress: Option::None
+        }
+    }
+
+    /// Find an attacker index used by the objective or a constraint which
+    /// does not match any attacker.
+    pub fn invalid_attacker(&self) -> Option<usize> {
+        let mut indices = vec![];
+        if let Objective::PreserveUnit(idx) = self.objective {
+            indices.push(idx);
+        }
+        for constraint in self.constraints.iter() {
+            indices.extend(constraint.attackers());
+        }
+        indices.into_iter().find(|idx| *idx >= self.battle.attackers.len())
+    }
+
+    /// Put the attackers in a standard order, so that requests which only
+    /// list them differently become the same. Returns the reordered input,
+    /// and the index in this input of each attacker in it.
+    pub fn canonical(&self) -> (OptimInput, Vec<usize>) {
+        // Attackers are sorted by everything but the attackers they heal or
+        // boost, since those indices are about to change.
+        let keys: Vec<String> = self.battle.attackers.iter().map(|attacker| {
+            let mut attacker = attacker.clone();
+            attacker.heal = Option::None;
+            attacker.boost = Option::None;
+            serde_json::to_string(&attacker).unwrap()
+        }).collect();
+        let mut attackers: Vec<usize> = (0..keys.len()).collect();
+        attackers.sort_by(|first, second| keys[*first].cmp(&keys[*second]));
+        let mut new_indices = vec![0; attackers.len()];
+        for (new_idx, idx) in attackers.iter().enumerate() {
+            new_indices[*idx] = new_idx;
+        }
+        let new_index = |idx: usize| new_indices.get(idx).copied()
+            .unwrap_or(idx);
+        let mut input = self.clone();
+        input.battle.attackers = attackers.iter().map(|idx| {
+            let mut attacker = self.battle.attackers[*idx].clone();
+            attacker.heal = attacker.heal.map(new_index);
+            attacker.boost = attacker.boost.map(new_index);
+            attacker
+        }).collect();
+        input.objective = self.objective.reindexed(new_index);
+        input.constraints = self.constraints.iter().map(
+            |constraint| constraint.reindexed(new_index)
+        ).collect();
+        (input, attackers)
+    }
+
+    /// Search for the best order of attack, or the fewest attackers, for a
+    /// battle built from this input, giving the result with statistics
+    /// about the search. The search gives up early if `cancelled` is set,
+    /// and reports how far it has got to `progress`.
+    pub fn optimise(
+        &self, state: BattleState, cancelled: Option<Arc<AtomicBool>>,
+        progress: Option<Arc<SearchProgress>>
+    ) -> Value {
+        // With no order which meets the constraints, or no subset which
+        // kills the defender, the order and state are null.
+        let no_order = json!({
+            "order": null,
+            "named_order": null,
+            "state": null
+        });
+        let options = SearchOptions {
+            cancelled,
+            progress,
+            ..self.search_options()
+        };
+        let span = tracing::info_span!(
+            "optimise",
+            attackers = state.attackers.len(),
+            minimise = self.minimise,
+            mode = ?self.mode,
+            permutations = tracing::field::Empty
+        );
+        let _entered = span.enter();
+        let mut search = SearchStats::default();
+        let started = Instant::now();
+        let mut response = if self.minimise {
+            match minimise_attackers(state, &options, &mut search) {
+                Option::Some((order, state)) => order_json(&order, &state),
+                Option::None => no_order
+            }
+        } else {
+            let mut orders = vec![];
+            for (order, state) in best_orders(state, &options, &mut search) {
+                orders.push(order_json(&order, &state));
+            }
+            let mut response = match orders.first() {
+                Option::Some(best) => best.clone(),
+                Option::None => no_order
+            };
+            if self.top.is_some() {
+                response["orders"] = json!(orders);
+            }
+            response
+        };
+        search.elapsed_ms = started.elapsed().as_millis() as u64;
+        span.record("permutations", search.permutations_evaluated);
+        response["search"] = json!(search);
+        // A heuristic search can't prove that the order it found is the
+        // best.
+        response["proven_optimal"] = json!(search.exhaustive);
+        response["complete"] = json!(search.complete);
+        response
+    }
+}
+
+
+#[derive(Clone, Serialize)]
+pub struct BattleState {
+    pub attackers: Vec<units::Unit>,
+    pub defender: units::Unit,
+    // Further defenders, targeted from index 1 onwards.
+    pub defenders: Vec<units::Unit>,
+    pub adjacent: Vec<units::Unit>,
+    #[serde(skip)]
+    pub rules: Ruleset
+}
+
+impl BattleState {
+    pub fn count_dead(&self) -> u8 {
+        let mut count = 0;
+        for attacker in self.attackers.iter() {
+            if attacker.health <= 0 {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Summarise the outcome of the battle so it can be compared to others.
+    pub fn score(&self) -> StateScore {
+        let converted = self.defender.converted;
+        let mut defenders_out = 0;
+        let mut defenders_health = 0;
+        for unit in &self.defenders {
+            if unit.health <= 0 || unit.converted {
+                defenders_out += 1;
+            } else {
+                defenders_health -= unit.health;
+            }
+        }
+        let mut adjacent_health = 0;
+        for unit in &self.adjacent {
+            adjacent_health -= unit.health.max(0);
+        }
+        let mut attackers_health = vec![];
+        let mut attackers_total_health = 0;
+        let mut follow_up_kills = 0;
+        for attacker in &self.attackers {
+            for unit in &attacker.follow_up {
+                if unit.health <= 0 {
+                    follow_up_kills += 1;
+                }
+            }
+            attackers_health.push(attacker.health);
+            if attacker.health > 0 {
+                attackers_total_health += attacker.health;
+            }
+        }
+        StateScore {
+            defender_converted: converted,
+            // A converted defender is on our side, so we want it healthy and
+            // able to act. Otherwise, we want it as weak as possible, but
+            // overkill is no better than just killing it.
+            defender_health: if converted {
+                self.defender.health
+            } else {
+                -self.defender.health.max(0)
+            },
+            defender_frozen: self.defender.frozen != converted,
+            follow_up_kills,
+            defenders_out,
+            defenders_health,
+            attackers_dead: self.count_dead(),
+            adjacent_health,
+            attackers_total_health,
+            attackers_health
+        }
+    }
+
+    /// How much damage the defender took beyond what it took to kill it.
+    pub fn overkill(&self) -> i32 {
+        (-self.defender.health).max(0)
+    }
+
+    /// Check if the defender has been killed or converted.
+    pub fn defender_out(&self) -> bool {
+        self.defender.health <= 0 || self.defender.converted
+    }
+
+    /// The total damage dealt to the defending units, not counting damage
+    /// beyond what it took to kill them.
+    pub fn damage_done(&self) -> i32 {
+        let follow_ups = self.attackers.iter().flat_map(
+            |attacker| attacker.follow_up.iter()
+        );
+        std::iter::once(&self.defender)
+            .chain(self.defenders.iter())
+            .chain(self.adjacent.iter())
+            .chain(follow_ups)
+            .map(|unit| unit.damage_taken.min(unit.damage_taken + unit.health))
+            .sum()
+    }
+
+    pub fn is_better_than(
+        &self, other: &BattleState, objective: Objective
+    ) -> bool {
+        objective.compare(self, other) == Ordering::Greater
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut attackers_health = vec![];
+        let mut drained = vec![];
+        let mut attacks = vec![];
+        let mut promotions = vec![];
+        let mut follow_ups = vec![];
+        for attacker in &self.attackers {
+            attackers_health.push(attacker.health);
+            drained.push(attacker.drained);
+            attacks.push(json!({
+                "damage_dealt": attacker.damage_dealt,
+                "retaliation_taken": attacker.retaliation_taken,
+                "survived": attacker.health > 0
+            }));
+            promotions.push(attacker.can_promote());
+            let mut targets = vec![];
+            for unit in &attacker.follow_up {
+                targets.push(unit_json(unit));
+            }
+            follow_ups.push(targets);
+        }
+        let mut defenders = vec![unit_json(&self.defender)];
+        for unit in &self.defenders {
+            defenders.push(unit_json(unit));
+        }
+        let mut adjacent = vec![];
+        for unit in &self.adjacent {
+            adjacent.push(unit_json(unit));
+        }
+        json!({
+            "attackers": attackers_health,
+            "drained": drained,
+            "attacks": attacks,
+            "promotions": promotions,
+            "defender": {
+                "health": self.defender.health,
+                "damage": self.defender.damage_taken,
+                "frozen": self.defender.frozen,
+                "poisoned": self.defender.poisoned,
+                "converted": self.defender.converted
+            },
+            "defender_killed": self.defender.health <= 0,
+            "overkill": self.overkill(),
+            "defenders": defenders,
+            "adjacent": adjacent,
+            "follow_ups": follow_ups
+        })
+    }
+}
+
+
+/// An order of attack, with the type of each attacker, given the state of
+/// the battle after attacking in that order.
+pub fn order_json(order: &[usize], state: &BattleState) -> Value {
+    let mut named = vec![];
+    for (idx, attacker) in order.iter().zip(state.attackers.iter()) {
+        named.push(json!({
+            "index": idx,
+            "id": attacker.id,
+            "display_name": attacker.display_name
+        }));
+    }
+    json!({
+        "order": order,
+        "named_order": named,
+        "state": state.to_json()
+    })
+}
+
+
+/// Replace the attacker indices in the result of `OptimInput::optimise`
+/// with the index in `attackers` they point to, to undo
+/// `OptimInput::canonical`.
+pub fn restore_indices(result: &mut Value, attackers: &[usize]) {
+    let restore_order = |order: &mut Value| {
+        if let Option::Some(order) = order["order"].as_array_mut() {
+            for idx in order.iter_mut() {
+                *idx = json!(attackers[idx.as_u64().unwrap() as usize]);
+            }
+        }
+        if let Option::Some(named) = order["named_order"].as_array_mut() {
+            for attacker in named.iter_mut() {
+                let idx = attacker["index"].as_u64().unwrap() as usize;
+                attacker["index"] = json!(attackers[idx]);
+            }
+        }
+    };
+    restore_order(result);
+    if let Option::Some(orders) = result.get_mut("orders") {
+        for order in orders.as_array_mut().unwrap().iter_mut() {
+            restore_order(order);
+        }
+    }
+}
+
+
+/// The state of a defending unit after a battle.
+fn unit_json(unit: &units::Unit) -> Value {
+    json!({
+        "health": unit.health,
+        "damage": unit.damage_taken,
+        "frozen": unit.frozen,
+        "poisoned": unit.poisoned,
+        "converted": unit.converted
+    })
+}
+
+
+/// The outcome of a battle, reduced to the values used to compare it.
+/// Better outcomes for the attacker compare as greater. This is a total
+/// order: states are only equal if every attacker has the same health.
+#[derive(Debug)]
+pub struct StateScore {
+    defender_converted: bool,
+    defender_health: i32,
+    defender_frozen: bool,
+    follow_up_kills: u8,
+    defenders_out: u8,
+    defenders_health: i32,
+    attackers_dead: u8,
+    adjacent_health: i32,
+    attackers_total_health: i32,
+    attackers_health: Vec<i32>
+}
+
+impl Ord for StateScore {
+    fn cmp(&self, other: &StateScore) -> Ordering {
+        self.defender_converted.cmp(&other.defender_converted)
+            .then(self.defender_health.cmp(&other.defender_health))
+            .then(self.defender_frozen.cmp(&other.defender_frozen))
+            .then(self.follow_up_kills.cmp(&other.follow_up_kills))
+            .then(self.defenders_out.cmp(&other.defenders_out))
+            .then(self.defenders_health.cmp(&other.defenders_health))
+            .then(other.attackers_dead.cmp(&self.attackers_dead))
+            .then(self.adjacent_health.cmp(&other.adjacent_health))
+            .then(self.attackers_total_health.cmp(
+                &other.attackers_total_health
+            ))
+            .then_with(|| self.attackers_health.cmp(&other.attackers_health))
+    }
+}
+
+impl PartialOrd for StateScore {
+    fn partial_cmp(&self, other: &StateScore) -> Option<Ordering> {
+        Option::Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for StateScore {
+    fn eq(&self, other: &StateScore) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for StateScore {}
+
+
+/// Check if an attacker will recieve retaliation from a defender.
+fn check_retaliation(attacker: &units::Unit, defender: &units::Unit) -> bool {
+    let prevented = defender.frozen || defender.converted
+        || defender.health <= 0 || !defender.can_retaliate || defender.stiff
+        || attacker.surprise;
+    if prevented {
+        false
+    } else if let Option::Some(forced) = attacker.forced_retaliation {
+        forced
+    } else if let Option::Some(forced) = defender.forced_retaliation {
+        forced
+    } else {
+        (!attacker.ranged) || defender.ranged
+    }
+}
+
+
+/// The intermediate and final values of the damage formula.
+#[derive(Serialize, Debug)]
+pub struct DamageBreakdown {
+    pub total_force: f32,
+    pub raw_damage: f32,
+    pub damage: i32,
+    pub raw_retaliation: f32,
+    pub retaliation: i32
+}
+
+
+/// Round an amount of damage to whole HP, with halves rounded as the rules
+/// say. The amount is first snapped to the nearest ten-thousandth of a HP,
+/// so that float error can't push an exact half the wrong way.
+pub fn round_damage(damage: f32, rounding: Rounding) -> i32 {
+    let snapped = (f64::from(damage) * 10_000.0).round() / 10_000.0;
+    match rounding {
+        Rounding::HalfUp => snapped.round() as i32,
+        Rounding::HalfEven => snapped.round_ties_even() as i32
+    }
+}
+
+
+/// Calculate the damage and retaliation of an attack from the forces and
+/// stats of the attacker and defender, independent of any unit.
+/// Returns `None` if the forces do not sum to more than zero, or if any
+/// value is not a finite number.
+pub fn damage_formula(
+    attack_force: f32, defence_force: f32, attack: f32, defence: f32,
+    rules: &Ruleset
+) -> Option<DamageBreakdown> {
+    let finite = [attack_force, defence_force, attack, defence].iter().all(
+        |value| value.is_finite()
+    );
+    let forces = attack_force + defence_force;
+    let valid = finite && forces > 0.0;
+    if !valid {
+        return Option::None;
+    }
+    let total_force = rules.total_force / forces;
+    let raw_damage = attack_force * attack * total_force;
+    let raw_retaliation = defence_force * defence * total_force;
+    Option::Some(DamageBreakdown {
+        total_force,
+        raw_damage,
+        damage: round_damage(raw_damage, rules.rounding),
+        raw_retaliation,
+        retaliation: round_damage(raw_retaliation, rules.rounding)
+    })
+}
+
+
+/// The force a unit attacks with, which falls as it loses health.
+fn attack_force(attacker: &units::Unit) -> f32 {
+    attacker.attack * (attacker.health as f32 / attacker.max_health as f32)
+}
+
+
+/// The defence multiplier a unit gets from the city it is in, against a
+/// given attacker. Only units which can fortify get a bonus, and some
+/// attackers ignore walls.
+fn city_bonus(
+    defender: &units::Unit, attacker: &units::Unit, rules: &Ruleset
+) -> f32 {
+    if !defender.can_fortify {
+        return 1.0;
+    }
+    match defender.city {
+        units::City::Outside => 1.0,
+        units::City::Unwalled => rules.defence_bonus,
+        units::City::Walled => if attacker.ignores_walls {
+            rules.defence_bonus
+        } else {
+            rules.wall_bonus
+        }
+    }
+}
+
+
+/// The force a unit defends with against an attacker, which falls as it
+/// loses health. A frozen unit loses any defence bonus, unless the rules say
+/// otherwise.
+fn defence_force(
+    defender: &units::Unit, attacker: &units::Unit, rules: &Ruleset
+) -> f32 {
+    let defence = if defender.frozen && !rules.frozen_keeps_bonus {
+        defender.defence
+    } else {
+        defender.defence_with_bonus(rules)
+            * city_bonus(defender, attacker, rules)
+    };
+    defence * (defender.health as f32 / defender.max_health as f32)
+}
+
+
+/// A reference to a unit in a battle, for describing what happened to it.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitRef {
+    Attacker(usize),
+    // Defenders are numbered as attackers target them.
+    Defender(usize),
+    Adjacent(usize),
+    // An attacker's index, then the index of one of its follow-up units.
+    FollowUp(usize, usize)
+}
+
+
+/// Something which happened during a battle.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Attack {
+        attacker: UnitRef,
+        defender: UnitRef,
+        damage: i32,
+        retaliation: i32
+    },
+    Tentacles { unit: UnitRef, target: UnitRef, damage: i32 },
+    Splash { attacker: UnitRef, unit: UnitRef, damage: i32 },
+    Explosion { attacker: UnitRef, unit: UnitRef, damage: i32 },
+    Drain { unit: UnitRef, amount: i32 },
+    Heal { unit: UnitRef, target: UnitRef, amount: i32 },
+    Boost { unit: UnitRef, target: UnitRef },
+    Poison { unit: UnitRef },
+    Freeze { unit: UnitRef },
+    Convert { unit: UnitRef },
+    Death { unit: UnitRef }
+}
+
+
+/// Where the events of a battle are recorded, if it is being explained.
+type Log = Option<Vec<Event>>;
+
+
+/// The adjacent units of a battle, with the indices of those next to the
+/// defender being attacked.
+type Adjacent<'a> = (&'a mut [units::Unit], &'a [usize]);
+
+
+/// The adjacent units next to the defender being attacked, with their
+/// indices.
+fn next_to(
+    (adjacent, indices): Adjacent<'_>
+) -> impl Iterator<Item = (usize, &'_ mut units::Unit)> {
+    adjacent.iter_mut().enumerate().filter(
+        move |(idx, _)| indices.contains(idx)
+    )
+}
+
+
+fn record(log: &mut Log, event: Event) {
+    if let Option::Some(events) = log {
+        events.push(event);
+    }
+}
+
+
+/// Deal damage to a unit, recording its death if this kills it.
+fn hurt(unit: &mut units::Unit, id: UnitRef, damage: i32, log: &mut Log) {
+    let was_alive = unit.health > 0;
+    unit.health -= damage;
+    unit.damage_taken += damage;
+    if was_alive && unit.health <= 0 {
+        record(log, Event::Death { unit: id });
+    }
+}
+
+
+/// Poison a unit, recording it if it was not already poisoned.
+fn poison(unit: &mut units::Unit, id: UnitRef, log: &mut Log) {
+    if !unit.poisoned {
+        record(log, Event::Poison { unit: id });
+    }
+    unit.apply_poison();
+}
+
+
+/// Freeze a unit, recording it if it was not already frozen.
+fn freeze(unit: &mut units::Unit, id: UnitRef, log: &mut Log) {
+    if !unit.frozen {
+        record(log, Event::Freeze { unit: id });
+    }
+    unit.apply_freeze();
+}
+
+
+/// Calculate the damage done to a defender, and retaliation to an attacker.
+/// An attacker with drain then heals by the damage it did.
+fn attack(
+    attacker: &mut units::Unit, defender: &mut units::Unit, rules: &Ruleset,
+    ids: (UnitRef, UnitRef), log: &mut Log
+) {
+    let breakdown = match damage_formula(
+        attack_force(attacker), defence_force(defender, attacker, rules),
+        attacker.attack, defender.defence, rules
+    ) {
+        Option::Some(breakdown) => breakdown,
+        Option::None => return
+    };
+    let retaliation = if check_retaliation(attacker, defender) {
+        breakdown.retaliation
+    } else {
+        0
+    };
+    record(log, Event::Attack {
+        attacker: ids.0,
+        defender: ids.1,
+        damage: breakdown.damage,
+        retaliation
+    });
+    hurt(defender, ids.1, breakdown.damage, log);
+    attacker.damage_dealt += breakdown.damage;
+    if retaliation > 0 {
+        hurt(attacker, ids.0, retaliation, log);
+        attacker.retaliation_taken += retaliation;
+        defender.damage_dealt += retaliation;
+    }
+    if attacker.drain && attacker.health > 0 {
+        let health = attacker.health;
+        attacker.heal(breakdown.damage);
+        attacker.drained += attacker.health - health;
+        record(log, Event::Drain {
+            unit: ids.0,
+            amount: attacker.health - health
+        });
+    }
+}
+
+
+/// Deal splash damage to the units next to the defender. Each takes part
+/// (normally half) of the damage a normal attack on it would do, and none of
+/// them retaliate. Attackers with poison also poison every unit they splash.
+fn splash(
+    attacker: &mut units::Unit, adjacent: Adjacent, rules: &Ruleset,
+    attacker_id: UnitRef, log: &mut Log
+) {
+    for (idx, unit) in next_to(adjacent) {
+        if unit.health <= 0 || unit.converted {
+            continue;
+        }
+        let id = UnitRef::Adjacent(idx);
+        let breakdown = damage_formula(
+            attack_force(attacker), defence_force(unit, attacker, rules),
+            attacker.attack, unit.defence, rules
+        );
+        if let Option::Some(breakdown) = breakdown {
+            let damage = round_damage(
+                breakdown.raw_damage * rules.splash_damage, rules.rounding
+            );
+            record(log, Event::Splash {
+                attacker: attacker_id, unit: id, damage
+            });
+            hurt(unit, id, damage, log);
+            attacker.damage_dealt += damage;
+        }
+        if attacker.can_poison && unit.health > 0 {
+            poison(unit, id, log);
+        }
+    }
+}
+
+
+/// Blow up an attacker, hitting the defender and the units next to it with
+/// part (in the latest rules, all) of the damage a normal attack would do,
+/// and no retaliation. Every unit hit is poisoned, and the attacker dies.
+fn explode(
+    attacker: &mut units::Unit, defender: &mut units::Unit,
+    adjacent: Adjacent, rules: &Ruleset, ids: (UnitRef, UnitRef),
+    log: &mut Log
+) {
+    let attack = attack_force(attacker);
+    let adjacent = next_to(adjacent).map(
+        |(idx, unit)| (UnitRef::Adjacent(idx), unit)
+    );
+    for (id, unit) in std::iter::once((ids.1, defender)).chain(adjacent) {
+        if unit.health <= 0 || unit.converted {
+            continue;
+        }
+        let breakdown = damage_formula(
+            attack, defence_force(unit, attacker, rules), attacker.attack,
+            unit.defence, rules
+        );
+        if let Option::Some(breakdown) = breakdown {
+            let damage = round_damage(
+                breakdown.raw_damage * rules.explode_damage, rules.rounding
+            );
+            record(log, Event::Explosion {
+                attacker: ids.0, unit: id, damage
+            });
+            hurt(unit, id, damage, log);
+            attacker.damage_dealt += damage;
+        }
+        if unit.health > 0 {
+            poison(unit, id, log);
+        }
+    }
+    attacker.health = 0;
+    record(log, Event::Death { unit: ids.0 });
+}
+
+
+/// Freeze the units next to the defender.
+fn freeze_adjacent(adjacent: Adjacent, log: &mut Log) {
+    for (idx, unit) in next_to(adjacent) {
+        if unit.health > 0 && !unit.converted {
+            freeze(unit, UnitRef::Adjacent(idx), log);
+        }
+    }
+}
+
+
+/// Have a defender with tentacles hit a melee attacker before it attacks.
+/// The attacker does not retaliate.
+fn tentacles(
+    defender: &mut units::Unit, attacker: &mut units::Unit, rules: &Ruleset,
+    ids: (UnitRef, UnitRef), log: &mut Log
+) {
+    let breakdown = damage_formula(
+        attack_force(defender), defence_force(attacker, defender, rules),
+        defender.attack, attacker.defence, rules
+    );
+    if let Option::Some(breakdown) = breakdown {
+        record(log, Event::Tentacles {
+            unit: ids.1, target: ids.0, damage: breakdown.damage
+        });
+        hurt(attacker, ids.0, breakdown.damage, log);
+        defender.damage_dealt += breakdown.damage;
+    }
+}
+
+
+/// Calculate a battle between two units.
+/// Includes tentacles, poisoning, converting and freezing as well as
+/// actually attacking.
+pub fn battle(
+    attacker: &mut units::Unit, defender: &mut units::Unit, rules: &Ruleset
+) {
+    // Nothing is recorded, so the references don't matter.
+    let ids = (UnitRef::Attacker(0), UnitRef::Defender(0));
+    logged_battle(attacker, defender, rules, ids, &mut Option::None);
+}
+
+
+/// Calculate a battle between two units, recording what happens.
+fn logged_battle(
+    attacker: &mut units::Unit, defender: &mut units::Unit, rules: &Ruleset,
+    ids: (UnitRef, UnitRef), log: &mut Log
+) {
+    if defender.converted {
+        return;
+    }
+    let was_alive = defender.health > 0;
+    let can_hit = was_alive && !defender.frozen;
+    if defender.tentacles && can_hit && !attacker.ranged {
+        tentacles(defender, attacker, rules, ids, log);
+        if attacker.health <= 0 {
+            return;
+        }
+    }
+    if attacker.attack > 0.0 {
+        attack(attacker, defender, rules, ids, log);
+        if attacker.can_poison && defender.health > 0 {
+            poison(defender, ids.1, log);
+        }
+        if was_alive && defender.health <= 0 {
+            attacker.kills = attacker.kills.saturating_add(1);
+        }
+    }
+    if attacker.health > 0 {
+        if attacker.can_convert {
+            defender.converted = true;
+            record(log, Event::Convert { unit: ids.1 });
+        } else if attacker.can_freeze || attacker.freeze_area {
+            freeze(defender, ids.1, log);
+        }
+    }
+}
+
+
+/// Have a persisting attacker which has just killed its target move on to
+/// its follow-up defenders, one after another, until it fails to kill one
+/// or dies.
+fn persist(
+    attacker: &mut units::Unit, idx: usize, rules: &Ruleset, log: &mut Log
+) {
+    let mut follow_up = std::mem::take(&mut attacker.follow_up);
+    for (unit_idx, unit) in follow_up.iter_mut().enumerate() {
+        if attacker.health <= 0 {
+            break;
+        }
+        if unit.health <= 0 || unit.converted {
+            continue;
+        }
+        let ids = (UnitRef::Attacker(idx), UnitRef::FollowUp(idx, unit_idx));
+        logged_battle(attacker, unit, rules, ids, log);
+        if unit.health > 0 {
+            break;
+        }
+    }
+    attacker.follow_up = follow_up;
+}
+
+
+/// Find the index of the attacker at a requested position, other than the
+/// one at `idx`, if it is alive.
+fn find_ally(
+    attackers: &[units::Unit], idx: usize, position: usize
+) -> Option<usize> {
+    attackers.iter().enumerate().position(|(other_idx, other)| {
+        other_idx != idx && other.position == position && other.health > 0
+    })
+}
+
+
+/// Calculate the result of attacking a defender with a series of attackers.
+/// Attackers with an aura boost the attack of every attacker after them, and
+/// attackers with splash damage the units next to the defender. Attackers
+/// with persist which kill the defender continue into their follow-ups.
+/// Each attacker attacks the defender it targets, heals or boosts another
+/// attacker instead if it is given one, or explodes if asked to. Dead or
+/// frozen attackers, and attackers whose target is already dead, do
+/// nothing.
+pub fn battle_many(state: &mut BattleState) {
+    logged_battle_many(state, &mut Option::None);
+}
+
+
+/// Calculate the result of a battle as `battle_many` does, and list
+/// everything which happened, in order.
+pub fn explain_battle(state: &mut BattleState) -> Vec<Event> {
+    let mut log = Option::Some(vec![]);
+    logged_battle_many(state, &mut log);
+    log.unwrap_or_default()
+}
+
+
+fn logged_battle_many(state: &mut BattleState, log: &mut Log) {
+    let mut aura = 1.0;
+    for idx in 0..state.attackers.len() {
+        act(state, idx, &mut aura, log);
+    }
+}
+
+
+/// Have the attacker at `idx` act, as one step of `battle_many`. `aura` is
+/// the multiplier to the attack of it and every attacker after it, which
+/// an attacker with an aura increases.
+fn act(state: &mut BattleState, idx: usize, aura: &mut f32, log: &mut Log) {
+    let mut attackers = std::mem::take(&mut state.attackers);
+    act_with(&mut attackers, state, idx, aura, log);
+    state.attackers = attackers;
+}
+
+
+/// Have an attacker act, with the attackers taken out of the state so they
+/// can be changed alongside it.
+fn act_with(
+    attackers: &mut [units::Unit], state: &mut BattleState, idx: usize,
+    aura: &mut f32, log: &mut Log
+) {
+    if attackers[idx].health <= 0 || attackers[idx].frozen {
+        return;
+    }
+    attackers[idx].attack *= *aura;
+    *aura *= 1.0 + attackers[idx].attack_aura;
+    if let (true, Option::Some(position)) = (
+        attackers[idx].can_heal, attackers[idx].heal_target
+    ) {
+        if let Option::Some(ally) = find_ally(attackers, idx, position) {
+            let health = attackers[ally].health;
+            attackers[ally].heal(HEAL_AMOUNT);
+            record(log, Event::Heal {
+                unit: UnitRef::Attacker(idx),
+                target: UnitRef::Attacker(ally),
+                amount: attackers[ally].health - health
+            });
+        }
+        return;
+    }
+    if let (true, Option::Some(position)) = (
+        attackers[idx].can_boost, attackers[idx].boost_target
+    ) {
+        if let Option::Some(ally) = find_ally(attackers, idx, position) {
+            attackers[ally].apply_boost();
+            record(log, Event::Boost {
+                unit: UnitRef::Attacker(idx),
+                target: UnitRef::Attacker(ally)
+            });
+        }
+        return;
+    }
+    let attacker = &mut attackers[idx];
+    let defender = if attacker.target == 0 {
+        &mut state.defender
+    } else {
+        match state.defenders.get_mut(attacker.target - 1) {
+            Option::Some(defender) => defender,
+            Option::None => return
+        }
+    };
+    if defender.health <= 0 {
+        return;
+    }
+    let ids = (UnitRef::Attacker(idx), UnitRef::Defender(attacker.target));
+    // Only the units next to the defender being attacked are hit.
+    let next_to = defender.adjacent.clone();
+    let adjacent = &mut state.adjacent;
+    if attacker.exploding {
+        explode(
+            attacker, defender, (adjacent, &next_to), &state.rules, ids, log
+        );
+        return;
+    }
+    let attacks = attacker.attack > 0.0 && !defender.converted;
+    if attacker.splash && attacks {
+        splash(attacker, (adjacent, &next_to), &state.rules, ids.0, log);
+    }
+    logged_battle(attacker, defender, &state.rules, ids, log);
+    if attacker.freeze_area && attacker.health > 0 {
+        freeze_adjacent((adjacent, &next_to), log);
+    }
+    let killed = defender.health <= 0;
+    if attacker.persist && killed && attacker.health > 0 {
+        persist(attacker, idx, &state.rules, log);
+    }
+}
+
+
+/// Calculate a duel: `first` attacks `second`, then `second` attacks back
+/// if it is still able to.
+pub fn duel(
+    first: &mut units::Unit, second: &mut units::Unit, rules: &Ruleset
+) {
+    battle(first, second, rules);
+    if second.health > 0 && !second.frozen && !second.converted {
+        battle(second, first, rules);
+    }
+}
+
+
+/// The least health a defender needs to survive a battle with a defence
+/// bonus, and the battle at that health, if any health is enough.
+#[derive(Serialize)]
+pub struct SurvivalThreshold {
+    pub defence: DefenceBonus,
+    pub health: Option<i32>,
+    pub state: Option<Value>
+}
+
+
+/// Find the least health the defender needs to survive the attackers, in
+/// the order given, with each defence bonus. The bonus the defender
+/// already has is ignored.
+pub fn survival_thresholds(state: &BattleState) -> Vec<SurvivalThreshold> {
+    let bonuses = [
+        DefenceBonus::None, DefenceBonus::Bonus, DefenceBonus::Wall
+    ];
+    let mut thresholds = vec![];
+    for bonus in bonuses.iter() {
+        let mut threshold = SurvivalThreshold {
+            defence: *bonus,
+            health: Option::None,
+            state: Option::None
+        };
+        for health in 1..=state.defender.max_health {
+            let mut battle = state.clone();
+            let defender = &mut battle.defender;
+            defender.health = health;
+            defender.bonus = false;
+            defender.walled = false;
+            defender.city = units::City::Outside;
+            match bonus {
+                DefenceBonus::None => (),
+                DefenceBonus::Bonus => defender.apply_bonus(),
+                DefenceBonus::Wall => defender.apply_wall()
+            }
+            battle_many(&mut battle);
+            if !battle.defender_out() {
+                threshold.health = Option::Some(health);
+                threshold.state = Option::Some(battle.to_json());
+                break;
+            }
+        }
+        thresholds.push(threshold);
+    }
+    thresholds
+}
+
+
+/// One unit attacking another, once.
+#[derive(Deserialize)]
+pub struct DamageInput {
+    pub attacker: UnitInput,
+    pub defender: UnitInput
+}
+
+impl DamageInput {
+    /// The attack, as a battle, so it can be checked as battles are.
+    pub fn battle(&self) -> BattleInput {
+        BattleInput {
+            attackers: vec![self.attacker.clone()],
+            defender: self.defender.clone(),
+            defenders: vec![],
+            adjacent: vec![]
+        }
+    }
+}
+
+
+/// The outcome of a single attack.
+#[derive(Serialize)]
+pub struct DamagePreview {
+    pub damage: i32,
+    pub retaliation: i32,
+    pub attacker_health: i32,
+    pub defender_health: i32,
+    pub defender_killed: bool,
+    pub attacker_killed: bool
+}
+
+
+/// Calculate a single attack, without the rest of a battle.
+pub fn preview_damage(
+    attacker: &units::Unit, defender: &units::Unit, rules: &Ruleset
+) -> DamagePreview {
+    let (mut attacker, mut defender) = (attacker.clone(), defender.clone());
+    battle(&mut attacker, &mut defender, rules);
+    DamagePreview {
+        damage: defender.damage_taken,
+        retaliation: attacker.damage_taken,
+        attacker_health: attacker.health,
+        defender_health: defender.health,
+        defender_killed: defender.health <= 0,
+        attacker_killed: attacker.health <= 0
+    }
+}
+
+
+/// An attacker to send copies of against a defender, until it is killed.
+#[derive(Deserialize)]
+pub struct KillThresholdInput {
+    pub attacker: UnitInput,
+    pub defender: UnitInput,
+    // The most copies of the attacker to try.
+    #[serde(default)]
+    pub max: Option<usize>
+}
+
+impl KillThresholdInput {
+    /// A battle with some number of copies of the attacker.
+    pub fn battle(&self, count: usize) -> BattleInput {
+        BattleInput {
+            attackers: vec![self.attacker.clone(); count],
+            defender: self.defender.clone(),
+            defenders: vec![],
+            adjacent: vec![]
+        }
+    }
+}
+
+
+/// Find the fewest copies of an attacker which kill or convert the
+/// defender, attacking one after another, trying up to `max` copies.
+/// Returns the number of copies and the battle they fight, or `None` if
+/// even `max` copies aren't enough.
+pub fn kill_threshold(
+    input: &KillThresholdInput, max: usize, rules: &Ruleset
+) -> Result<Option<(usize, BattleState)>, UnknownUnit> {
+    for count in 1..=max {
+        let mut state = input.battle(count).to_state()?;
+        state.rules = *rules;
+        battle_many(&mut state);
+        if state.defender_out() {
+            return Ok(Option::Some((count, state)));
+        }
+    }
+    Ok(Option::None)
+}
+
+
+/// Two units wanting to know which of them should attack first.
+#[derive(Deserialize)]
+pub struct InitiativeInput {
+    pub unit: UnitInput,
+    pub enemy: UnitInput
+}
+
+impl InitiativeInput {
+    /// The two duels, with either unit attacking, as battles, so they can
+    /// be checked as battles are.
+    pub fn battles(&self) -> [BattleInput; 2] {
+        let duel = |attacker: &UnitInput, defender: &UnitInput| BattleInput {
+            attackers: vec![attacker.clone()],
+            defender: defender.clone(),
+            defenders: vec![],
+            adjacent: vec![]
+        };
+        [duel(&self.unit, &self.enemy), duel(&self.enemy, &self.unit)]
+    }
+}
+
+
+/// The health of both units after a duel.
+#[derive(Serialize)]
+pub struct DuelOutcome {
+    pub unit: i32,
+    pub enemy: i32
+}
+
+impl DuelOutcome {
+    fn new(unit: &units::Unit, enemy: &units::Unit) -> DuelOutcome {
+        DuelOutcome { unit: unit.health, enemy: enemy.health }
+    }
+
+    /// Compare two outcomes, from the point of view of the unit. Killing the
+    /// enemy matters most, then surviving, then the difference in health.
+    fn compare(&self, other: &DuelOutcome) -> Ordering {
+        (self.enemy <= 0).cmp(&(other.enemy <= 0))
+            .then((self.unit > 0).cmp(&(other.unit > 0)))
+            .then((self.unit - self.enemy).cmp(&(other.unit - other.enemy)))
+    }
+}
+
+
+/// Which unit it is better to have attack first.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Initiative {
+    AttackFirst,
+    DefendFirst,
+    Equal
+}
+
+
+/// The outcomes of a duel with either unit attacking first.
+#[derive(Serialize)]
+pub struct InitiativeResult {
+    pub attack_first: DuelOutcome,
+    pub defend_first: DuelOutcome,
+    pub verdict: Initiative
+}
+
+
+/// Work out whether a unit should attack an enemy, or let the enemy attack.
+pub fn initiative(
+    unit: &units::Unit, enemy: &units::Unit, rules: &Ruleset
+) -> InitiativeResult {
+    let (mut attacking, mut attacked) = (unit.clone(), enemy.clone());
+    duel(&mut attacking, &mut attacked, rules);
+    let attack_first = DuelOutcome::new(&attacking, &attacked);
+    let (mut defending, mut defended) = (unit.clone(), enemy.clone());
+    duel(&mut defended, &mut defending, rules);
+    let defend_first = DuelOutcome::new(&defending, &defended);
+    let verdict = match attack_first.compare(&defend_first) {
+        Ordering::Greater => Initiative::AttackFirst,
+        Ordering::Less => Initiative::DefendFirst,
+        Ordering::Equal => Initiative::Equal
+    };
+    InitiativeResult { attack_first, defend_first, verdict }
+}
+
+
+/// The defence bonus given to every defender in a matchup table.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+#[serde(rename_all = "snake_case")]
+pub enum DefenceBonus {
+    #[default]
+    None,
+    /// The bonus for terrain or a city.
+    Bonus,
+    /// The bonus for city walls.
+    Wall
+}
+
+
+/// Calculate one attack by each unit type on each unit type, both at full
+/// health. There is a row for each attacker, with a column for each
+/// defender.
+pub fn matchup_table(
+    unit_types: &[&units::UnitType], bonus: DefenceBonus, rules: &Ruleset
+) -> Vec<Vec<DamagePreview>> {
+    let mut table = vec![];
+    for attacker_type in unit_types.iter() {
+        let mut row = vec![];
+        for defender_type in unit_types.iter() {
+            let attacker = attacker_type.create_unit();
+            let mut defender = defender_type.create_unit();
+            match bonus {
+                DefenceBonus::None => (),
+                DefenceBonus::Bonus => defender.apply_bonus(),
+                DefenceBonus::Wall => defender.apply_wall()
+            }
+            row.push(preview_damage(&attacker, &defender, rules));
+        }
+        table.push(row);
+    }
+    table
+}
+
+
+/// Check if two attackers would do exactly the same in any battle, so
+/// swapping them in an order of attack makes no difference.
+fn interchangeable(first: &units::Unit, second: &units::Unit) -> bool {
+    let mut first = first.clone();
+    first.position = second.position;
+    first == *second
+}
+
+
+/// Group the attackers into classes of interchangeable attackers, giving
+/// the index of the first attacker in each attacker's class. Attackers
+/// which another attacker heals or boosts, or which are given in `distinct`
+/// by their index in the request, are never grouped.
+fn attacker_classes(
+    attackers: &[units::Unit], distinct: &[usize]
+) -> Vec<usize> {
+    let mut pinned = distinct.to_vec();
+    for attacker in attackers.iter() {
+        pinned.extend(attacker.heal_target);
+        pinned.extend(attacker.boost_target);
+    }
+    let is_pinned = |unit: &units::Unit| pinned.contains(&unit.position);
+    let mut classes = vec![];
+    for (idx, attacker) in attackers.iter().enumerate() {
+        let class = if is_pinned(attacker) {
+            idx
+        } else {
+            (0..idx).find(|other| {
+                !is_pinned(&attackers[*other])
+                    && interchangeable(&attackers[*other], attacker)
+            }).unwrap_or(idx)
+        };
+        classes.push(class);
+    }
+    classes
+}
+
+
+/// Options for a search for the best orders of attack.
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    // How many of the best orders to find.
+    pub count: usize,
+    pub perspective: Perspective,
+    pub objective: Objective,
+    pub constraints: Vec<Constraint>,
+    // Attackers, by index in the request, which are never treated as
+    // interchangeable with identical attackers, since the request cares
+    // about them in particular.
+    pub distinct: Vec<usize>,
+    pub mode: SearchMode,
+    // When to stop searching and give the best orders found so far.
+    pub deadline: Option<Instant>,
+    // Set when whoever asked for the search no longer wants the result.
+    pub cancelled: Option<Arc<AtomicBool>>,
+    // Where to report how far the search has got, for whoever is waiting.
+    pub progress: Option<Arc<SearchProgress>>
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            count: 1,
+            perspective: Perspective::Attacker,
+            objective: Objective::Overall,
+            constraints: vec![],
+            distinct: vec![],
+            mode: SearchMode::Exhaustive,
+            deadline: Option::None,
+            cancelled: Option::None,
+            progress: Option::None
+        }
+    }
+}
+
+impl SearchOptions {
+    /// Compare two states, returning `Greater` if the first is better.
+    fn compare(&self, this: &BattleState, other: &BattleState) -> Ordering {
+        self.perspective.compare(self.objective, this, other)
+    }
+
+    /// Check if the search has been cancelled.
+    fn is_cancelled(&self) -> bool {
+        match &self.cancelled {
+            Option::Some(cancelled) => {
+                cancelled.load(atomic::Ordering::Relaxed)
+            },
+            Option::None => false
+        }
+    }
+}
+
+
+/// How far a search for the best order of attack has got, shared with
+/// whoever is waiting for it while it runs.
+#[derive(Debug, Default)]
+pub struct SearchProgress {
+    permutations: AtomicU64,
+    // The best order found so far, as given by `order_json`.
+    best: Mutex<Option<Value>>
+}
+
+impl SearchProgress {
+    /// Note that some more orders have been tried.
+    fn tried(&self, permutations: u64) {
+        self.permutations.fetch_add(permutations, atomic::Ordering::Relaxed);
+    }
+
+    /// Note a new best order.
+    fn improved(&self, order: &[usize], state: &BattleState) {
+        *self.best.lock().unwrap() = Option::Some(order_json(order, state));
+    }
+
+    /// How many orders have been tried so far.
+    pub fn permutations(&self) -> u64 {
+        self.permutations.load(atomic::Ordering::Relaxed)
+    }
+
+    /// The best order found so far, if any.
+    pub fn best(&self) -> Option<Value> {
+        self.best.lock().unwrap().clone()
+    }
+}
+
+
+/// How much work a search for the best order of attack did.
+#[derive(Debug, Serialize)]
+pub struct SearchStats {
+    pub permutations_evaluated: u64,
+    // How many times an attacker acted. Orders which start the same share
+    // the simulation of their start, so this is far fewer than the number of
+    // orders times the number of attackers.
+    pub attacks_simulated: u64,
+    // How many partial orders were given up on, since no way of finishing
+    // them could beat the orders already found.
+    pub branches_pruned: u64,
+    pub elapsed_ms: u64,
+    // Whether every order which could be the best was tried, so the best
+    // order found is proven optimal.
+    pub exhaustive: bool,
+    // Whether the search finished before its deadline.
+    pub complete: bool
+}
+
+impl Default for SearchStats {
+    fn default() -> SearchStats {
+        SearchStats {
+            permutations_evaluated: 0,
+            attacks_simulated: 0,
+            branches_pruned: 0,
+            elapsed_ms: 0,
+            exhaustive: true,
+            complete: true
+        }
+    }
+}
+
+
+/// The most the defender could still lose to the attackers which have not
+/// acted yet, and whether one of them could convert it. `aura` is the
+/// multiplier to their attack so far. The damage is an upper bound: every
+/// attacker is assumed to be boosted, get every remaining aura, and meet no
+/// defence at all.
+fn remaining_threat(
+    state: &BattleState, acted: usize, aura: f32
+) -> (i32, bool) {
+    let remaining = &state.attackers[acted..];
+    let mut max_aura = aura;
+    for attacker in remaining.iter() {
+        max_aura *= 1.0 + attacker.attack_aura;
+    }
+    let mut damage = 0;
+    let mut can_convert = false;
+    for attacker in remaining.iter() {
+        if attacker.target != 0 {
+            continue;
+        }
+        let mut attack = (attacker.attack + 0.5) * max_aura;
+        if attacker.exploding {
+            attack *= state.rules.explode_damage.max(1.0);
+        }
+        damage += round_damage(
+            attack * state.rules.total_force, state.rules.rounding
+        );
+        can_convert |= attacker.can_convert;
+    }
+    (damage, can_convert)
+}
+
+
+/// Check if no way of finishing a battle, where the first `acted` attackers
+/// have acted, could beat `best`. This only looks at what happens to the
+/// defender, so for objectives which care about more than that, and for the
+/// defender's perspective, it is always false.
+fn cannot_beat(
+    options: &SearchOptions, state: &BattleState, acted: usize, aura: f32,
+    best: &BattleState
+) -> bool {
+    if let Perspective::Defender = options.perspective {
+        return false;
+    }
+    let defender = &state.defender;
+    let settled = defender.converted || defender.health <= 0;
+    let (damage, can_convert) = if settled {
+        (0, false)
+    } else {
+        remaining_threat(state, acted, aura)
+    };
+    match options.objective {
+        Objective::Overall => {
+            // The defender's conversion, then its health, as in the score.
+            let key = |converted: bool, health: i32| if converted {
+                (true, health)
+            } else {
+                (false, -health.max(0))
+            };
+            let possible = if can_convert {
+                key(true, defender.health)
+            } else {
+                key(defender.converted, defender.health - damage)
+            };
+            key(best.defender.converted, best.defender.health) > possible
+        },
+        Objective::KillDefender => {
+            let possible = settled || can_convert
+                || defender.health - damage <= 0;
+            best.defender_out() && !possible
+        },
+        _ => false
+    }
+}
+
+
+/// An order of attack which not every attacker has acted in yet.
+struct PartialOrder {
+    order: Vec<usize>,
+    // The battle so far, with the attackers in `order` first.
+    state: BattleState,
+    // The attackers yet to act, in index order.
+    rest: Vec<usize>,
+    aura: f32
+}
+
+impl PartialOrder {
+    /// Have the attacker at `place` in `rest` act next. Only its action is
+    /// simulated, on a copy of the battle so far.
+    fn then(&self, place: usize) -> PartialOrder {
+        let acted = self.order.len();
+        let mut state = self.state.clone();
+        state.attackers[acted..=acted + place].rotate_right(1);
+        let mut aura = self.aura;
+        act(&mut state, acted, &mut aura, &mut Option::None);
+        let mut order = self.order.clone();
+        order.push(self.rest[place]);
+        let mut rest = self.rest.clone();
+        rest.remove(place);
+        PartialOrder { order, state, rest, aura }
+    }
+}
+
+
+/// A search through the orders of attack, simulating each partial order
+/// once and sharing it between every order which starts with it.
+struct OrderSearch<'a> {
+    options: &'a SearchOptions,
+    classes: Vec<usize>,
+    // The best orders found so far, best first.
+    best: Vec<(Vec<usize>, BattleState)>,
+    stats: &'a mut SearchStats
+}
+
+impl OrderSearch<'_> {
+    /// Check if the search has passed its deadline, noting that it was cut
+    /// short if so.
+    fn out_of_time(&mut self) -> bool {
+        let out_of_time = match self.options.deadline {
+            Option::Some(deadline) => Instant::now() >= deadline,
+            Option::None => false
+        };
+        self.cut_short(out_of_time)
+    }
+
+    /// Check if the search has been cancelled, noting that it was cut short
+    /// if so.
+    fn cancelled(&mut self) -> bool {
+        let cancelled = self.options.is_cancelled();
+        self.cut_short(cancelled)
+    }
+
+    /// Note that the search was cut short, if it was.
+    fn cut_short(&mut self, stopped: bool) -> bool {
+        if stopped {
+            self.stats.complete = false;
+            self.stats.exhaustive = false;
+        }
+        stopped
+    }
+
+    /// The places in `rest` of the attackers which may act next: those the
+    /// constraints allow, leaving out any interchangeable with an attacker
+    /// before them.
+    fn candidates(&self, partial: &PartialOrder) -> Vec<usize> {
+        let acted = partial.order.len();
+        let rest = &partial.rest;
+        let remaining: Vec<&units::Unit> = partial.state.attackers[acted..]
+            .iter().collect();
+        let mut places = vec![];
+        for (place, next) in rest.iter().enumerate() {
+            let tried = rest[..place].iter().any(
+                |other| self.classes[*other] == self.classes[*next]
+            );
+            let allowed = self.options.constraints.iter().all(
+                |constraint| constraint.allows_next(
+                    acted, remaining[place], &remaining
+                )
+            );
+            if !tried && allowed {
+                places.push(place);
+            }
+        }
+        places
+    }
+
+    /// Have the attacker at `place` in a partial order's `rest` act next.
+    fn then(&mut self, partial: &PartialOrder, place: usize) -> PartialOrder {
+        self.stats.attacks_simulated += 1;
+        partial.then(place)
+    }
+
+    /// Try every way to finish an order of attack, depth first. Once out of
+    /// time, only the first way is tried, and only until an order is found.
+    fn extend(&mut self, partial: &PartialOrder) {
+        if partial.rest.is_empty() {
+            self.finish(&partial.state, &partial.order);
+            return;
+        }
+        let worst_kept = if self.best.len() == self.options.count {
+            self.best.last()
+        } else {
+            Option::None
+        };
+        if let Option::Some((_, worst_kept)) = worst_kept {
+            if cannot_beat(
+                self.options, &partial.state, partial.order.len(),
+                partial.aura, worst_kept
+            ) {
+                self.stats.branches_pruned += 1;
+                return;
+            }
+        }
+        let candidates = self.candidates(partial);
+        for (tried, place) in candidates.into_iter().enumerate() {
+            let stop = tried > 0 || !self.best.is_empty();
+            if self.cancelled() || (stop && self.out_of_time()) {
+                return;
+            }
+            let next = self.then(partial, place);
+            self.extend(&next);
+        }
+    }
+
+    /// Build orders an attacker at a time, keeping the `width` best partial
+    /// orders at each step. Partial orders which already break a constraint
+    /// on the result are dropped. Once out of time, only the best partial
+    /// order is kept.
+    fn beam(&mut self, start: PartialOrder, width: usize) {
+        let mut beam = vec![start];
+        for _ in 0..beam[0].rest.len() {
+            if self.cancelled() {
+                return;
+            }
+            let width = if self.out_of_time() { 1 } else { width };
+            let mut next_beam: Vec<PartialOrder> = vec![];
+            for partial in beam.iter() {
+                for place in self.candidates(partial) {
+                    let next = self.then(partial, place);
+                    if !self.options.constraints.iter().all(
+                        |constraint| constraint.allows_result(&next.state)
+                    ) {
+                        self.stats.branches_pruned += 1;
+                        continue;
+                    }
+                    let position = next_beam.iter().position(|other| {
+                        self.options.compare(&next.state, &other.state)
+                            == Ordering::Greater
+                    }).unwrap_or(next_beam.len());
+                    next_beam.insert(position, next);
+                    if next_beam.len() > width {
+                        next_beam.pop();
+                        self.stats.branches_pruned += 1;
+                    }
+                }
+            }
+            beam = next_beam;
+        }
+        for partial in beam.iter() {
+            self.finish(&partial.state, &partial.order);
+        }
+        self.stats.exhaustive = false;
+    }
+
+    /// Keep a finished order, if it meets the constraints and is one of the
+    /// best so far.
+    fn finish(&mut self, state: &BattleState, order: &[usize]) {
+        self.stats.permutations_evaluated += 1;
+        if let Option::Some(progress) = &self.options.progress {
+            progress.tried(1);
+        }
+        if !self.options.constraints.iter().all(
+            |constraint| constraint.allows_result(state)
+        ) {
+            return;
+        }
+        let position = self.best.iter().position(|(_, other)| {
+            self.options.compare(state, other) == Ordering::Greater
+        }).unwrap_or(self.best.len());
+        if position == 0 {
+            if let Option::Some(progress) = &self.options.progress {
+                progress.improved(order, state);
+            }
+        }
+        if position < self.options.count {
+            self.best.insert(position, (order.to_vec(), state.clone()));
+            self.best.truncate(self.options.count);
+        }
+    }
+}
+
+
+/// Calculate the best order of attack. Returns `None` if no order meets
+/// the constraints, or if the search was stopped before it found one.
+pub fn optimise_battle(
+    state: BattleState, options: &SearchOptions
+) -> Option<(Vec<usize>, BattleState)> {
+    let mut stats = SearchStats::default();
+    let options = SearchOptions { count: 1, ..options.clone() };
+    best_orders(state, &options, &mut stats).into_iter().next()
+}
+
+
+/// Calculate the best orders of attack which meet every constraint, best
+/// first. Orders which are as good as each other are kept in the order they
+/// were tried. Orders which break a constraint on the order, or which only
+/// swap interchangeable attackers, are skipped without being tried, as are
+/// partial orders which can't beat the orders already found. In heuristic
+/// mode, only the most promising partial orders are kept at each step.
+pub fn best_orders(
+    state: BattleState, options: &SearchOptions, stats: &mut SearchStats
+) -> Vec<(Vec<usize>, BattleState)> {
+    let mut distinct = options.distinct.clone();
+    for constraint in options.constraints.iter() {
+        distinct.extend(constraint.attackers());
+    }
+    let classes = attacker_classes(&state.attackers, &distinct);
+    let start = PartialOrder {
+        order: vec![],
+        rest: (0..state.attackers.len()).collect(),
+        state,
+        aura: 1.0
+    };
+    let mut search = OrderSearch { options, classes, best: vec![], stats };
+    match options.mode {
+        SearchMode::Exhaustive => search.extend(&start),
+        SearchMode::Heuristic => {
+            search.beam(start, BEAM_WIDTH.max(options.count));
+        }
+    }
+    search.best
+}
+
+
+/// Step to the next combination of `k` indices out of `n`, in increasing
+/// order. Returns `false` once every combination has been seen.
+fn next_combination(combination: &mut [usize], n: usize) -> bool {
+    let k = combination.len();
+    for idx in (0..k).rev() {
+        if combination[idx] < n - k + idx {
+            combination[idx] += 1;
+            for later in (idx + 1)..k {
+                combination[later] = combination[later - 1] + 1;
+            }
+            return true;
+        }
+    }
+    false
+}
+
+
+/// Find the fewest attackers which can kill or convert the defender, and
+/// the order they should attack in, meeting every constraint. Of the
+/// subsets that size, the best one is used. The order gives indices into the
+/// original attackers. Returns `None` if even every attacker together can't
+/// do it.
+pub fn minimise_attackers(
+    state: BattleState, options: &SearchOptions, stats: &mut SearchStats
+) -> Option<(Vec<usize>, BattleState)> {
+    // Progress is reported here rather than by each search, since those
+    // only see a subset of the attackers.
+    let progress = options.progress.clone();
+    let options = SearchOptions {
+        count: 1,
+        progress: Option::None,
+        ..options.clone()
+    };
+    let n = state.attackers.len();
+    for k in 1..=n {
+        let mut best: Option<(Vec<usize>, BattleState)> = Option::None;
+        let mut combination: Vec<usize> = (0..k).collect();
+        loop {
+            if options.is_cancelled() {
+                return Option::None;
+            }
+            let mut attackers = vec![];
+            for idx in combination.iter() {
+                attackers.push(state.attackers[*idx].clone());
+            }
+            let subset = BattleState {
+                attackers,
+                defender: state.defender.clone(),
+                defenders: state.defenders.clone(),
+                adjacent: state.adjacent.clone(),
+                rules: state.rules
+            };
+            let tried = stats.permutations_evaluated;
+            let found = best_orders(subset, &options, stats).pop();
+            if let Option::Some(progress) = &progress {
+                progress.tried(stats.permutations_evaluated - tried);
+            }
+            if let Option::Some((order, result)) = found {
+                let is_better = match &best {
+                    Option::Some((_, best_state)) => {
+                        options.compare(&result, best_state)
+                            == Ordering::Greater
+                    },
+                    Option::None => true
+                };
+                if result.defender_out() && is_better {
+                    let order: Vec<usize> = order.iter().map(
+                        |idx| combination[*idx]
+                    ).collect();
+                    if let Option::Some(progress) = &progress {
+                        progress.improved(&order, &result);
+                    }
+                    best = Option::Some((order, result));
+                }
+            }
+            if !next_combination(&mut combination, n) {
+                break;
+            }
+        }
+        if best.is_some() {
+            return best;
+        }
+    }
+    Option::None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small xorshift generator, so the property tests are repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// A value in `-span..=span`, kept small so that ties are common.
+        fn small(&mut self, span: i32) -> i32 {
+            (self.next() % (2 * span as u64 + 1)) as i32 - span
+        }
+
+        fn flag(&mut self) -> bool {
+            self.next() & 1 == 0
+        }
+    }
+
+    fn random_score(rng: &mut Rng) -> StateScore {
+        let attackers = (rng.next() % 3) as usize;
+        StateScore {
+            defender_converted: rng.flag(),
+            defender_health: rng.small(2),
+            defender_frozen: rng.flag(),
+            follow_up_kills: (rng.next() % 2) as u8,
+            defenders_out: (rng.next() % 2) as u8,
+            defenders_health: rng.small(1),
+            attackers_dead: (rng.next() % 2) as u8,
+            adjacent_health: rng.small(1),
+            attackers_total_health: rng.small(1),
+            attackers_health: (0..attackers).map(|_| rng.small(1)).collect()
+        }
+    }
+
+    fn random_scores() -> Vec<StateScore> {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        (0..60).map(|_| random_score(&mut rng)).collect()
+    }
+
+    #[test]
+    fn state_score_is_antisymmetric() {
+        let scores = random_scores();
+        for a in &scores {
+            for b in &scores {
+                assert_eq!(a.cmp(b), b.cmp(a).reverse(), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn state_score_is_transitive() {
+        let scores = random_scores();
+        for a in &scores {
+            for b in &scores {
+                for c in &scores {
+                    if a <= b && b <= c {
+                        assert!(a <= c, "{:?} {:?} {:?}", a, b, c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn damage_formula_rejects_invalid_forces() {
+        let rules = Ruleset::default();
+        assert!(damage_formula(1.0, 1.0, 2.0, 2.0, &rules).is_some());
+        assert!(damage_formula(0.0, 0.0, 2.0, 2.0, &rules).is_none());
+        assert!(damage_formula(f32::NAN, 1.0, 2.0, 2.0, &rules).is_none());
+        assert!(damage_formula(1.0, 1.0, f32::NAN, 2.0, &rules).is_none());
+        assert!(
+            damage_formula(f32::INFINITY, 1.0, 2.0, 2.0, &rules).is_none()
+        );
+    }
+
+    #[test]
+    fn rounding_follows_the_rules() {
+        assert_eq!(round_damage(2.5, Rounding::HalfUp), 3);
+        assert_eq!(round_damage(2.5, Rounding::HalfEven), 2);
+        assert_eq!(round_damage(3.5, Rounding::HalfEven), 4);
+        assert_eq!(round_damage(2.6, Rounding::HalfEven), 3);
+    }
+
+    #[test]
+    fn versions_fight_differently() {
+        use crate::rules::Version;
+        units::use_embedded_units();
+        let input: BattleInput = serde_json::from_value(json!({
+            "attackers": [{"unit": "doomux", "explode": true}],
+            "defender": {"unit": "warrior"}
+        })).unwrap();
+        let health = |version| {
+            let mut state = input.to_state().unwrap();
+            state.rules = Ruleset::for_version(version);
+            battle_many(&mut state);
+            state.defender.health
+        };
+        assert!(health(Version::Legacy) > health(Version::Moonrise));
+    }
+
+    fn unit(spec: &str) -> units::Unit {
+        units::use_embedded_units();
+        UnitInput::from_spec(spec).unwrap().to_unit().unwrap()
+    }
+
+    #[test]
+    fn health_is_clamped() {
+        assert_eq!(unit("warrior:25").health, 10);
+        assert_eq!(unit("warrior:-3").health, MIN_HEALTH);
+        assert_eq!(unit("warrior:6.6").health, 7);
+    }
+
+    #[test]
+    fn wounded_veteran_keeps_its_health() {
+        let veteran = unit("warrior:13:veteran");
+        assert_eq!(veteran.max_health, 15);
+        assert_eq!(veteran.health, 13);
+        assert_eq!(unit("warrior:20:veteran").health, 15);
+        assert_eq!(unit("warrior:veteran").health, 15);
+    }
+
+    fn state(attackers: Vec<units::Unit>, defender: &str) -> BattleState {
+        BattleState {
+            attackers,
+            defender: units::test_unit(defender),
+            defenders: vec![],
+            adjacent: vec![],
+            rules: Ruleset::default()
+        }
+    }
+
+    #[test]
+    fn overkill_does_not_outrank_attackers() {
+        let mut overkill = state(vec![units::test_unit("warrior")], "warrior");
+        let mut kill = overkill.clone();
+        overkill.defender.health = -5;
+        overkill.attackers[0].health = 5;
+        kill.defender.health = 0;
+        kill.attackers[0].health = 8;
+        assert!(kill.score() > overkill.score());
+    }
+
+    #[test]
+    fn surprise_prevents_retaliation() {
+        // No unit type has surprise yet, so it is given directly.
+        let mut attacker = units::test_unit("warrior");
+        let defender = units::test_unit("warrior");
+        assert!(check_retaliation(&attacker, &defender));
+        attacker.surprise = true;
+        assert!(!check_retaliation(&attacker, &defender));
+    }
+
+    #[test]
+    fn stiff_units_never_retaliate() {
+        let attacker = units::test_unit("warrior");
+        let mut kiton = units::test_unit("kiton");
+        assert!(kiton.stiff);
+        assert!(!check_retaliation(&attacker, &kiton));
+        kiton.forced_retaliation = Option::Some(true);
+        assert!(!check_retaliation(&attacker, &kiton));
+    }
+
+    fn splash_battle(target: usize) -> BattleState {
+        units::use_embedded_units();
+        let input: BattleInput = serde_json::from_value(json!({
+            "attackers": [{"unit": "firedragon", "target": target}],
+            "defender": {"unit": "warrior"},
+            "defenders": [{"unit": "warrior"}],
+            "adjacent": [{"unit": "warrior"}]
+        })).unwrap();
+        let mut state = input.to_state().unwrap();
+        battle_many(&mut state);
+        state
+    }
+
+    #[test]
+    fn splash_hits_the_targets_neighbours() {
+        let state = splash_battle(0);
+        assert!(state.adjacent[0].health < 10);
+        let state = splash_battle(1);
+        assert_eq!(state.adjacent[0].health, 10);
+    }
+
+    #[test]
+    fn adjacency_survives_skipping() {
+        units::use_embedded_units();
+        let input: BattleInput = serde_json::from_value(json!({
+            "attackers": [{"unit": "warrior"}],
+            "defender": {"unit": "warrior", "adjacent": [1]},
+            "adjacent": [{"unit": "nope"}, {"unit": "warrior"}]
+        })).unwrap();
+        let state = input.to_state_with(OnUnknown::Skip, DEFAULT_UNIT);
+        assert_eq!(state.unwrap().defender.adjacent, vec![0]);
+    }
+
+    #[test]
+    fn aura_boosts_later_attackers() {
+        let mut leader = units::test_unit("warrior");
+        leader.attack_aura = 0.5;
+        let warrior = units::test_unit("warrior");
+        let mut first = state(
+            vec![leader.clone(), warrior.clone(), warrior.clone()], "giant"
+        );
+        let mut last = state(vec![warrior.clone(), warrior, leader], "giant");
+        battle_many(&mut first);
+        battle_many(&mut last);
+        assert!(first.defender.health < last.defender.health);
+    }
+
+    #[test]
+    fn perspective_flips_comparison() {
+        let untouched = state(vec![units::test_unit("warrior")], "warrior");
+        let mut fought = untouched.clone();
+        battle_many(&mut fought);
+        let objective = Objective::Overall;
+        assert_eq!(
+            Perspective::Attacker.compare(objective, &fought, &untouched),
+            Ordering::Greater
+        );
+        assert_eq!(
+            Perspective::Defender.compare(objective, &fought, &untouched),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn impossible_constraints_give_no_order() {
+        let warrior = units::test_unit("warrior");
+        let mut battle = state(vec![warrior.clone(), warrior], "giant");
+        battle.attackers[1].position = 1;
+        let options = SearchOptions {
+            constraints: vec![Constraint::First(0), Constraint::First(1)],
+            ..SearchOptions::default()
+        };
+        assert!(optimise_battle(battle, &options).is_none());
+    }
+
+    #[test]
+    fn perspective_flips_best_order() {
+        let mut leader = units::test_unit("warrior");
+        leader.attack_aura = 0.5;
+        let battle = state(
+            vec![units::test_unit("warrior"), leader], "giant"
+        );
+        let attacker = SearchOptions::default();
+        let defender = SearchOptions {
+            perspective: Perspective::Defender,
+            ..SearchOptions::default()
+        };
+        let (order, _) = optimise_battle(battle.clone(), &attacker).unwrap();
+        assert_eq!(order, vec![1, 0]);
+        let (order, _) = optimise_battle(battle, &defender).unwrap();
+        assert_eq!(order, vec![0, 1]);
+    }
+}
@@ -0,0 +1,406 @@
+//! Engagements between armies, with several attackers and defenders.
+//!
+//! Each attacker attacks once, so the outcome depends on which defender each
+//! attacker is assigned to and the order they attack in. For small
+//! engagements every assignment is tried, with the order for each defender
+//! found by `calc::optimise_battle`. Otherwise, a greedy heuristic is used:
+//! each attacker in turn attacks whichever defender gives the best
+//! immediate trade. The same search assigns the attackers in a battle with
+//! several defenders.
+use std::cmp::Ordering;
+
+use crate::calc;
+use crate::rules::Ruleset;
+use crate::units::Unit;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+
+/// The most attackers to try every assignment for.
+const MAX_EXHAUSTIVE_ATTACKERS: usize = 6;
+
+
+/// The most assignments of attackers to defenders to try.
+const MAX_ASSIGNMENTS: usize = 1000;
+
+
+#[derive(Deserialize)]
+pub struct EngagementInput {
+    pub attackers: Vec<calc::UnitInput>,
+    pub defenders: Vec<calc::UnitInput>
+}
+
+impl EngagementInput {
+    pub fn to_units(
+        &self
+    ) -> Result<(Vec<Unit>, Vec<Unit>), calc::UnknownUnit> {
+        let mut attackers = vec![];
+        for attacker in self.attackers.iter() {
+            attackers.push(attacker.to_unit()?);
+        }
+        let mut defenders = vec![];
+        for defender in self.defenders.iter() {
+            defenders.push(defender.to_unit()?);
+        }
+        Ok((attackers, defenders))
+    }
+
+    /// The engagement as a battle, with every attacker targeting the first
+    /// defender, so it can be checked as battles are. Returns `None` if
+    /// there are no defenders.
+    pub fn battle(&self) -> Option<calc::BattleInput> {
+        let (defender, defenders) = self.defenders.split_first()?;
+        Option::Some(calc::BattleInput {
+            attackers: self.attackers.iter().map(|attacker| {
+                calc::UnitInput { target: 0, ..attacker.clone() }
+            }).collect(),
+            defender: defender.clone(),
+            defenders: defenders.to_vec(),
+            adjacent: vec![]
+        })
+    }
+}
+
+
+/// A single attack, as indices into the attackers and defenders.
+#[derive(Serialize, Clone, Copy)]
+pub struct Attack {
+    pub attacker: usize,
+    pub defender: usize
+}
+
+
+/// The value, in stars, of the units killed and lost in an engagement.
+/// Converted defenders count as killed.
+#[derive(Serialize)]
+pub struct Trade {
+    pub stars_killed: u32,
+    pub stars_lost: u32,
+    pub net: i32
+}
+
+
+/// The outcome of an engagement.
+pub struct Engagement {
+    /// The attacks made, in the order to make them.
+    pub attacks: Vec<Attack>,
+    pub attackers: Vec<Unit>,
+    pub defenders: Vec<Unit>,
+    /// Whether every assignment was tried, rather than using the heuristic.
+    pub exhaustive: bool
+}
+
+impl Engagement {
+    pub fn trade(&self) -> Trade {
+        let mut stars_killed = 0;
+        for defender in self.defenders.iter() {
+            if is_out(defender) {
+                stars_killed += u32::from(defender.cost);
+            }
+        }
+        let mut stars_lost = 0;
+        for attacker in self.attackers.iter() {
+            if attacker.health <= 0 {
+                stars_lost += u32::from(attacker.cost);
+            }
+        }
+        Trade {
+            stars_killed,
+            stars_lost,
+            net: stars_killed as i32 - stars_lost as i32
+        }
+    }
+
+    /// Compare two engagements, returning `Greater` if this one is better for
+    /// the attackers. The trade matters most, then the health left to the
+    /// defenders, then the health left to the attackers.
+    fn compare(&self, other: &Engagement) -> Ordering {
+        self.trade().net.cmp(&other.trade().net)
+            .then(other.defenders_health().cmp(&self.defenders_health()))
+            .then(self.attackers_health().cmp(&other.attackers_health()))
+    }
+
+    /// The total health of the defenders which are still fighting.
+    fn defenders_health(&self) -> i32 {
+        let mut health = 0;
+        for defender in self.defenders.iter() {
+            if !is_out(defender) {
+                health += defender.health;
+            }
+        }
+        health
+    }
+
+    /// The total health of the attackers which are still alive.
+    fn attackers_health(&self) -> i32 {
+        let mut health = 0;
+        for attacker in self.attackers.iter() {
+            if attacker.health > 0 {
+                health += attacker.health;
+            }
+        }
+        health
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut attackers_health = vec![];
+        for attacker in &self.attackers {
+            attackers_health.push(attacker.health);
+        }
+        let mut defenders = vec![];
+        for defender in &self.defenders {
+            defenders.push(json!({
+                "health": defender.health,
+                "frozen": defender.frozen,
+                "converted": defender.converted
+            }));
+        }
+        json!({
+            "attacks": self.attacks,
+            "attackers": attackers_health,
+            "defenders": defenders,
+            "trade": self.trade(),
+            "exhaustive": self.exhaustive
+        })
+    }
+}
+
+
+/// Check if a defender has been killed or converted.
+fn is_out(defender: &Unit) -> bool {
+    defender.health <= 0 || defender.converted
+}
+
+
+/// Calculate the outcome of attackers attacking the defenders they are
+/// assigned to, in the best order for each defender.
+/// `targets` gives the index of the defender for each attacker. Returns
+/// `None` if no order of attack could be found for a defender.
+fn simulate_assignment(
+    attackers: &[Unit], defenders: &[Unit], targets: &[usize],
+    rules: &Ruleset
+) -> Option<Engagement> {
+    let mut engagement = Engagement {
+        attacks: vec![],
+        attackers: attackers.to_vec(),
+        defenders: defenders.to_vec(),
+        exhaustive: true
+    };
+    for (defender_idx, defender) in defenders.iter().enumerate() {
+        let mut group = vec![];
+        for (attacker_idx, target) in targets.iter().enumerate() {
+            if *target == defender_idx {
+                group.push(attacker_idx);
+            }
+        }
+        if group.is_empty() {
+            continue;
+        }
+        let mut state_attackers = vec![];
+        for idx in group.iter() {
+            state_attackers.push(attackers[*idx].clone());
+        }
+        let mut state = calc::BattleState {
+            attackers: state_attackers,
+            defender: defender.clone(),
+            defenders: vec![],
+            adjacent: vec![],
+            rules: *rules
+        };
+        let order = if group.len() > 1 {
+            let (order, best_state) = calc::optimise_battle(
+                state, &calc::SearchOptions::default()
+            )?;
+            state = best_state;
+            order
+        } else {
+            calc::battle_many(&mut state);
+            vec![0]
+        };
+        for (position, group_idx) in order.iter().enumerate() {
+            let attacker_idx = group[*group_idx];
+            engagement.attackers[attacker_idx] = state.attackers[position]
+                .clone();
+            engagement.attacks.push(Attack {
+                attacker: attacker_idx,
+                defender: defender_idx
+            });
+        }
+        engagement.defenders[defender_idx] = state.defender;
+    }
+    Option::Some(engagement)
+}
+
+
+/// Try every assignment of attackers to defenders.
+fn optimise_exhaustive(
+    attackers: &[Unit], defenders: &[Unit], rules: &Ruleset
+) -> Option<Engagement> {
+    let mut targets = vec![0; attackers.len()];
+    let mut best = simulate_assignment(
+        attackers, defenders, &targets, rules
+    )?;
+    loop {
+        // Step to the next assignment, counting in base `defenders.len()`.
+        let mut idx = 0;
+        loop {
+            if idx == targets.len() {
+                return Option::Some(best);
+            }
+            targets[idx] += 1;
+            if targets[idx] < defenders.len() {
+                break;
+            }
+            targets[idx] = 0;
+            idx += 1;
+        }
+        let engagement = simulate_assignment(
+            attackers, defenders, &targets, rules
+        )?;
+        if engagement.compare(&best) == Ordering::Greater {
+            best = engagement;
+        }
+    }
+}
+
+
+/// Have each attacker in turn attack the defender which gives the best
+/// immediate trade, breaking ties by the proportion of health removed.
+fn optimise_greedy(
+    attackers: &[Unit], defenders: &[Unit], rules: &Ruleset
+) -> Engagement {
+    let mut engagement = Engagement {
+        attacks: vec![],
+        attackers: attackers.to_vec(),
+        defenders: defenders.to_vec(),
+        exhaustive: false
+    };
+    for attacker_idx in 0..attackers.len() {
+        let mut best: Option<(usize, Unit, Unit, i32, f32)> = Option::None;
+        let defenders = engagement.defenders.iter().enumerate();
+        for (defender_idx, defender) in defenders {
+            if is_out(defender) {
+                continue;
+            }
+            let mut attacker = engagement.attackers[attacker_idx].clone();
+            let mut result = defender.clone();
+            calc::battle(&mut attacker, &mut result, rules);
+            let mut gain = 0;
+            if is_out(&result) {
+                gain += i32::from(result.cost);
+            }
+            if attacker.health <= 0 {
+                gain -= i32::from(attacker.cost);
+            }
+            let damage = (
+                defender.health - result.health
+            ) as f32 / defender.max_health as f32;
+            let is_better = match &best {
+                Option::Some((_, _, _, best_gain, best_damage)) => {
+                    gain.cmp(best_gain).then(damage.total_cmp(best_damage))
+                        == Ordering::Greater
+                },
+                Option::None => true
+            };
+            if is_better {
+                best = Option::Some(
+                    (defender_idx, attacker, result, gain, damage)
+                );
+            }
+        }
+        if let Option::Some((defender_idx, attacker, defender, _, _)) = best {
+            engagement.attackers[attacker_idx] = attacker;
+            engagement.defenders[defender_idx] = defender;
+            engagement.attacks.push(Attack {
+                attacker: attacker_idx,
+                defender: defender_idx
+            });
+        }
+    }
+    engagement
+}
+
+
+/// Calculate the best assignment and order of attackers against defenders.
+/// Every assignment is tried if there are few enough, otherwise the greedy
+/// heuristic is used. Returns `None` if no order of attack could be found.
+pub fn optimise_engagement(
+    attackers: &[Unit], defenders: &[Unit], rules: &Ruleset
+) -> Option<Engagement> {
+    let _span = tracing::info_span!(
+        "optimise_engagement",
+        attackers = attackers.len(),
+        defenders = defenders.len()
+    ).entered();
+    if attackers.is_empty() || defenders.is_empty() {
+        return Option::Some(Engagement {
+            attacks: vec![],
+            attackers: attackers.to_vec(),
+            defenders: defenders.to_vec(),
+            exhaustive: true
+        });
+    }
+    let assignments = defenders.len().checked_pow(attackers.len() as u32);
+    let small_enough = match assignments {
+        Option::Some(count) => count <= MAX_ASSIGNMENTS,
+        Option::None => false
+    };
+    if attackers.len() <= MAX_EXHAUSTIVE_ATTACKERS && small_enough {
+        optimise_exhaustive(attackers, defenders, rules)
+    } else {
+        Option::Some(optimise_greedy(attackers, defenders, rules))
+    }
+}
+
+
+/// The best assignment of the attackers in a battle to its defenders.
+pub struct Assignment {
+    /// The attackers which attack, as indices, in the order to attack.
+    pub order: Vec<usize>,
+    /// The index of the defender each attacker should target, if any.
+    pub targets: Vec<Option<usize>>,
+    /// The battle after the attacks, with the attackers in `order`.
+    pub state: calc::BattleState
+}
+
+impl Assignment {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "order": self.order,
+            "targets": self.targets,
+            "state": self.state.to_json()
+        })
+    }
+}
+
+
+/// Choose which defender each attacker in a battle should attack, and the
+/// order to attack in, using the best engagement. Attackers left with no
+/// defender worth attacking are left out of the order. Returns `None` if no
+/// order of attack could be found.
+pub fn assign_battle(state: &calc::BattleState) -> Option<Assignment> {
+    let mut defenders = vec![state.defender.clone()];
+    defenders.extend(state.defenders.iter().cloned());
+    let engagement = optimise_engagement(
+        &state.attackers, &defenders, &state.rules
+    )?;
+    let mut order = vec![];
+    let mut targets = vec![Option::None; state.attackers.len()];
+    let mut attackers = vec![];
+    for attack in engagement.attacks.iter() {
+        order.push(attack.attacker);
+        targets[attack.attacker] = Option::Some(attack.defender);
+        let mut attacker = state.attackers[attack.attacker].clone();
+        attacker.target = attack.defender;
+        attackers.push(attacker);
+    }
+    let mut result = calc::BattleState {
+        attackers,
+        defender: state.defender.clone(),
+        defenders: state.defenders.clone(),
+        adjacent: state.adjacent.clone(),
+        rules: state.rules
+    };
+    calc::battle_many(&mut result);
+    Option::Some(Assignment { order, targets, state: result })
+}
@@ -0,0 +1,20 @@
+//! The battle calculator behind the API: the unit types, and the
+//! calculations of battles between them. It doesn't depend on Rocket, so
+//! bots and command line tools can use it without running a server. With
+//! the `rocket` feature, the query types can be read from Rocket forms.
+//!
+//! Without the default `fetch` feature, it builds for WebAssembly, where the
+//! unit data is built in or given by `units::provide_units`. The `wasm`
+//! crate exports it to JavaScript.
+#[macro_use] extern crate lazy_static;
+#[cfg(feature = "rocket")]
+#[macro_use] extern crate rocket;
+#[macro_use] extern crate serde_json;
+
+pub mod abilities;
+pub mod bindings;
+pub mod calc;
+pub mod engagement;
+pub mod rules;
+pub mod simulate;
+pub mod units;
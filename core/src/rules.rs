@@ -0,0 +1,234 @@
+//! Combat rules which have changed between versions of the game.
+use std::env;
+use serde::Serialize;
+use serde_json::Value;
+
+
+lazy_static! {
+    /// Whether frozen defenders keep their defence bonus by default, as they
+    /// did in older versions of the calculator. Set by the `LEGACY_FREEZE`
+    /// environment variable.
+    static ref LEGACY_FREEZE: bool = env::var("LEGACY_FREEZE").is_ok();
+
+    /// The constants for the latest version of the game, which can be set
+    /// by the `TOTAL_FORCE`, `SPLASH_DAMAGE`, `EXPLODE_DAMAGE`,
+    /// `DEFENCE_BONUS` and `WALL_BONUS` environment variables to follow
+    /// balance changes.
+    pub static ref LATEST: Ruleset = init_latest();
+}
+
+
+/// Read a constant from an environment variable, if it is set.
+fn env_constant(name: &str, default: f32) -> f32 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(
+            |_| panic!("{} must be a number, not '{}'.", name, value)
+        ),
+        Err(_) => default
+    }
+}
+
+
+fn init_latest() -> Ruleset {
+    let rules = Ruleset {
+        total_force: env_constant("TOTAL_FORCE", 4.5),
+        splash_damage: env_constant("SPLASH_DAMAGE", 0.5),
+        explode_damage: env_constant("EXPLODE_DAMAGE", 1.0),
+        defence_bonus: env_constant("DEFENCE_BONUS", 1.5),
+        wall_bonus: env_constant("WALL_BONUS", 4.0),
+        rounding: Rounding::HalfUp,
+        frozen_keeps_bonus: false
+    };
+    if let Err(error) = rules.check() {
+        panic!("{}", error.message());
+    }
+    rules
+}
+
+
+/// An error for a rule constant set to a value which makes no sense.
+#[derive(Debug)]
+pub struct InvalidConstant {
+    pub constant: &'static str,
+    pub value: f32
+}
+
+impl InvalidConstant {
+    fn message(&self) -> String {
+        format!(
+            "The '{}' constant can't be {}.", self.constant, self.value
+        )
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": self.message(),
+            "constant": self.constant
+        })
+    }
+}
+
+
+/// The rules asked for by a request: a version of the game, and any of its
+/// constants to replace.
+#[cfg_attr(feature = "rocket", derive(FromForm))]
+pub struct RulesQuery {
+    pub ruleset: Option<Version>,
+    pub total_force: Option<f32>,
+    pub splash_damage: Option<f32>,
+    pub explode_damage: Option<f32>,
+    pub defence_bonus: Option<f32>,
+    pub wall_bonus: Option<f32>
+}
+
+
+/// A version of the game's combat rules.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "rocket", derive(FromFormField))]
+pub enum Version {
+    /// Before the Moonrise update.
+    Legacy,
+    Moonrise,
+    /// The current version of the game.
+    Latest
+}
+
+
+/// How damage is rounded to whole HP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    /// Halves are rounded up.
+    HalfUp,
+    /// Halves are rounded to the nearest even number, as the game did
+    /// before the Moonrise update.
+    HalfEven
+}
+
+
+/// The constants and behaviour used to calculate battles.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Ruleset {
+    /// The damage formula's constant, shared between the attack and defence
+    /// forces.
+    pub total_force: f32,
+    /// The proportion of a normal attack's damage that splash deals.
+    pub splash_damage: f32,
+    /// The proportion of a normal attack's damage that an explosion deals
+    /// to each unit it hits.
+    pub explode_damage: f32,
+    /// The defence multiplier for a unit on terrain it gets a bonus on, or
+    /// fortified in a city.
+    pub defence_bonus: f32,
+    /// The defence multiplier for a unit fortified in a walled city.
+    pub wall_bonus: f32,
+    /// How damage is rounded to whole HP.
+    pub rounding: Rounding,
+    /// Whether frozen units keep their defence bonus.
+    pub frozen_keeps_bonus: bool
+}
+
+impl Ruleset {
+    pub fn for_version(version: Version) -> Ruleset {
+        match version {
+            Version::Legacy => Ruleset {
+                total_force: 4.5,
+                splash_damage: 0.5,
+                explode_damage: 0.5,
+                defence_bonus: 1.5,
+                wall_bonus: 4.0,
+                rounding: Rounding::HalfEven,
+                frozen_keeps_bonus: true
+            },
+            Version::Moonrise => Ruleset {
+                total_force: 4.5,
+                splash_damage: 0.5,
+                explode_damage: 1.0,
+                defence_bonus: 1.5,
+                wall_bonus: 4.0,
+                rounding: Rounding::HalfUp,
+                frozen_keeps_bonus: false
+            },
+            Version::Latest => *LATEST
+        }
+    }
+
+    /// Check that every constant makes sense. Splash and explosions may do
+    /// no damage, but the other constants must be positive.
+    pub fn check(&self) -> Result<(), InvalidConstant> {
+        let constants = [
+            ("total_force", self.total_force, false),
+            ("splash_damage", self.splash_damage, true),
+            ("explode_damage", self.explode_damage, true),
+            ("defence_bonus", self.defence_bonus, false),
+            ("wall_bonus", self.wall_bonus, false)
+        ];
+        for (constant, value, can_be_zero) in constants.iter() {
+            let valid = value.is_finite()
+                && (*value > 0.0 || (*can_be_zero && *value == 0.0));
+            if !valid {
+                return Err(InvalidConstant {
+                    constant, value: *value
+                });
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Get the ruleset for a version, if one is given. Otherwise, use the
+    /// latest rules, unless the server is set to keep legacy freezing.
+    pub fn for_optional_version(version: Option<Version>) -> Ruleset {
+        match version {
+            Option::Some(version) => Ruleset::for_version(version),
+            Option::None => Ruleset::default()
+        }
+    }
+}
+
+impl Default for Ruleset {
+    fn default() -> Ruleset {
+        let mut rules = Ruleset::for_version(Version::Latest);
+        rules.frozen_keeps_bonus = *LEGACY_FREEZE;
+        rules
+    }
+}
+
+
+impl RulesQuery {
+    /// Ask for the rules of a version of the game, with none of its
+    /// constants replaced.
+    pub fn of_version(version: Version) -> RulesQuery {
+        RulesQuery {
+            ruleset: Option::Some(version),
+            total_force: Option::None,
+            splash_damage: Option::None,
+            explode_damage: Option::None,
+            defence_bonus: Option::None,
+            wall_bonus: Option::None
+        }
+    }
+
+    /// Get the ruleset for the version asked for, with any constants given
+    /// replaced.
+    pub fn to_ruleset(&self) -> Result<Ruleset, InvalidConstant> {
+        let mut rules = Ruleset::for_optional_version(self.ruleset);
+        if let Option::Some(total_force) = self.total_force {
+            rules.total_force = total_force;
+        }
+        if let Option::Some(splash_damage) = self.splash_damage {
+            rules.splash_damage = splash_damage;
+        }
+        if let Option::Some(explode_damage) = self.explode_damage {
+            rules.explode_damage = explode_damage;
+        }
+        if let Option::Some(defence_bonus) = self.defence_bonus {
+            rules.defence_bonus = defence_bonus;
+        }
+        if let Option::Some(wall_bonus) = self.wall_bonus {
+            rules.wall_bonus = wall_bonus;
+        }
+        rules.check()?;
+        Ok(rules)
+    }
+}
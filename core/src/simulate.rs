@@ -0,0 +1,204 @@
+//! Battles lasting several turns, with the defenders healing in between.
+//!
+//! Each turn, every attacker which is still alive attacks in order, then
+//! every defender still fighting heals. A frozen defender spends its turn
+//! thawing out instead of healing. Defenders which are converted join the
+//! attackers from the next turn on, and attackers with enough kills are
+//! promoted to veterans at the end of the turn.
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::calc;
+use crate::units::Unit;
+
+
+/// The most turns to simulate.
+pub const MAX_TURNS: u8 = 20;
+
+
+/// How much a defender heals each turn outside of its own territory.
+const ENEMY_TERRITORY_HEAL: i32 = 2;
+
+
+/// How much a defender heals each turn in its own territory.
+const FRIENDLY_TERRITORY_HEAL: i32 = 4;
+
+
+fn default_turns() -> u8 {
+    3
+}
+
+
+#[derive(Deserialize)]
+pub struct SimulateInput {
+    #[serde(flatten)]
+    pub battle: calc::BattleInput,
+    #[serde(default = "default_turns")]
+    pub turns: u8,
+    // Whether the defenders are in their own territory, so heal faster.
+    #[serde(default)]
+    pub friendly_territory: bool,
+    // How much the defenders heal each turn, overriding the territory.
+    // Rounded to whole HP.
+    #[serde(default)]
+    pub heal: Option<f32>
+}
+
+impl SimulateInput {
+    /// The amount to heal by, if it is given and is negative or not a
+    /// finite number.
+    pub fn invalid_heal(&self) -> Option<f32> {
+        self.heal.filter(|heal| !heal.is_finite() || *heal < 0.0)
+    }
+
+    /// How much the defenders heal each turn.
+    pub fn heal_amount(&self) -> i32 {
+        match self.heal {
+            Option::Some(heal) => heal.round() as i32,
+            Option::None => if self.friendly_territory {
+                FRIENDLY_TERRITORY_HEAL
+            } else {
+                ENEMY_TERRITORY_HEAL
+            }
+        }
+    }
+}
+
+
+/// A converted defender which has joined the attackers.
+#[derive(Serialize)]
+pub struct Recruit {
+    /// The turn it was converted on.
+    pub turn: u8,
+    /// Its index as a defender.
+    pub defender: usize,
+    /// Its index as an attacker.
+    pub attacker: usize
+}
+
+
+/// An attacker which was promoted to a veteran.
+#[derive(Serialize)]
+pub struct Promotion {
+    pub turn: u8,
+    pub attacker: usize
+}
+
+
+/// The outcome of a simulation.
+pub struct Simulation {
+    /// The state of the battle at the end of each turn.
+    pub turns: Vec<Value>,
+    /// The turn the defender was killed on, counting from 1, if it was.
+    pub killed_on: Option<u8>,
+    pub recruits: Vec<Recruit>,
+    pub promotions: Vec<Promotion>
+}
+
+impl Simulation {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "turns": self.turns,
+            "killed_on": self.killed_on,
+            "recruits": self.recruits,
+            "promotions": self.promotions
+        })
+    }
+}
+
+
+/// Check if a defender is still fighting.
+fn is_fighting(defender: &Unit) -> bool {
+    defender.health > 0 && !defender.converted
+}
+
+
+/// Get every defender in a battle, in target order.
+fn all_defenders(state: &calc::BattleState) -> Vec<&Unit> {
+    let mut defenders = vec![&state.defender];
+    defenders.extend(state.defenders.iter());
+    defenders
+}
+
+
+/// Heal a defender between turns, or thaw it out if it is frozen.
+fn heal_defender(defender: &mut Unit, heal: i32) {
+    if defender.health <= 0 || defender.converted {
+        return;
+    }
+    if defender.frozen {
+        defender.frozen = false;
+    } else {
+        defender.heal(heal);
+    }
+}
+
+
+/// Simulate a battle over several turns, stopping early once every
+/// defender has been killed or converted.
+pub fn simulate(
+    state: &mut calc::BattleState, turns: u8, heal: i32
+) -> Simulation {
+    let _span = tracing::info_span!(
+        "simulate", attackers = state.attackers.len(), turns
+    ).entered();
+    // Auras change the attack of attackers, so reset it every turn.
+    let mut attacks: Vec<f32> = state.attackers.iter().map(
+        |attacker| attacker.attack
+    ).collect();
+    let mut simulation = Simulation {
+        turns: vec![],
+        killed_on: Option::None,
+        recruits: vec![],
+        promotions: vec![]
+    };
+    let mut recruited: Vec<usize> = vec![];
+    for turn in 1..=turns.min(MAX_TURNS) {
+        for (attacker, attack) in state.attackers.iter_mut().zip(&attacks) {
+            attacker.attack = *attack;
+        }
+        calc::battle_many(state);
+        if simulation.killed_on.is_none() && state.defender.health <= 0 {
+            simulation.killed_on = Option::Some(turn);
+        }
+        for (idx, attacker) in state.attackers.iter_mut().enumerate() {
+            if attacker.health > 0 && attacker.can_promote() {
+                attacker.promote();
+                simulation.promotions.push(Promotion { turn, attacker: idx });
+            }
+        }
+        heal_defender(&mut state.defender, heal);
+        for defender in state.defenders.iter_mut() {
+            heal_defender(defender, heal);
+        }
+        let defenders = all_defenders(state);
+        let target = defenders.iter().position(|unit| is_fighting(unit));
+        let mut recruits = vec![];
+        for (idx, defender) in defenders.iter().enumerate() {
+            if defender.converted && !recruited.contains(&idx) {
+                let mut recruit = (*defender).clone();
+                recruit.converted = false;
+                recruit.follow_up = vec![];
+                recruit.heal_target = Option::None;
+                recruit.target = target.unwrap_or(0);
+                recruits.push((idx, recruit));
+            }
+        }
+        for (idx, mut recruit) in recruits.into_iter() {
+            recruited.push(idx);
+            recruit.position = state.attackers.len();
+            attacks.push(recruit.attack);
+            simulation.recruits.push(Recruit {
+                turn,
+                defender: idx,
+                attacker: state.attackers.len()
+            });
+            state.attackers.push(recruit);
+        }
+        simulation.turns.push(state.to_json());
+        if target.is_none() {
+            break;
+        }
+    }
+    simulation
+}
@@ -0,0 +1,995 @@
+//! Tool for loading the unit types from the JSON file, and accessing them.
+extern crate serde;
+extern crate serde_json;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::{env, fs};
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "fetch")]
+use std::time::Duration;
+use crate::abilities;
+use crate::rules::Ruleset;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+
+/// A copy of the unit data built into the binary, used if it can't be
+/// fetched or read from elsewhere. Servers and bindings should use this,
+/// rather than reading the file themselves.
+pub const EMBEDDED_UNITS: &str = include_str!("../units.json");
+
+
+/// How long to wait for the unit data to be fetched from a URL.
+#[cfg(feature = "fetch")]
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+lazy_static! {
+    pub static ref UNIT_LIST: UnitTypeList = init_unit_list();
+}
+
+
+/// Unit data given by `provide_units`, used instead of loading it.
+static PROVIDED_UNITS: OnceLock<String> = OnceLock::new();
+
+
+/// The file given by `set_units_file`, to load the unit data from.
+#[cfg(not(target_arch = "wasm32"))]
+static UNITS_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+
+/// Whether `UNIT_LIST` has been loaded, after which the unit data can't be
+/// replaced.
+static UNITS_LOADED: AtomicBool = AtomicBool::new(false);
+
+
+/// How many kills a unit needs to be promoted to a veteran.
+pub const VETERAN_KILLS: u8 = 3;
+
+
+/// Whether a unit is in a city, and whether the city has walls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum City {
+    #[default]
+    Outside,
+    Unwalled,
+    Walled
+}
+
+
+/// The terrain a unit is on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Terrain {
+    #[default]
+    Field,
+    Forest,
+    Mountain,
+    Water,
+    City
+}
+
+impl Terrain {
+    /// The tech a tribe needs to get a defence bonus on this terrain, if
+    /// there is one. Cities are handled separately, by `City`.
+    fn defence_tech(self) -> Option<&'static str> {
+        match self {
+            Terrain::Forest => Option::Some("archery"),
+            Terrain::Mountain => Option::Some("climbing"),
+            Terrain::Water => Option::Some("aquatism"),
+            Terrain::Field | Terrain::City => Option::None
+        }
+    }
+}
+
+
+/// Utility to read a flag from a set of flags.
+fn read_flag(flags: u8, flag_num: u8) -> bool {
+    ((1 << flag_num) & flags) != 0
+}
+
+
+/// Conditions for choosing unit types from the list. Each condition which
+/// is given must be met.
+#[cfg_attr(feature = "rocket", derive(FromForm))]
+pub struct UnitFilter {
+    // Only unit types the tribe can use.
+    pub tribe: Option<String>,
+    pub ability: Option<String>,
+    pub ranged: Option<bool>,
+    pub hidden: Option<bool>,
+    pub min_attack: Option<f32>,
+    pub max_attack: Option<f32>,
+    pub min_defence: Option<f32>,
+    pub max_defence: Option<f32>
+}
+
+
+/// A single unit type, eg. Catapult, loaded from JSON.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct UnitType {
+    id: String,
+    display_name: String,
+    aliases: Vec<String>,
+    hidden: bool,
+    health: i32,
+    attack: f32,
+    defence: f32,
+    range: u8,
+    // The value of the unit, in stars.
+    #[serde(default)]
+    cost: u8,
+    abilities: Vec<String>,
+    // The special tribe the unit belongs to, or none if any tribe can use it.
+    #[serde(default)]
+    tribe: Option<String>,
+    // The IDs of the unit types this one can be upgraded into.
+    #[serde(default)]
+    upgrades: Vec<String>,
+    // Whether the unit never retaliates. Set from the abilities on load.
+    #[serde(skip_deserializing)]
+    stiff: bool
+}
+
+impl UnitType {
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    pub fn abilities(&self) -> &[String] {
+        &self.abilities
+    }
+
+    pub fn tribe(&self) -> Option<&String> {
+        self.tribe.as_ref()
+    }
+
+    /// Check if a tribe can use this unit type.
+    pub fn available_to(&self, tribe: &str) -> bool {
+        match &self.tribe {
+            Option::Some(own_tribe) => own_tribe == tribe,
+            Option::None => true
+        }
+    }
+
+    /// Check if this unit type meets every condition in a filter.
+    pub fn matches(&self, filter: &UnitFilter) -> bool {
+        filter.tribe.as_ref().is_none_or(|tribe| self.available_to(tribe))
+            && filter.ability.as_ref().is_none_or(
+                |ability| self.abilities.contains(ability)
+            )
+            && filter.ranged.is_none_or(|ranged| (self.range > 1) == ranged)
+            && filter.hidden.is_none_or(|hidden| self.hidden == hidden)
+            && filter.min_attack.is_none_or(|min| self.attack >= min)
+            && filter.max_attack.is_none_or(|max| self.attack <= max)
+            && filter.min_defence.is_none_or(|min| self.defence >= min)
+            && filter.max_defence.is_none_or(|max| self.defence <= max)
+    }
+
+    /// Check if units of this type can be promoted to veterans. Vessels
+    /// can't, since they take their health from the unit they carry, and
+    /// neither can units which can't attack to get kills.
+    pub fn can_promote(&self) -> bool {
+        self.attack > 0.0 && !self.abilities.contains(&String::from("carry"))
+    }
+
+    /// Get the upgrades for this unit type: the unit types it can be
+    /// upgraded into directly, and every unit type it can eventually become.
+    pub fn upgrades(&self, list: &UnitTypeList) -> Value {
+        let mut path: Vec<String> = vec![];
+        let mut next = self.upgrades.clone();
+        while let Option::Some(id) = next.pop() {
+            if path.contains(&id) || id == self.id {
+                continue;
+            }
+            if let Option::Some(unit_type) = list.get_unit_type(&id) {
+                next.extend(unit_type.upgrades.iter().cloned());
+            }
+            path.push(id);
+        }
+        json!({
+            "unit": self.id,
+            "upgrades": self.upgrades,
+            "path": path,
+            "veteran": self.can_promote()
+        })
+    }
+
+    /// Create an instance of a unit with default flags.
+    pub fn create_unit(&self) -> Unit {
+        let can_retaliate = (self.attack != 0.0) && (self.defence != 0.0);
+        let mut unit = Unit {
+            id: self.id.clone(),
+            display_name: self.display_name.clone(),
+            cost: self.cost,
+            max_health: self.health,
+            health: self.health,
+            attack: self.attack,
+            defence: self.defence,
+            attack_aura: 0.0,
+            forced_retaliation: Option::None,
+            can_retaliate,
+            can_convert: false,
+            can_freeze: false,
+            freeze_area: false,
+            ranged: self.range > 1,
+            splash: false,
+            persist: false,
+            follow_up: vec![],
+            target: 0,
+            position: 0,
+            can_heal: false,
+            can_poison: false,
+            can_explode: false,
+            exploding: false,
+            can_boost: false,
+            boost_target: Option::None,
+            tentacles: false,
+            drain: false,
+            can_fortify: false,
+            can_carry: false,
+            ignores_walls: false,
+            city: City::Outside,
+            adjacent: vec![],
+            heal_target: Option::None,
+            surprise: false,
+            stiff: false,
+            veteran: false,
+            kills: 0,
+            boosted: false,
+            poisoned: false,
+            bonus: false,
+            walled: false,
+            frozen: false,
+            converted: false,
+            damage_taken: 0,
+            drained: 0,
+            damage_dealt: 0,
+            retaliation_taken: 0
+        };
+        abilities::apply_abilities(&mut unit, &self.abilities);
+        unit
+    }
+}
+
+
+/// An actual unit, an instance of one of the `UnitType`s.
+/// Includes additional flags to indicate the current state of the unit.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Unit {
+    // The ID of the unit's type.
+    pub id: String,
+    pub display_name: String,
+    pub cost: u8,
+    // Health is in whole HP, as in the game.
+    pub max_health: i32,
+    pub health: i32,
+    pub attack: f32,
+    pub defence: f32,
+    // Multiplier bonus to the attack of units attacking after this one.
+    pub attack_aura: f32,
+    // For an attacker: will it recieve retaliation.
+    // For a defender: will it retaliate.
+    pub forced_retaliation: Option<bool>,
+    // Whether the unit freezes the units it attacks.
+    pub can_freeze: bool,
+    // Whether the unit freezes the units next to its target, as well as the
+    // target itself.
+    pub freeze_area: bool,
+    pub can_convert: bool,
+    pub can_retaliate: bool,
+    pub ranged: bool,
+    // Whether attacks also damage units next to the defender.
+    pub splash: bool,
+    // Whether the unit can keep attacking after killing its target.
+    pub persist: bool,
+    // For a persisting attacker: the units it moves on to after each kill.
+    pub follow_up: Vec<Unit>,
+    // For an attacker: the index of the defender it attacks.
+    pub target: usize,
+    // For an attacker: its position in the list of attackers requested.
+    pub position: usize,
+    pub can_heal: bool,
+    // Whether the unit poisons the units it attacks.
+    pub can_poison: bool,
+    pub can_explode: bool,
+    // For an attacker with explode: whether it explodes instead of attacking.
+    pub exploding: bool,
+    pub can_boost: bool,
+    // For an attacker with boost: the position of the attacker it boosts.
+    pub boost_target: Option<usize>,
+    // Whether the unit hits melee attackers before they attack it.
+    pub tentacles: bool,
+    // Whether the unit heals by the damage it deals.
+    pub drain: bool,
+    // Whether the unit gets a defence bonus in a city.
+    pub can_fortify: bool,
+    // Whether the unit is a vessel, which carries a land unit.
+    pub can_carry: bool,
+    // Whether the unit's attacks ignore city walls.
+    pub ignores_walls: bool,
+    pub city: City,
+    // For a defender: the indices of the adjacent units next to it.
+    pub adjacent: Vec<usize>,
+    // For an attacker with heal: the position of the attacker it heals.
+    pub heal_target: Option<usize>,
+    // Whether the unit's attacks are never retaliated against.
+    pub surprise: bool,
+    // Whether the unit never retaliates, even if forced to.
+    pub stiff: bool,
+    pub veteran: bool,
+    // How many units this unit has killed, towards promotion.
+    pub kills: u8,
+    pub boosted: bool,
+    pub poisoned: bool,
+    // Whether the unit has a defence bonus from its terrain, and whether it
+    // is behind walls, without counting the city it is in.
+    pub bonus: bool,
+    pub walled: bool,
+    pub frozen: bool,
+    pub converted: bool,
+    // The total damage done to the unit during the battle.
+    pub damage_taken: i32,
+    // The total health the unit has drained during the battle.
+    pub drained: i32,
+    // The total damage the unit has dealt during the battle.
+    pub damage_dealt: i32,
+    // How much of the damage the unit took was retaliation.
+    pub retaliation_taken: i32
+}
+
+impl Unit {
+    /// Read and apply bit flags from a byte.
+    pub fn apply_bit_flags(&mut self, flags: u8) {
+        self.apply_flags(&UnitFlags::from_bits(flags));
+    }
+
+    /// Apply a set of named flags.
+    pub fn apply_flags(&mut self, flags: &UnitFlags) {
+        if flags.poisoned {
+            self.apply_poison();
+        }
+        if flags.bonus {
+            self.apply_bonus();
+        }
+        if flags.walled {
+            self.apply_wall();
+        }
+        if flags.boosted {
+            self.apply_boost();
+        }
+        if flags.veteran {
+            self.apply_veteran();
+        }
+        if flags.forced_retaliation {
+            self.forced_retaliation = Option::Some(true);
+        } else if flags.no_retaliation {
+            self.forced_retaliation = Option::Some(false);
+        }
+        if flags.frozen {
+            self.apply_freeze();
+        }
+    }
+
+    /// Poison the unit, reducing its defence.
+    pub fn apply_poison(&mut self) {
+        self.poisoned = true;
+    }
+
+    /// Apply the defence bonus for the terrain the unit is on, given the
+    /// techs its tribe knows. A unit in a city is treated as being in an
+    /// unwalled one unless its city has already been set.
+    pub fn apply_terrain(&mut self, terrain: Terrain, techs: &[String]) {
+        if terrain == Terrain::City {
+            if self.city == City::Outside {
+                self.city = City::Unwalled;
+            }
+        } else if let Option::Some(tech) = terrain.defence_tech() {
+            if techs.iter().any(|known| known == tech) {
+                self.apply_bonus();
+            }
+        }
+    }
+
+    pub fn apply_bonus(&mut self) {
+        self.bonus = true;
+    }
+
+    pub fn apply_wall(&mut self) {
+        self.walled = true;
+    }
+
+    /// The unit's defence after poison and its defence bonus, not counting
+    /// the city it is in.
+    pub fn defence_with_bonus(&self, rules: &Ruleset) -> f32 {
+        let mut defence = self.defence;
+        if self.poisoned {
+            defence *= 0.8;
+        }
+        if self.bonus {
+            defence *= rules.defence_bonus;
+        }
+        if self.walled {
+            defence *= rules.wall_bonus;
+        }
+        defence
+    }
+
+    /// Boost the unit, increasing its attack. This has no effect if it is
+    /// already boosted.
+    pub fn apply_boost(&mut self) {
+        if !self.boosted {
+            self.boosted = true;
+            self.attack += 0.5;
+        }
+    }
+
+    pub fn apply_veteran(&mut self) {
+        self.veteran = true;
+        self.max_health += 5;
+    }
+
+    /// Load a land unit onto this vessel. The vessel keeps its own attack
+    /// and defence, but takes the health of the unit it carries, and losing
+    /// it loses both.
+    pub fn carry(&mut self, carried: &Unit) {
+        self.max_health = carried.max_health;
+        self.health = carried.health;
+        self.cost += carried.cost;
+    }
+
+    /// Check if the unit has enough kills to be promoted to a veteran.
+    pub fn can_promote(&self) -> bool {
+        !self.veteran && self.kills >= VETERAN_KILLS
+    }
+
+    /// Promote the unit to a veteran, which also fully heals it.
+    pub fn promote(&mut self) {
+        self.apply_veteran();
+        self.health = self.max_health;
+    }
+
+    pub fn apply_freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Restore some health, up to the unit's maximum.
+    pub fn heal(&mut self, amount: i32) {
+        self.health = (self.health + amount).min(self.max_health);
+    }
+}
+
+
+/// Flags to apply to a unit, by name rather than as a bit field.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnitFlags {
+    pub poisoned: bool,
+    pub bonus: bool,
+    pub walled: bool,
+    pub boosted: bool,
+    pub veteran: bool,
+    pub forced_retaliation: bool,
+    pub no_retaliation: bool,
+    pub frozen: bool
+}
+
+impl UnitFlags {
+    /// Read the named flags from a bit field.
+    pub fn from_bits(flags: u8) -> UnitFlags {
+        UnitFlags {
+            poisoned: read_flag(flags, 0),
+            bonus: read_flag(flags, 1),
+            walled: read_flag(flags, 2),
+            boosted: read_flag(flags, 3),
+            veteran: read_flag(flags, 4),
+            forced_retaliation: read_flag(flags, 5),
+            no_retaliation: read_flag(flags, 6),
+            frozen: read_flag(flags, 7)
+        }
+    }
+
+    /// Set a flag by its name. Returns `false` if there is no such flag.
+    pub fn set(&mut self, name: &str) -> bool {
+        let flag = match name {
+            "poisoned" => &mut self.poisoned,
+            "bonus" => &mut self.bonus,
+            "walled" => &mut self.walled,
+            "boosted" => &mut self.boosted,
+            "veteran" => &mut self.veteran,
+            "forced_retaliation" => &mut self.forced_retaliation,
+            "no_retaliation" => &mut self.no_retaliation,
+            "frozen" => &mut self.frozen,
+            _ => return false
+        };
+        *flag = true;
+        true
+    }
+}
+
+
+/// How closely a unit type's name matched a search, best first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    Exact,
+    Prefix,
+    Contains,
+    // Within a few typos of the name, or of its start.
+    Fuzzy
+}
+
+
+/// A unit type found by a search, and how well it matched.
+#[derive(Serialize)]
+pub struct SearchMatch<'a> {
+    pub unit: &'a UnitType,
+    // The ID, display name or alias which matched best.
+    pub matched: &'a str,
+    #[serde(rename = "match")]
+    pub kind: MatchKind,
+    // The number of typos, for a fuzzy match.
+    pub distance: usize
+}
+
+
+/// The number of single character insertions, deletions and substitutions
+/// needed to turn one string into another.
+fn edit_distance(first: &str, second: &str) -> usize {
+    let second: Vec<char> = second.chars().collect();
+    let mut row: Vec<usize> = (0..=second.len()).collect();
+    for (idx, first_char) in first.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = idx + 1;
+        for (other_idx, second_char) in second.iter().enumerate() {
+            let changed = usize::from(first_char != *second_char);
+            let substitution = diagonal + changed;
+            diagonal = row[other_idx + 1];
+            row[other_idx + 1] = substitution
+                .min(row[other_idx] + 1)
+                .min(row[other_idx + 1] + 1);
+        }
+    }
+    row[second.len()]
+}
+
+
+/// Compare a search to a name, both in lower case.
+fn match_name(query: &str, name: &str) -> Option<(MatchKind, usize)> {
+    if name == query {
+        return Option::Some((MatchKind::Exact, 0));
+    }
+    if name.starts_with(query) {
+        return Option::Some((MatchKind::Prefix, 0));
+    }
+    if name.contains(query) {
+        return Option::Some((MatchKind::Contains, 0));
+    }
+    let start: String = name.chars().take(query.chars().count()).collect();
+    let distance = edit_distance(query, name).min(
+        edit_distance(query, &start)
+    );
+    let allowed = (query.chars().count() / 3).max(1);
+    if distance <= allowed {
+        Option::Some((MatchKind::Fuzzy, distance))
+    } else {
+        Option::None
+    }
+}
+
+
+/// A list of all the possible unit types.
+/// Only one of these should ever need to be initialised.
+#[derive(Debug)]
+pub struct UnitTypeList {
+    pub units: Vec<UnitType>,
+    // Where the unit data was loaded from: a URL, a file path,
+    // `provided` or `embedded`.
+    pub source: String,
+    // A hash of the unit data, to tell which version of it is in use.
+    pub hash: String
+}
+
+impl UnitTypeList {
+    /// Read all the units from the data given by `provide_units`, if any,
+    /// or else as set up by `load_units`.
+    /// Panics if the data is missing, badly formatted or invalid.
+    pub fn read_units(&mut self) {
+        let (units, raw, source) = match PROVIDED_UNITS.get() {
+            Option::Some(raw) => {
+                let units = parse_units(raw).unwrap_or_else(|error| {
+                    panic!("{}", error)
+                });
+                (units, raw.clone(), String::from("provided"))
+            },
+            Option::None => load_units()
+        };
+        self.units = units;
+        self.source = source;
+        self.hash = hash_data(&raw);
+    }
+
+    /// Look up a unit type by ID.
+    pub fn get_unit_type(&self, unit_id: &str) -> Option<&UnitType> {
+        self.units.iter().find(|unit_type| unit_type.id == unit_id)
+    }
+
+    /// Look up a unit type by ID, or failing that, by one of its aliases.
+    /// Where unit types share an alias, the first is used.
+    pub fn find_unit_type(&self, name: &str) -> Option<&UnitType> {
+        let name = name.to_lowercase();
+        self.get_unit_type(&name).or_else(|| self.units.iter().find(
+            |unit_type| unit_type.aliases.contains(&name)
+        ))
+    }
+
+    /// Find the unit types whose ID, display name or an alias matches a
+    /// search, best match first. Exact matches come first, then names which
+    /// start with the search, then names which contain it, then names with a
+    /// few typos.
+    pub fn search(&self, query: &str) -> Vec<SearchMatch<'_>> {
+        let query = query.to_lowercase();
+        let mut matches = vec![];
+        for unit in self.units.iter() {
+            let names = std::iter::once(&unit.id)
+                .chain(std::iter::once(&unit.display_name))
+                .chain(unit.aliases.iter());
+            let mut best: Option<SearchMatch> = Option::None;
+            for name in names {
+                let found = match_name(&query, &name.to_lowercase());
+                if let Option::Some((kind, distance)) = found {
+                    let is_better = match &best {
+                        Option::Some(best) => {
+                            (kind, distance) < (best.kind, best.distance)
+                        },
+                        Option::None => true
+                    };
+                    if is_better {
+                        best = Option::Some(SearchMatch {
+                            unit, matched: name, kind, distance
+                        });
+                    }
+                }
+            }
+            matches.extend(best);
+        }
+        matches.sort_by_key(|found| (found.kind, found.distance));
+        matches
+    }
+
+    /// Look up a unit by ID.
+    pub fn get_unit_by_id(&self, unit_id: &String) -> Option<Unit> {
+        for elem in self.units.iter() {
+            if &elem.id == unit_id {
+                return Option::Some(elem.create_unit());
+            }
+        }
+        Option::None
+    }
+}
+
+
+/// Parse a list of unit types from JSON, and check that it is valid.
+/// Errors say where the data is wrong, and which unit type is affected.
+pub fn parse_units(raw: &str) -> Result<Vec<UnitType>, String> {
+    let mut units: Vec<UnitType> = serde_json::from_str(raw).map_err(
+        |error| match invalid_unit(raw) {
+            Option::Some(unit) => format!(
+                "Unit data is invalid: {}, in {}.", error, unit
+            ),
+            Option::None => format!("Unit data is invalid: {}.", error)
+        }
+    )?;
+    for unit in units.iter_mut() {
+        unit.stiff = unit.abilities.contains(&String::from("stiff"));
+    }
+    let mut duplicates: Vec<String> = vec![];
+    for (idx, unit) in units.iter().enumerate() {
+        let seen = units[..idx].iter().any(|other| other.id == unit.id);
+        if seen && !duplicates.contains(&unit.id) {
+            duplicates.push(unit.id.clone());
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(format!(
+            "Unit data has duplicate IDs: {}.", duplicates.join(", ")
+        ));
+    }
+    for unit in units.iter() {
+        let unknown = unit.upgrades.iter().find(
+            |upgrade| !units.iter().any(|other| &&other.id == upgrade)
+        );
+        if let Option::Some(upgrade) = unknown {
+            return Err(format!(
+                "Unit data is invalid: unit type '{}' upgrades into '{}', \
+                which isn't a unit type.",
+                unit.id, upgrade
+            ));
+        }
+    }
+    Ok(units)
+}
+
+
+/// Describe the first unit type in some unit data which can't be read,
+/// such as `unit type 4 ('archer')`, if the data is a list at all.
+fn invalid_unit(raw: &str) -> Option<String> {
+    let values: Vec<Value> = serde_json::from_str(raw).ok()?;
+    let idx = values.iter().position(
+        |value| UnitType::deserialize(value).is_err()
+    )?;
+    Option::Some(match values[idx]["id"].as_str() {
+        Option::Some(id) => format!("unit type {} ('{}')", idx + 1, id),
+        Option::None => format!("unit type {}", idx + 1)
+    })
+}
+
+
+/// A hash of some data which stays the same between builds, unlike the
+/// standard library's hasher. This is 64 bit FNV-1a, as hex.
+fn hash_data(data: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+
+/// Give the unit data to use, as JSON, rather than loading it from a file
+/// or URL. This must be done before any unit is looked up, and only once.
+pub fn provide_units(raw: &str) -> Result<(), String> {
+    if UNITS_LOADED.load(Ordering::SeqCst) {
+        return Err(String::from("The unit data has already been loaded."));
+    }
+    parse_units(raw)?;
+    PROVIDED_UNITS.set(String::from(raw)).map_err(
+        |_| String::from("The unit data has already been provided.")
+    )
+}
+
+
+/// Load the unit data from this file, rather than the one in `UNITS_FILE`
+/// or `units.json`. This must be done before any unit is looked up, and
+/// only once.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_units_file(path: impl Into<PathBuf>) -> Result<(), String> {
+    if UNITS_LOADED.load(Ordering::SeqCst) {
+        return Err(String::from("The unit data has already been loaded."));
+    }
+    UNITS_FILE.set(path.into()).map_err(
+        |_| String::from("The unit data file has already been set.")
+    )
+}
+
+
+/// Whether a file to load the unit data from has been given, either to
+/// `set_units_file` or in the `UNITS_FILE` environment variable.
+#[cfg(not(target_arch = "wasm32"))]
+fn units_file_given() -> bool {
+    UNITS_FILE.get().is_some() || env::var_os("UNITS_FILE").is_some()
+}
+
+
+/// The file the unit data is loaded from: as given to `set_units_file`, or
+/// else in the `UNITS_FILE` environment variable, or else `units.json` in
+/// the working directory.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn units_file() -> PathBuf {
+    match UNITS_FILE.get() {
+        Option::Some(path) => path.clone(),
+        Option::None => env::var_os("UNITS_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("units.json"))
+    }
+}
+
+
+/// Load the unit data from the file given by `units_file`, or from the URL
+/// in `UNITS_URL` if it is set, returning the unit types with the data they
+/// were parsed from and where it came from. If the URL can't be loaded, or
+/// no file was given and there is no `units.json`, the copy of the file
+/// built into the binary is used instead. Panics if a file can't be read or
+/// is invalid, saying why.
+#[cfg(not(target_arch = "wasm32"))]
+fn load_units() -> (Vec<UnitType>, String, String) {
+    match env::var("UNITS_URL") {
+        Ok(url) => match fetch_units(&url) {
+            Ok((units, raw)) => (units, raw, url),
+            Err(error) => {
+                tracing::warn!(
+                    %url, %error,
+                    "Could not load units, so using the embedded unit data."
+                );
+                embedded_units()
+            }
+        },
+        Err(_) if !units_file_given() && !units_file().exists() => {
+            tracing::info!("No unit data file, so using the embedded data.");
+            embedded_units()
+        },
+        Err(_) => {
+            let path = units_file();
+            let source = path.display().to_string();
+            let raw = fs::read_to_string(&path).unwrap_or_else(|error| {
+                panic!(
+                    "Could not read the unit data from {}: {}. Set \
+                    UNITS_FILE to the path of the file.",
+                    source, error
+                )
+            });
+            let units = parse_units(&raw).unwrap_or_else(|error| {
+                panic!("Could not load units from {}: {}", source, error)
+            });
+            tracing::info!(%source, "Loaded units.");
+            (units, raw, source)
+        }
+    }
+}
+
+
+/// WebAssembly has no files, so the copy of the unit data built into the
+/// binary is used.
+#[cfg(target_arch = "wasm32")]
+fn load_units() -> (Vec<UnitType>, String, String) {
+    embedded_units()
+}
+
+
+/// The copy of the unit data built into the binary.
+fn embedded_units() -> (Vec<UnitType>, String, String) {
+    let units = parse_units(EMBEDDED_UNITS).unwrap_or_else(
+        |error| panic!("{}", error)
+    );
+    (units, String::from(EMBEDDED_UNITS), String::from("embedded"))
+}
+
+
+/// Fetch and parse a list of unit types from a URL, returning them with the
+/// data they were parsed from.
+#[cfg(feature = "fetch")]
+fn fetch_units(url: &str) -> Result<(Vec<UnitType>, String), String> {
+    let agent = ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build();
+    let raw = agent.get(url).call()
+        .map_err(|error| error.to_string())?
+        .into_string()
+        .map_err(|error| error.to_string())?;
+    let units = parse_units(&raw)?;
+    tracing::info!(%url, "Loaded units.");
+    Ok((units, raw))
+}
+
+
+/// Without the `fetch` feature, unit data can't be fetched from a URL.
+#[cfg(all(not(feature = "fetch"), not(target_arch = "wasm32")))]
+fn fetch_units(_url: &str) -> Result<(Vec<UnitType>, String), String> {
+    Err(String::from("This build can't fetch unit data."))
+}
+
+
+/// Utility to create and initialise a UnitTypeList.
+/// This should only be called once.
+pub fn init_unit_list() -> UnitTypeList {
+    UNITS_LOADED.store(true, Ordering::SeqCst);
+    let mut units = UnitTypeList {
+        units: vec![],
+        source: String::new(),
+        hash: String::new()
+    };
+    units.read_units();
+    units
+}
+
+
+/// Use the unit data built into the binary, for tests, which don't run
+/// where `units.json` is.
+#[cfg(test)]
+pub(crate) fn use_embedded_units() {
+    let _ = provide_units(EMBEDDED_UNITS);
+}
+
+
+/// Look up a unit from the unit data built into the binary, for tests.
+#[cfg(test)]
+pub(crate) fn test_unit(unit_id: &str) -> Unit {
+    use_embedded_units();
+    UNIT_LIST.get_unit_by_id(&String::from(unit_id)).unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_ids_are_named() {
+        let mut raw: Vec<serde_json::Value> = serde_json::from_str(
+            EMBEDDED_UNITS
+        ).unwrap();
+        let rider = raw.iter().find(|unit| unit["id"] == "rider").cloned();
+        raw.push(raw[0].clone());
+        raw.push(rider.unwrap());
+        raw.push(raw[0].clone());
+        let error = parse_units(&serde_json::to_string(&raw).unwrap())
+            .err().unwrap();
+        assert_eq!(error, "Unit data has duplicate IDs: warrior, rider.");
+    }
+
+    fn flagged(flags: &str) -> Unit {
+        let flags: UnitFlags = serde_json::from_str(flags).unwrap();
+        let mut unit = test_unit("warrior");
+        unit.apply_flags(&flags);
+        unit
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let flags = serde_json::from_str::<UnitFlags>(
+            r#"{"poisoned": true, "veteren": true}"#
+        );
+        assert!(flags.is_err());
+    }
+
+    #[test]
+    fn poison_lowers_defence() {
+        let rules = Ruleset::default();
+        let plain = test_unit("warrior").defence_with_bonus(&rules);
+        let unit = flagged(r#"{"poisoned": true}"#);
+        assert!(unit.poisoned);
+        assert!(unit.defence_with_bonus(&rules) < plain);
+    }
+
+    #[test]
+    fn bonus_raises_defence() {
+        let rules = Ruleset::default();
+        let plain = test_unit("warrior").defence_with_bonus(&rules);
+        let unit = flagged(r#"{"bonus": true}"#);
+        assert!(unit.bonus);
+        assert!(unit.defence_with_bonus(&rules) > plain);
+    }
+
+    #[test]
+    fn wall_raises_defence() {
+        let rules = Ruleset::default();
+        let plain = test_unit("warrior").defence_with_bonus(&rules);
+        let unit = flagged(r#"{"walled": true}"#);
+        assert!(unit.walled);
+        assert!(unit.defence_with_bonus(&rules) > plain);
+    }
+
+    #[test]
+    fn boost_raises_attack_once() {
+        let plain = test_unit("warrior").attack;
+        let mut unit = flagged(r#"{"boosted": true}"#);
+        assert_eq!(unit.attack, plain + 0.5);
+        unit.apply_boost();
+        assert_eq!(unit.attack, plain + 0.5);
+    }
+
+    #[test]
+    fn veteran_raises_max_health() {
+        let plain = test_unit("warrior").max_health;
+        let unit = flagged(r#"{"veteran": true}"#);
+        assert!(unit.veteran);
+        assert_eq!(unit.max_health, plain + 5);
+    }
+
+    #[test]
+    fn retaliation_can_be_forced_or_prevented() {
+        let forced = flagged(r#"{"forced_retaliation": true}"#);
+        assert_eq!(forced.forced_retaliation, Option::Some(true));
+        let prevented = flagged(r#"{"no_retaliation": true}"#);
+        assert_eq!(prevented.forced_retaliation, Option::Some(false));
+    }
+
+    #[test]
+    fn freeze_freezes() {
+        let unit = flagged(r#"{"frozen": true}"#);
+        assert!(unit.frozen);
+    }
+}
@@ -0,0 +1,73 @@
+//! Node.js bindings for the calculator, so that bots written in JavaScript
+//! can use it in the same process rather than over HTTP. Build them with
+//! `npm run build`.
+//!
+//! Inputs are objects as in the API's request bodies, and results are
+//! objects as in its responses. Errors are thrown with the error as the API
+//! gives it, as JSON, in their message, such as
+//! `{"error":"Unknown unit ID 'foo'."}`.
+use std::env;
+
+use napi::bindgen_prelude::Buffer;
+use napi::{Error, Result, Status};
+use napi_derive::napi;
+use polycalc_core::{bindings, units};
+use serde_json::Value;
+
+
+/// Use the copy of the unit data built in, unless `loadUnits` has been
+/// called or there is other data to load, since an addon may be loaded
+/// from anywhere.
+fn default_units() {
+    if env::var("UNITS_URL").is_err() && !units::units_file().exists() {
+        // This fails if the data has been given or loaded already, which
+        // is fine.
+        let _ = units::provide_units(units::EMBEDDED_UNITS);
+    }
+}
+
+
+/// Turn a result from the bindings into a JavaScript value, throwing
+/// errors.
+fn output(result: std::result::Result<String, String>) -> Result<Value> {
+    let raw = result.map_err(|error| Error::new(Status::InvalidArg, error))?;
+    serde_json::from_str(&raw)
+        .map_err(|error| Error::from_reason(error.to_string()))
+}
+
+
+/// Calculate a battle, as `POST /battle`.
+#[napi]
+pub fn battle(input: Value) -> Result<Value> {
+    default_units();
+    output(bindings::battle(&input.to_string()))
+}
+
+
+/// Find the best order of attack, or the fewest attackers, as
+/// `POST /optim`.
+#[napi]
+pub fn optimise(input: Value) -> Result<Value> {
+    default_units();
+    output(bindings::optimise(&input.to_string()))
+}
+
+
+/// Look up a unit type by ID or alias, as `GET /units/<id>`.
+#[napi]
+pub fn unit(id: String) -> Result<Value> {
+    default_units();
+    output(bindings::unit(&id))
+}
+
+
+/// Use this unit data, as JSON, rather than the file in `UNITS_FILE` or
+/// `units.json`, or the data in `UNITS_URL`. This must be called before
+/// anything else, and only once.
+#[napi]
+pub fn load_units(data: Buffer) -> Result<()> {
+    let raw = std::str::from_utf8(&data)
+        .map_err(|error| Error::new(Status::InvalidArg, error.to_string()))?;
+    units::provide_units(raw)
+        .map_err(|error| Error::new(Status::GenericFailure, error))
+}
@@ -0,0 +1,104 @@
+//! Python bindings for the calculator, for working through many battles
+//! from a script or notebook without the overhead of HTTP. Build and
+//! install them with `maturin develop` or `pip install ./python`.
+//!
+//! Inputs may be dicts, or JSON strings, as in the API's request bodies,
+//! and results are returned as dicts, as in its responses. Errors are
+//! raised as `polycalc.CalcError`, whose argument is the error as the API
+//! gives it, such as `{"error": "Unknown unit ID 'foo'."}`.
+use std::env;
+
+use polycalc_core::{bindings, units};
+use pyo3::create_exception;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+
+create_exception!(polycalc, CalcError, PyValueError);
+
+
+/// Use the copy of the unit data built in, unless `load_units` has been
+/// called or there is other data to load, since a module may be imported
+/// from anywhere.
+fn default_units() {
+    if env::var("UNITS_URL").is_err() && !units::units_file().exists() {
+        // This fails if the data has been given or loaded already, which
+        // is fine.
+        let _ = units::provide_units(units::EMBEDDED_UNITS);
+    }
+}
+
+
+/// Read an input as JSON, whether it is a string already or something
+/// which `json.dumps` can write.
+fn input_json(input: &Bound<'_, PyAny>) -> PyResult<String> {
+    if let Ok(raw) = input.extract::<String>() {
+        return Ok(raw);
+    }
+    input.py().import("json")?.call_method1("dumps", (input,))?.extract()
+}
+
+
+/// Turn a result from the bindings into Python objects, raising errors.
+fn output(
+    py: Python<'_>, result: Result<String, String>
+) -> PyResult<Py<PyAny>> {
+    let json = py.import("json")?;
+    match result {
+        Ok(raw) => Ok(json.call_method1("loads", (raw,))?.unbind()),
+        Err(raw) => Err(CalcError::new_err(
+            json.call_method1("loads", (raw,))?.unbind()
+        ))
+    }
+}
+
+
+/// Calculate a battle, as `POST /battle`.
+#[pyfunction]
+fn battle(py: Python<'_>, input: &Bound<'_, PyAny>) -> PyResult<Py<PyAny>> {
+    let input = input_json(input)?;
+    default_units();
+    let result = py.detach(|| bindings::battle(&input));
+    output(py, result)
+}
+
+
+/// Find the best order of attack, or the fewest attackers, as
+/// `POST /optim`. Other Python threads may run while it searches.
+#[pyfunction]
+fn optimise_battle(
+    py: Python<'_>, input: &Bound<'_, PyAny>
+) -> PyResult<Py<PyAny>> {
+    let input = input_json(input)?;
+    default_units();
+    let result = py.detach(|| bindings::optimise(&input));
+    output(py, result)
+}
+
+
+/// Look up a unit type by ID or alias, as `GET /units/<id>`.
+#[pyfunction]
+fn unit(py: Python<'_>, id: &str) -> PyResult<Py<PyAny>> {
+    default_units();
+    output(py, bindings::unit(id))
+}
+
+
+/// Use this unit data, as JSON, rather than the file in `UNITS_FILE` or
+/// `units.json`, or the data in `UNITS_URL`. This must be called before
+/// anything else, and only once.
+#[pyfunction]
+fn load_units(data: &str) -> PyResult<()> {
+    units::provide_units(data).map_err(CalcError::new_err)
+}
+
+
+#[pymodule]
+fn polycalc(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("CalcError", module.py().get_type::<CalcError>())?;
+    module.add_function(wrap_pyfunction!(battle, module)?)?;
+    module.add_function(wrap_pyfunction!(optimise_battle, module)?)?;
+    module.add_function(wrap_pyfunction!(unit, module)?)?;
+    module.add_function(wrap_pyfunction!(load_units, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,671 @@
+//! What each route does, apart from how it is served: every route is a
+//! function from its parsed inputs to its response or error, so that Rocket
+//! and, with the `axum` feature, axum serve the same API.
+use std::panic;
+use std::time::Instant;
+
+use rocket::http::Status;
+use rocket::tokio::task;
+use serde_json::Value;
+use tracing::Span;
+
+use crate::error::ApiError;
+use crate::{
+    abilities, cache, calc, engagement, jobs, limits, metrics, rules,
+    simulate, stats, units, versions
+};
+
+
+lazy_static! {
+    /// When the server started, to report its uptime.
+    pub static ref STARTED: Instant = Instant::now();
+}
+
+
+/// Run work which may take a while on a thread where blocking is allowed,
+/// so that other requests aren't held up. The work is done in the span of
+/// the request, and with its hub if errors are reported, and a panic in it
+/// is resumed here.
+pub async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> T + Send + 'static
+) -> T {
+    let span = Span::current();
+    let work = move || span.in_scope(work);
+    #[cfg(feature = "sentry")]
+    let work = {
+        let hub = sentry::Hub::current();
+        move || sentry::Hub::run(hub, work)
+    };
+    match task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(error) => panic::resume_unwind(error.into_panic())
+    }
+}
+
+
+/// Reject a battle where an attacker targets a defender that doesn't exist,
+/// or where a unit has a flag that doesn't apply to its side.
+pub fn check_battle(battle: &calc::BattleInput) -> Result<(), ApiError> {
+    battle.check_flags()?;
+    if let Option::Some(idx) = battle.invalid_adjacent() {
+        return Err(ApiError::new(
+            Status::BadRequest, "invalid_adjacent",
+            format!("No adjacent unit with index {}.", idx)
+        ).with("adjacent", json!(idx)));
+    }
+    match battle.invalid_target() {
+        Option::Some(target) => Err(ApiError::new(
+            Status::BadRequest, "invalid_target",
+            format!("No defender with index {}.", target)
+        ).with("target", json!(target))),
+        Option::None => Ok(())
+    }
+}
+
+
+/// The error for a search which found no order of attack, though it should
+/// always find one.
+fn no_order() -> ApiError {
+    ApiError::new(
+        Status::InternalServerError, "no_order",
+        "No order of attack could be found."
+    )
+}
+
+
+/// The unit types which meet a filter.
+pub fn units(filter: &units::UnitFilter) -> Value {
+    let units: Vec<&units::UnitType> = units::UNIT_LIST.units.iter()
+        .filter(|unit_type| unit_type.matches(filter))
+        .collect();
+    json!(units)
+}
+
+
+/// The unit types best matching a search, best first.
+pub fn search_units(q: &str, limit: Option<usize>) -> Value {
+    let mut matches = units::UNIT_LIST.search(q);
+    matches.truncate(limit.unwrap_or(10));
+    json!(matches)
+}
+
+
+/// A unit type, by its ID or an alias.
+pub fn unit(id: &str) -> Result<Value, ApiError> {
+    match units::UNIT_LIST.find_unit_type(id) {
+        Option::Some(unit_type) => Ok(json!(unit_type)),
+        Option::None => Err(
+            ApiError::from(calc::UnknownUnit(String::from(id)))
+                .with_status(Status::NotFound)
+        )
+    }
+}
+
+
+/// The unit types a unit type can become.
+pub fn upgrades(id: &str) -> Result<Value, ApiError> {
+    match units::UNIT_LIST.get_unit_type(id) {
+        Option::Some(unit_type) => Ok(unit_type.upgrades(&units::UNIT_LIST)),
+        Option::None => Err(
+            ApiError::from(calc::UnknownUnit(String::from(id)))
+                .with_status(Status::NotFound)
+        )
+    }
+}
+
+
+pub fn abilities() -> Value {
+    abilities::list_abilities(&units::UNIT_LIST)
+}
+
+
+/// Calculate a battle, with the events in it if asked for.
+pub fn battle(
+    input: &calc::ExplainInput,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: &Option<String>,
+    rules: &rules::Ruleset,
+    stats: &stats::MatchupStats
+) -> Result<Value, ApiError> {
+    limits::check_attackers(
+        input.battle.attackers.len(), *limits::MAX_BATTLE_ATTACKERS
+    )?;
+    check_battle(&input.battle)?;
+    let mut state = input.battle.to_state_with(
+        on_unknown.unwrap_or_default(),
+        default_unit.as_ref().map_or(calc::DEFAULT_UNIT, String::as_str)
+    )?;
+    state.rules = *rules;
+    stats.record(&state);
+    let span = tracing::info_span!(
+        "battle", attackers = state.attackers.len(), explain = input.explain
+    );
+    let events = span.in_scope(|| if input.explain {
+        Option::Some(calc::explain_battle(&mut state))
+    } else {
+        calc::battle_many(&mut state);
+        Option::None
+    });
+    let mut response = state.to_json();
+    if let Option::Some(events) = events {
+        response["events"] = json!(events);
+    }
+    let warnings = input.battle.tribe_warnings();
+    if !warnings.is_empty() {
+        response["warnings"] = json!(warnings);
+    }
+    Ok(response)
+}
+
+
+/// Calculate a battle given as query parameters, so that it can be linked
+/// to. Each attacker is given as an `a` parameter and the defender as `d`,
+/// in the form read by `UnitInput::from_spec`; other parameters are
+/// ignored.
+pub fn link_battle<'a>(
+    params: impl Iterator<Item = (&'a str, &'a str)>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: &Option<String>,
+    rules: &rules::Ruleset,
+    stats: &stats::MatchupStats
+) -> Result<Value, ApiError> {
+    let mut attackers = vec![];
+    let mut defender = Option::None;
+    for (key, spec) in params {
+        match key {
+            "a" => attackers.push(
+                calc::UnitInput::from_spec(spec)?
+            ),
+            "d" => defender = Option::Some(
+                calc::UnitInput::from_spec(spec)?
+            ),
+            _ => ()
+        }
+    }
+    let defender = defender.ok_or_else(|| ApiError::new(
+        Status::BadRequest, "missing_defender", "No defender was given."
+    ))?;
+    let input = calc::ExplainInput {
+        battle: calc::BattleInput {
+            attackers,
+            defender,
+            defenders: vec![],
+            adjacent: vec![]
+        },
+        explain: false
+    };
+    battle(&input, on_unknown, default_unit, rules, stats)
+}
+
+
+/// Calculate several unrelated battles at once. Each result is either the
+/// battle or the error it was rejected with, in the order the battles were
+/// given.
+pub async fn batch(
+    inputs: Vec<calc::ExplainInput>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: &rules::RulesQuery,
+    stats: &stats::MatchupStats
+) -> Result<Value, ApiError> {
+    limits::check_batch(inputs.len())?;
+    let rules = rules.to_ruleset()?;
+    let stats = stats.clone();
+    let results: Vec<Value> = blocking(move || inputs.iter().map(|input| {
+        match battle(input, on_unknown, &default_unit, &rules, &stats) {
+            Ok(result) => result,
+            Err(error) => error.to_json()
+        }
+    }).collect()).await;
+    Ok(json!(results))
+}
+
+
+/// Check a battle to optimise, and build its initial state. The attackers
+/// are put in a standard order first, so the same battle with them listed
+/// differently gives the same result, unless unknown attackers are skipped,
+/// which would leave the indices in the result out of line with the
+/// request. Returns the input used, its state, and the index in the request
+/// of each of its attackers. Searches for the best order of every attacker
+/// which are heuristic or have a time budget, of at most `MAX_OPTIM_MS`,
+/// may have as many attackers as a battle, but others grow too quickly to
+/// allow that.
+pub fn optim_state(
+    input: &calc::OptimInput,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: &Option<String>,
+    rules: &rules::RulesQuery
+) -> Result<
+    (calc::OptimInput, calc::BattleState, Vec<usize>), ApiError
+> {
+    // The time budget is checked first, since it lets the search have more
+    // attackers.
+    if let Option::Some(ms) = input.max_ms {
+        let limit = *limits::MAX_OPTIM_MS;
+        if ms > limit as u64 {
+            return Err(ApiError::new(
+                Status::UnprocessableEntity, "invalid_max_ms",
+                format!(
+                    "The time budget can be at most {} ms, not {} ms.",
+                    limit, ms
+                )
+            ).with("limit", json!(limit)));
+        }
+    }
+    let heuristic = match input.mode {
+        calc::SearchMode::Heuristic => true,
+        calc::SearchMode::Exhaustive => false
+    };
+    let bounded = heuristic || input.max_ms.is_some();
+    let limit = if bounded && !input.minimise {
+        *limits::MAX_BATTLE_ATTACKERS
+    } else {
+        *limits::MAX_OPTIM_ATTACKERS
+    };
+    limits::check_attackers(input.battle.attackers.len(), limit)?;
+    check_battle(&input.battle)?;
+    if let Option::Some(idx) = input.invalid_attacker() {
+        return Err(ApiError::new(
+            Status::BadRequest, "invalid_attacker",
+            format!("No attacker with index {}.", idx)
+        ).with("attacker", json!(idx)));
+    }
+    let rules = rules.to_ruleset()?;
+    let default_unit = default_unit.as_deref().unwrap_or(calc::DEFAULT_UNIT);
+    let mut state = input.battle.to_state_with(
+        on_unknown.unwrap_or_default(), default_unit
+    )?;
+    state.rules = rules;
+    if state.attackers.len() != input.battle.attackers.len() {
+        let attackers = state.attackers.iter().map(
+            |attacker| attacker.position
+        ).collect();
+        return Ok((input.clone(), state, attackers));
+    }
+    let (canonical, attackers) = input.canonical();
+    let mut state = canonical.battle.to_state_with(
+        on_unknown.unwrap_or_default(), default_unit
+    )?;
+    state.rules = rules;
+    Ok((canonical, state, attackers))
+}
+
+
+/// Find the best order of attack, or the fewest attackers, for a battle,
+/// using the result found before for the same battle if there is one.
+pub fn optimise(
+    input: &calc::OptimInput,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: &Option<String>,
+    rules: &rules::RulesQuery,
+    stats: &stats::MatchupStats,
+    cache: &cache::ResultCache
+) -> Result<Value, ApiError> {
+    let (input, state, attackers) = optim_state(
+        input, on_unknown, default_unit, rules
+    )?;
+    stats.record(&state);
+    let key = json!({
+        "input": input,
+        "rules": state.rules,
+        "on_unknown": format!("{:?}", on_unknown.unwrap_or_default()),
+        "default_unit": default_unit
+    }).to_string();
+    let cached = cache.get(&key);
+    metrics::record_cache_lookup(cached.is_some());
+    let mut result = match cached {
+        Option::Some(mut result) => {
+            result["cached"] = json!(true);
+            result
+        },
+        Option::None => {
+            let started = Instant::now();
+            let mut result = input.optimise(
+                state, Option::None, Option::None
+            );
+            metrics::record_search(&result, started.elapsed());
+            // Results cut short by a time budget might be beaten by another
+            // search, so aren't kept.
+            if result["complete"] == true {
+                cache.insert(key, result.clone());
+            }
+            result["cached"] = json!(false);
+            result
+        }
+    };
+    calc::restore_indices(&mut result, &attackers);
+    Ok(result)
+}
+
+
+/// Queue an optimisation as a job, to be polled for its result.
+pub fn start_job(
+    input: &calc::OptimInput,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: &Option<String>,
+    rules: &rules::RulesQuery,
+    stats: &stats::MatchupStats,
+    jobs: &jobs::Jobs
+) -> Result<Value, ApiError> {
+    let (input, state, attackers) = optim_state(
+        input, on_unknown, default_unit, rules
+    )?;
+    stats.record(&state);
+    let id = jobs.submit(input, state, attackers)?;
+    Ok(json!({"id": id, "status": "queued"}))
+}
+
+
+pub fn job_status(id: u64, jobs: &jobs::Jobs) -> Result<Value, ApiError> {
+    jobs.status(id).ok_or_else(|| ApiError::from(jobs::UnknownJob(id)))
+}
+
+
+pub fn cancel_job(id: u64, jobs: &jobs::Jobs) -> Result<(), ApiError> {
+    if jobs.cancel(id) {
+        Ok(())
+    } else {
+        Err(ApiError::from(jobs::UnknownJob(id)))
+    }
+}
+
+
+/// Choose a target for each attacker in an engagement.
+pub fn assign(
+    units: &calc::BattleInput,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: &Option<String>,
+    rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    limits::check_attackers(
+        units.attackers.len(), *limits::MAX_BATTLE_ATTACKERS
+    )?;
+    check_battle(units)?;
+    let mut state = units.to_state_with(
+        on_unknown.unwrap_or_default(),
+        default_unit.as_deref().unwrap_or(calc::DEFAULT_UNIT)
+    )?;
+    state.rules = rules.to_ruleset()?;
+    match engagement::assign_battle(&state) {
+        Option::Some(assignment) => Ok(assignment.to_json()),
+        Option::None => Err(no_order())
+    }
+}
+
+
+/// Play a battle out over several turns.
+pub fn simulate(
+    input: &simulate::SimulateInput,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: &Option<String>,
+    rules: &rules::RulesQuery,
+    stats: &stats::MatchupStats
+) -> Result<Value, ApiError> {
+    limits::check_attackers(
+        input.battle.attackers.len(), *limits::MAX_BATTLE_ATTACKERS
+    )?;
+    check_battle(&input.battle)?;
+    if let Option::Some(heal) = input.invalid_heal() {
+        return Err(ApiError::new(
+            Status::BadRequest, "invalid_heal",
+            "The amount to heal must be a finite number, at least zero."
+        ).with("heal", json!(heal)));
+    }
+    let mut state = input.battle.to_state_with(
+        on_unknown.unwrap_or_default(),
+        default_unit.as_deref().unwrap_or(calc::DEFAULT_UNIT)
+    )?;
+    state.rules = rules.to_ruleset()?;
+    stats.record(&state);
+    Ok(simulate::simulate(
+        &mut state, input.turns, input.heal_amount()
+    ).to_json())
+}
+
+
+pub fn damage(
+    units: &calc::DamageInput, rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    check_battle(&units.battle())?;
+    let attacker = units.attacker.to_unit()?;
+    let defender = units.defender.to_unit()?;
+    let rules = rules.to_ruleset()?;
+    Ok(json!(calc::preview_damage(&attacker, &defender, &rules)))
+}
+
+
+/// The fewest attackers of a type which kill the defender.
+pub fn kill_threshold(
+    input: &calc::KillThresholdInput, rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    let max = input.max.unwrap_or(*limits::MAX_BATTLE_ATTACKERS);
+    limits::check_attackers(max, *limits::MAX_BATTLE_ATTACKERS)?;
+    check_battle(&input.battle(1))?;
+    let rules = rules.to_ruleset()?;
+    let found = calc::kill_threshold(input, max, &rules)?;
+    Ok(match found {
+        Option::Some((count, state)) => {
+            let dead = usize::from(state.count_dead());
+            let survivors = state.attackers.len() - dead;
+            json!({
+                "count": count,
+                "survivors": survivors,
+                "state": state.to_json()
+            })
+        },
+        // Even the most attackers allowed can't kill the defender.
+        Option::None => json!({
+            "count": null,
+            "survivors": null,
+            "state": null,
+            "max": max
+        })
+    })
+}
+
+
+pub fn survive(
+    input: &calc::BattleInput, rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    limits::check_attackers(
+        input.attackers.len(), *limits::MAX_BATTLE_ATTACKERS
+    )?;
+    check_battle(input)?;
+    let mut state = input.to_state()?;
+    state.rules = rules.to_ruleset()?;
+    Ok(json!(calc::survival_thresholds(&state)))
+}
+
+
+pub fn initiative(
+    units: &calc::InitiativeInput, rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    for battle in units.battles().iter() {
+        check_battle(battle)?;
+    }
+    let unit = units.unit.to_unit()?;
+    let enemy = units.enemy.to_unit()?;
+    let rules = rules.to_ruleset()?;
+    Ok(json!(calc::initiative(&unit, &enemy, &rules)))
+}
+
+
+pub fn engagement(
+    units: &engagement::EngagementInput, rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    limits::check_attackers(
+        units.attackers.len(), *limits::MAX_BATTLE_ATTACKERS
+    )?;
+    if let Option::Some(battle) = units.battle() {
+        check_battle(&battle)?;
+    }
+    let (attackers, defenders) = units.to_units()?;
+    let rules = rules.to_ruleset()?;
+    match engagement::optimise_engagement(&attackers, &defenders, &rules) {
+        Option::Some(engagement) => Ok(engagement.to_json()),
+        Option::None => Err(no_order())
+    }
+}
+
+
+pub fn formula(
+    attack_force: f32, defence_force: f32, attack: f32, defence: f32,
+    rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    let rules = rules.to_ruleset()?;
+    match calc::damage_formula(
+        attack_force, defence_force, attack, defence, &rules
+    ) {
+        Option::Some(breakdown) => Ok(json!(breakdown)),
+        Option::None => Err(ApiError::new(
+            Status::BadRequest, "invalid_forces",
+            "Every value must be a finite number, and attack and defence \
+            forces must sum to more than zero."
+        ))
+    }
+}
+
+
+/// Get the unit types with the given IDs or aliases, separated by commas,
+/// or every unit type if none are given.
+fn unit_types(
+    ids: Option<&str>
+) -> Result<Vec<&'static units::UnitType>, ApiError> {
+    let ids = match ids {
+        Option::Some(ids) => ids,
+        Option::None => return Ok(units::UNIT_LIST.units.iter().collect())
+    };
+    let mut unit_types = vec![];
+    for id in ids.split(',') {
+        match units::UNIT_LIST.find_unit_type(id.trim()) {
+            Option::Some(unit_type) => unit_types.push(unit_type),
+            Option::None => {
+                return Err(calc::UnknownUnit(id.to_string()).into());
+            }
+        }
+    }
+    Ok(unit_types)
+}
+
+
+/// How much damage each of some unit types does to each of the others.
+pub fn matchup(
+    units: Option<&str>,
+    defence: Option<calc::DefenceBonus>,
+    rules: &rules::RulesQuery
+) -> Result<Value, ApiError> {
+    let unit_types = unit_types(units)?;
+    let defence = defence.unwrap_or_default();
+    let rules = rules.to_ruleset()?;
+    let ids: Vec<&String> = unit_types.iter()
+        .map(|unit_type| unit_type.id())
+        .collect();
+    Ok(json!({
+        "units": ids,
+        "defence": defence,
+        "attacks": calc::matchup_table(&unit_types, defence, &rules)
+    }))
+}
+
+
+pub fn popular(limit: Option<usize>, stats: &stats::MatchupStats) -> Value {
+    json!(stats.top(limit.unwrap_or(10)))
+}
+
+
+/// Whether the server can calculate anything: it can't if no unit types
+/// were loaded, since every calculation needs them.
+pub fn health() -> Result<Value, ApiError> {
+    let unit_count = units::UNIT_LIST.units.len();
+    if unit_count == 0 {
+        return Err(ApiError::new(
+            Status::ServiceUnavailable, "no_units", "No unit types are loaded."
+        ));
+    }
+    Ok(json!({
+        "status": "ok",
+        "units_loaded": true,
+        "unit_count": unit_count,
+        "uptime_secs": STARTED.elapsed().as_secs()
+    }))
+}
+
+
+/// Every metric in the Prometheus text format.
+pub fn metrics(jobs: &jobs::Jobs) -> String {
+    let (queued, running) = jobs.counts();
+    metrics::render(queued, running)
+}
+
+
+/// Which build of the server is running, and with which unit data and
+/// rules.
+pub fn build_info(versions: &[versions::Version]) -> Value {
+    let commit = env!("GIT_COMMIT");
+    let api_versions: Vec<&str> = versions.iter()
+        .map(|version| version.name)
+        .collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": if commit.is_empty() { Option::None } else {
+            Option::Some(commit)
+        },
+        "built_at": env!("BUILD_TIMESTAMP").parse::<u64>().ok(),
+        "api_versions": api_versions,
+        "units": {
+            "source": units::UNIT_LIST.source,
+            "hash": units::UNIT_LIST.hash,
+            "count": units::UNIT_LIST.units.len()
+        },
+        "rules": *rules::LATEST
+    })
+}
+
+
+/// The error for a request which couldn't be read.
+pub fn malformed_request() -> ApiError {
+    ApiError::new(
+        Status::BadRequest, "malformed_request",
+        "The request could not be read. If it has a body, it may not be \
+        valid JSON."
+    )
+}
+
+
+/// The error for a request which doesn't match any route.
+pub fn not_found(method: &str, path: &str) -> ApiError {
+    ApiError::new(Status::NotFound, "not_found", format!(
+        "Nothing matches {} {}. Requests with a body must send it as JSON, \
+        with the header 'Content-Type: application/json'.",
+        method, path
+    ))
+}
+
+
+/// The error for a request body of the wrong shape.
+pub fn invalid_body() -> ApiError {
+    ApiError::new(
+        Status::UnprocessableEntity, "invalid_body",
+        "The request body is JSON, but not of the right shape: a field may \
+        be missing or of the wrong type."
+    )
+}
+
+
+pub fn body_too_large() -> ApiError {
+    ApiError::new(
+        Status::PayloadTooLarge, "body_too_large",
+        format!(
+            "The request body is bigger than the limit of {} bytes.",
+            *limits::MAX_BODY_BYTES
+        )
+    ).with("limit", json!(*limits::MAX_BODY_BYTES))
+}
+
+
+pub fn internal_error() -> ApiError {
+    ApiError::new(
+        Status::InternalServerError, "internal_error",
+        "Something went wrong on the server while handling the request."
+    )
+}
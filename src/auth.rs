@@ -0,0 +1,167 @@
+//! API keys for the expensive routes, such as `/optim`, so that only known
+//! clients can use them. Keys are given by the `API_KEYS` environment
+//! variable, separated by commas, and by the file named by `API_KEYS_FILE`,
+//! one on each line. If no keys are given, every route is public.
+//!
+//! Clients send their key in the `X-API-Key` header, or as
+//! `Authorization: Bearer <key>`.
+use std::collections::HashSet;
+use std::{env, fs};
+
+use rocket::Request;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+
+use crate::error::ApiError;
+use crate::ratelimit::RateLimited;
+
+
+/// The keys which may use the expensive routes.
+#[derive(Clone)]
+pub struct ApiKeys {
+    keys: HashSet<String>
+}
+
+impl ApiKeys {
+    pub fn new(keys: HashSet<String>) -> ApiKeys {
+        ApiKeys { keys }
+    }
+
+    /// Read the keys from the `API_KEYS` and `API_KEYS_FILE` environment
+    /// variables. Panics if the file can't be read, so that the routes
+    /// aren't left public by mistake.
+    pub fn from_env() -> ApiKeys {
+        let mut keys = HashSet::new();
+        if let Ok(listed) = env::var("API_KEYS") {
+            keys.extend(read_keys(&listed.replace(',', "\n")));
+        }
+        if let Ok(path) = env::var("API_KEYS_FILE") {
+            let file = fs::read_to_string(&path).unwrap_or_else(|error| {
+                panic!("Could not read API keys from {}: {}", path, error)
+            });
+            keys.extend(read_keys(&file));
+        }
+        ApiKeys::new(keys)
+    }
+
+    /// Whether any keys are needed.
+    fn required(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Check a key a request was sent with, if any, failing with the status
+    /// it should be refused with.
+    pub fn check(&self, key: Option<&str>) -> Result<(), Status> {
+        if !self.required() {
+            return Ok(());
+        }
+        match key {
+            Option::Some(key) if self.keys.contains(key) => Ok(()),
+            Option::Some(_) => Err(Status::Forbidden),
+            Option::None => Err(Status::Unauthorized)
+        }
+    }
+}
+
+
+/// Read keys given one on each line, ignoring blank lines and comments
+/// starting with `#`.
+fn read_keys(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+}
+
+
+/// The key a request was sent with, if any, from its `X-API-Key` and
+/// `Authorization` headers.
+pub fn header_key<'a>(
+    api_key: Option<&'a str>, authorization: Option<&'a str>
+) -> Option<&'a str> {
+    if let Option::Some(key) = api_key {
+        return Option::Some(key.trim());
+    }
+    authorization?.strip_prefix("Bearer ").map(str::trim)
+}
+
+
+/// The key a request was sent with, if any.
+fn request_key<'a>(request: &'a Request) -> Option<&'a str> {
+    header_key(
+        request.headers().get_one("X-API-Key"),
+        request.headers().get_one("Authorization")
+    )
+}
+
+
+/// Check the key a request was sent with, failing with the status it
+/// should be refused with. Every request passes if no keys are configured,
+/// or no `ApiKeys` are managed.
+fn check_key(request: &Request) -> Result<(), Status> {
+    match request.rocket().state::<ApiKeys>() {
+        Option::Some(keys) => keys.check(request_key(request)),
+        Option::None => Ok(())
+    }
+}
+
+
+/// The error for a request refused with this status by `check_key`.
+pub fn key_error(status: Status) -> ApiError {
+    if status == Status::Forbidden {
+        ApiError::new(
+            Status::Forbidden, "invalid_api_key", "The API key is not known."
+        )
+    } else {
+        ApiError::new(
+            Status::Unauthorized, "missing_api_key",
+            "This route needs an API key, given in the 'X-API-Key' header."
+        )
+    }
+}
+
+
+/// A request guard for routes which need an API key. Fails with 401
+/// Unauthorized if no key is given, or 403 Forbidden if the key isn't
+/// known. Every request passes if no keys are configured, or no `ApiKeys`
+/// are managed. Requests are rate limited first, as by `RateLimited`, so
+/// that keys can't be guessed quickly.
+pub struct Authorised;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authorised {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r Request<'_>
+    ) -> request::Outcome<Self, ()> {
+        if let Outcome::Error(error) = request.guard::<RateLimited>().await {
+            return Outcome::Error(error);
+        }
+        match check_key(request) {
+            Ok(()) => Outcome::Success(Authorised),
+            Err(status) => Outcome::Error((status, ()))
+        }
+    }
+}
+
+
+/// Whether a request has a valid API key, for routes where only some
+/// requests need one: the status to refuse those which do with, if it
+/// hasn't. Unlike `Authorised`, this never fails, and doesn't rate limit
+/// requests.
+#[cfg(any(feature = "graphql", feature = "websocket"))]
+pub struct KeyCheck(pub Result<(), Status>);
+
+#[cfg(any(feature = "graphql", feature = "websocket"))]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for KeyCheck {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r Request<'_>
+    ) -> request::Outcome<Self, ()> {
+        Outcome::Success(KeyCheck(check_key(request)))
+    }
+}
@@ -0,0 +1,124 @@
+//! An in-memory cache of optimisation results, since many requests are
+//! about the same few battles. The least recently used result is dropped
+//! when the cache is full, and results expire after a while.
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+
+/// How many results to keep if the `OPTIM_CACHE_SIZE` environment variable
+/// is not set.
+const DEFAULT_CAPACITY: u64 = 256;
+
+
+/// How many seconds to keep a result for if the `OPTIM_CACHE_TTL`
+/// environment variable is not set.
+const DEFAULT_LIFETIME: u64 = 60 * 60;
+
+
+/// Read a whole number from an environment variable, if it is set.
+fn env_number(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(
+            |_| panic!("{} must be a whole number, not '{}'.", name, value)
+        ),
+        Err(_) => default
+    }
+}
+
+
+/// A cached result, with the key it was stored under in full, in case of a
+/// hash collision.
+struct CacheEntry {
+    key: String,
+    result: Value,
+    stored: Instant,
+    last_used: u64
+}
+
+
+/// The most recently used optimisation results. A capacity of zero turns
+/// the cache off. Clones share the same entries.
+#[derive(Clone)]
+pub struct ResultCache {
+    capacity: usize,
+    lifetime: Duration,
+    // The entries, by the hash of their key, and a counter used to tell
+    // which was used least recently.
+    entries: Arc<Mutex<(HashMap<u64, CacheEntry>, u64)>>
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize, lifetime: Duration) -> ResultCache {
+        ResultCache {
+            capacity,
+            lifetime,
+            entries: Arc::new(Mutex::new((HashMap::new(), 0)))
+        }
+    }
+
+    /// Create a cache with the size and lifetime set by the
+    /// `OPTIM_CACHE_SIZE` and `OPTIM_CACHE_TTL` environment variables.
+    pub fn from_env() -> ResultCache {
+        let capacity = env_number("OPTIM_CACHE_SIZE", DEFAULT_CAPACITY);
+        let lifetime = env_number("OPTIM_CACHE_TTL", DEFAULT_LIFETIME);
+        ResultCache::new(capacity as usize, Duration::from_secs(lifetime))
+    }
+
+    /// Get the result stored under a key, if it hasn't expired.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, clock) = &mut *guard;
+        let hash = hash_key(key);
+        let stored = entries.get(&hash)?.stored;
+        if stored.elapsed() >= self.lifetime {
+            entries.remove(&hash);
+            return Option::None;
+        }
+        let entry = entries.get_mut(&hash).unwrap();
+        if entry.key != key {
+            return Option::None;
+        }
+        *clock += 1;
+        entry.last_used = *clock;
+        Option::Some(entry.result.clone())
+    }
+
+    /// Store a result under a key, dropping the least recently used result
+    /// if the cache is full.
+    pub fn insert(&self, key: String, result: Value) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, clock) = &mut *guard;
+        let hash = hash_key(&key);
+        if entries.len() >= self.capacity && !entries.contains_key(&hash) {
+            let oldest = entries.iter().min_by_key(
+                |(_, entry)| entry.last_used
+            ).map(|(hash, _)| *hash);
+            if let Option::Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        *clock += 1;
+        entries.insert(hash, CacheEntry {
+            key,
+            result,
+            stored: Instant::now(),
+            last_used: *clock
+        });
+    }
+}
+
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,145 @@
+//! Compression of responses, negotiated by the `Accept-Encoding` header, so
+//! that big responses such as `/units` and matchup tables cost clients on
+//! slow connections less. Responses are compressed with gzip or deflate,
+//! whichever the client prefers, or left as they are if it accepts
+//! neither.
+use std::io::{Cursor, Write};
+
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+
+
+/// The smallest body worth compressing, in bytes. Smaller bodies may even
+/// grow when compressed.
+const MIN_BYTES: usize = 1024;
+
+
+/// A way of compressing a response body.
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Gzip,
+    Deflate
+}
+
+impl Encoding {
+    /// The name of the encoding, as in `Content-Encoding`.
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate"
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(
+                    vec![], Compression::default()
+                );
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(
+                    vec![], Compression::default()
+                );
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+
+/// The encoding a client would most like, from its `Accept-Encoding`
+/// header, if it accepts any we can give. Gzip is chosen over deflate if
+/// the client likes both as much.
+fn preferred_encoding(accept: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = Option::None;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .filter_map(|quality| quality.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        let encoding = match name.as_str() {
+            "gzip" | "x-gzip" | "*" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            _ => continue
+        };
+        if quality <= 0.0 {
+            continue;
+        }
+        let better = match best {
+            Option::Some((chosen, best_quality)) => quality > best_quality
+                || (quality == best_quality && chosen == Encoding::Deflate),
+            Option::None => true
+        };
+        if better {
+            best = Option::Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+
+/// Compresses response bodies for clients which accept it.
+pub struct Compress;
+
+#[rocket::async_trait]
+impl Fairing for Compress {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(
+        &self, request: &'r Request<'_>, response: &mut Response<'r>
+    ) {
+        let accept = request.headers().get_one("Accept-Encoding");
+        let encoding = match accept.and_then(preferred_encoding) {
+            Option::Some(encoding) => encoding,
+            Option::None => return
+        };
+        if response.headers().contains("Content-Encoding") {
+            return;
+        }
+        // Other media, such as images, are usually compressed already.
+        // Event streams are left alone, since they must be sent as they go.
+        let compressible = response.content_type().is_some_and(|kind| {
+            kind.is_json() || kind.sub() == "javascript"
+                || (kind.top() == "text" && kind.sub() != "event-stream")
+        });
+        if !compressible {
+            return;
+        }
+        if response.body().is_none() {
+            return;
+        }
+        let body = match response.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(_) => return
+        };
+        let encoded = if body.len() < MIN_BYTES {
+            Option::None
+        } else {
+            encoding.encode(&body).ok()
+        };
+        match encoded {
+            Option::Some(encoded) => {
+                response.set_sized_body(encoded.len(), Cursor::new(encoded));
+                response.set_raw_header("Content-Encoding", encoding.name());
+            },
+            Option::None => {
+                response.set_sized_body(body.len(), Cursor::new(body))
+            }
+        }
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+    }
+}
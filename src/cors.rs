@@ -0,0 +1,140 @@
+//! Cross-origin resource sharing, so that calculators running in a browser
+//! can use the API directly. Which origins are allowed is set by the
+//! `CORS_ALLOWED_ORIGINS` environment variable, as a list separated by
+//! commas, or `*` for any. If it is not set, no other origins are allowed.
+use std::env;
+use std::io::Cursor;
+
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+
+
+/// The methods allowed if the `CORS_ALLOWED_METHODS` environment variable
+/// is not set.
+const DEFAULT_METHODS: &str = "GET, POST, DELETE, OPTIONS";
+
+
+/// The request headers allowed if the `CORS_ALLOWED_HEADERS` environment
+/// variable is not set.
+const DEFAULT_HEADERS: &str = "Content-Type";
+
+
+/// How long, in seconds, browsers may remember the answer to a preflight
+/// request.
+const MAX_AGE: u32 = 60 * 60;
+
+
+/// The origins allowed to make requests from a browser.
+pub enum Origins {
+    Any,
+    List(Vec<String>)
+}
+
+
+/// Adds CORS headers to responses for allowed origins, and answers
+/// preflight requests.
+pub struct Cors {
+    origins: Origins,
+    methods: String,
+    headers: String
+}
+
+impl Cors {
+    pub fn new(origins: Origins, methods: &str, headers: &str) -> Cors {
+        Cors {
+            origins,
+            methods: String::from(methods),
+            headers: String::from(headers)
+        }
+    }
+
+    /// Read the CORS settings from the `CORS_ALLOWED_ORIGINS`,
+    /// `CORS_ALLOWED_METHODS` and `CORS_ALLOWED_HEADERS` environment
+    /// variables. Returns `None` if no origins are allowed.
+    pub fn from_env() -> Option<Cors> {
+        let origins = env::var("CORS_ALLOWED_ORIGINS").ok()?;
+        let origins = if origins.trim() == "*" {
+            Origins::Any
+        } else {
+            Origins::List(
+                origins.split(',')
+                    .map(|origin| origin.trim().trim_end_matches('/'))
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            )
+        };
+        let methods = env::var("CORS_ALLOWED_METHODS")
+            .unwrap_or_else(|_| String::from(DEFAULT_METHODS));
+        let headers = env::var("CORS_ALLOWED_HEADERS")
+            .unwrap_or_else(|_| String::from(DEFAULT_HEADERS));
+        Option::Some(Cors::new(origins, &methods, &headers))
+    }
+
+    /// The value to give `Access-Control-Allow-Origin` for a request from
+    /// an origin, if it is allowed.
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        match &self.origins {
+            Origins::Any => Option::Some("*"),
+            Origins::List(origins) => if origins.iter().any(
+                |allowed| allowed == origin
+            ) {
+                Option::Some(origin)
+            } else {
+                Option::None
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(
+        &self, request: &'r Request<'_>, response: &mut Response<'r>
+    ) {
+        let origin = match request.headers().get_one("Origin") {
+            Option::Some(origin) => origin,
+            Option::None => return
+        };
+        let allowed = match self.allow_origin(origin) {
+            Option::Some(allowed) => allowed,
+            Option::None => return
+        };
+        response.set_raw_header(
+            "Access-Control-Allow-Origin", String::from(allowed)
+        );
+        if let Origins::List(_) = self.origins {
+            response.adjoin_raw_header("Vary", "Origin");
+        }
+        // So that browser clients can quote it when reporting a problem.
+        response.set_raw_header(
+            "Access-Control-Expose-Headers", "X-Request-Id"
+        );
+        let preflight = request.method() == Method::Options
+            && request.headers().contains("Access-Control-Request-Method");
+        // No route handles OPTIONS, so preflight requests are answered here
+        // rather than left as not found.
+        if preflight && response.status() == Status::NotFound {
+            response.set_status(Status::NoContent);
+            response.remove_header("Content-Type");
+            response.set_sized_body(0, Cursor::new(""));
+            response.set_raw_header(
+                "Access-Control-Allow-Methods", self.methods.clone()
+            );
+            response.set_raw_header(
+                "Access-Control-Allow-Headers", self.headers.clone()
+            );
+            response.set_raw_header(
+                "Access-Control-Max-Age", MAX_AGE.to_string()
+            );
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! An endpoint for Discord interactions, so that the server can be a bot's
+//! backend without any other code. It is built with the `discord` feature,
+//! and served at `/discord` if the `DISCORD_PUBLIC_KEY` environment
+//! variable is set to the application's public key, as hex.
+//!
+//! The bot should have `battle` and `optim` slash commands, each with a
+//! string option named `units` holding a battle such as
+//! `warrior archer:7 vs rider:walled`: the attackers, then `vs`, then the
+//! defender, each in the form read by `UnitInput::from_spec`.
+use std::convert::TryFrom;
+use std::env;
+use std::time::Instant;
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rocket::{Data, Request};
+use rocket::data::{self, ByteUnit, FromData};
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::{calc, limits, metrics, rules, units};
+
+
+/// The interaction types Discord sends, and the response types we give.
+const PING: u64 = 1;
+const APPLICATION_COMMAND: u64 = 2;
+const PONG: u64 = 1;
+const CHANNEL_MESSAGE: u64 = 4;
+
+
+/// A flag for messages only the user who ran the command can see.
+const EPHEMERAL: u64 = 1 << 6;
+
+
+/// Embed colours for battles the defender loses and survives.
+const KILLED_COLOUR: u32 = 0x2e_cc71;
+const SURVIVED_COLOUR: u32 = 0xe7_4c3c;
+
+
+/// How long, in milliseconds, an optimisation may take, since Discord only
+/// waits three seconds for a response.
+const OPTIM_MAX_MS: u64 = 2000;
+
+
+/// The Discord application whose interactions are accepted.
+pub struct DiscordApp {
+    public_key: VerifyingKey
+}
+
+impl DiscordApp {
+    /// Read the application's public key from the `DISCORD_PUBLIC_KEY`
+    /// environment variable. Returns `None` if it is not set, and panics
+    /// if it is not a valid key, so that a mistake isn't missed.
+    pub fn from_env() -> Option<DiscordApp> {
+        let key = env::var("DISCORD_PUBLIC_KEY").ok()?;
+        let public_key = decode_hex(key.trim())
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .unwrap_or_else(
+                || panic!("DISCORD_PUBLIC_KEY is not a valid public key.")
+            );
+        Option::Some(DiscordApp { public_key })
+    }
+
+    /// Whether a request body was signed by Discord, as it must be checked
+    /// before anything else is done with it.
+    fn verify(&self, request: &Request, body: &[u8]) -> bool {
+        let headers = request.headers();
+        let signature = headers.get_one("X-Signature-Ed25519")
+            .and_then(decode_hex)
+            .and_then(|bytes| <[u8; 64]>::try_from(bytes).ok())
+            .map(|bytes| Signature::from_bytes(&bytes));
+        let timestamp = headers.get_one("X-Signature-Timestamp");
+        match (signature, timestamp) {
+            (Option::Some(signature), Option::Some(timestamp)) => {
+                let mut message = timestamp.as_bytes().to_vec();
+                message.extend_from_slice(body);
+                self.public_key.verify(&message, &signature).is_ok()
+            },
+            _ => false
+        }
+    }
+}
+
+
+/// Read bytes written as hex.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Option::None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+
+/// An interaction from Discord, whose signature has been checked. Requests
+/// which aren't signed by Discord are rejected with 401 Unauthorized, as
+/// Discord requires.
+pub struct Interaction(Value);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for Interaction {
+    type Error = String;
+
+    async fn from_data(
+        request: &'r Request<'_>, data: Data<'r>
+    ) -> data::Outcome<'r, Self> {
+        let app = match request.rocket().state::<DiscordApp>() {
+            Option::Some(app) => app,
+            Option::None => return Outcome::Forward((data, Status::NotFound))
+        };
+        let limit = ByteUnit::from(*limits::MAX_BODY_BYTES);
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            _ => return Outcome::Error((
+                Status::BadRequest, String::from("The body can't be read.")
+            ))
+        };
+        if !app.verify(request, &body) {
+            return Outcome::Error((
+                Status::Unauthorized, String::from("Invalid signature.")
+            ));
+        }
+        match serde_json::from_slice(&body) {
+            Ok(value) => Outcome::Success(Interaction(value)),
+            Err(error) => Outcome::Error((
+                Status::BadRequest, error.to_string()
+            ))
+        }
+    }
+}
+
+
+/// Read a battle written as `warrior archer:7 vs rider:walled`.
+fn parse_battle(text: &str) -> Result<calc::BattleInput, ApiError> {
+    let words: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .collect();
+    let split = words.iter().position(|word| word.eq_ignore_ascii_case("vs"));
+    let (attackers, defender) = match split {
+        Option::Some(split) => (&words[..split], &words[split + 1..]),
+        Option::None => (&words[..], &[][..])
+    };
+    let defender = match defender {
+        [defender] => calc::UnitInput::from_spec(defender)?,
+        _ => return Err(ApiError::new(
+            Status::BadRequest, "invalid_battle",
+            "Give the attackers, then 'vs', then one defender, such as \
+            'warrior archer:7 vs rider:walled'."
+        ))
+    };
+    let mut battle = calc::BattleInput {
+        attackers: vec![],
+        defender,
+        defenders: vec![],
+        adjacent: vec![]
+    };
+    for attacker in attackers.iter() {
+        battle.attackers.push(calc::UnitInput::from_spec(attacker)?);
+    }
+    if battle.attackers.is_empty() {
+        return Err(ApiError::new(
+            Status::BadRequest, "invalid_battle", "No attackers were given."
+        ));
+    }
+    Ok(battle)
+}
+
+
+/// Set up a battle to calculate, under the latest rules.
+fn battle_state(
+    battle: &calc::BattleInput, limit: usize
+) -> Result<calc::BattleState, ApiError> {
+    limits::check_attackers(battle.attackers.len(), limit)?;
+    crate::api::check_battle(battle)?;
+    let mut state = battle.to_state()?;
+    state.rules = *rules::LATEST;
+    Ok(state)
+}
+
+
+/// A unit's health, as it was and as it is now.
+fn health_change(before: i32, unit: &units::Unit) -> String {
+    if unit.health <= 0 {
+        format!("{} → dead", before)
+    } else {
+        format!("{} → {} HP", before, unit.health)
+    }
+}
+
+
+/// Describe the outcome of a battle as an embed.
+fn battle_embed(
+    before: &calc::BattleState, after: &calc::BattleState
+) -> Value {
+    let mut fields = vec![];
+    for (start, attacker) in before.attackers.iter()
+        .zip(after.attackers.iter())
+    {
+        fields.push(json!({
+            "name": attacker.display_name,
+            "value": format!(
+                "{}, dealt {}",
+                health_change(start.health, attacker), attacker.damage_dealt
+            ),
+            "inline": true
+        }));
+    }
+    let defender = &after.defender;
+    fields.push(json!({
+        "name": format!("{} (defender)", defender.display_name),
+        "value": health_change(before.defender.health, defender),
+        "inline": false
+    }));
+    let names: Vec<&str> = after.attackers.iter()
+        .map(|attacker| attacker.display_name.as_str())
+        .collect();
+    let killed = defender.health <= 0;
+    json!({
+        "title": format!(
+            "{} vs {}", names.join(", "), defender.display_name
+        ),
+        "color": if killed { KILLED_COLOUR } else { SURVIVED_COLOUR },
+        "fields": fields
+    })
+}
+
+
+/// Calculate a battle in the order given.
+fn run_battle(text: &str) -> Result<Value, ApiError> {
+    let battle = parse_battle(text)?;
+    let before = battle_state(&battle, *limits::MAX_BATTLE_ATTACKERS)?;
+    let mut after = before.clone();
+    calc::battle_many(&mut after);
+    Ok(battle_embed(&before, &after))
+}
+
+
+/// Find the best order of attack, and calculate the battle in that order.
+fn run_optim(text: &str) -> Result<Value, ApiError> {
+    let battle = parse_battle(text)?;
+    let state = battle_state(&battle, *limits::MAX_OPTIM_ATTACKERS)?;
+    let input: calc::OptimInput = serde_json::from_value(json!({
+        "attackers": battle.attackers,
+        "defender": battle.defender,
+        "max_ms": OPTIM_MAX_MS
+    })).map_err(|error| ApiError::new(
+        Status::InternalServerError, "internal_error", error.to_string()
+    ))?;
+    let started = Instant::now();
+    let result = input.optimise(state, Option::None, Option::None);
+    metrics::record_search(&result, started.elapsed());
+    let order: Vec<calc::UnitInput> = result["order"].as_array()
+        .map(|order| order.iter()
+            .filter_map(|idx| idx.as_u64())
+            .map(|idx| battle.attackers[idx as usize].clone())
+            .collect())
+        .unwrap_or_default();
+    let best = calc::BattleInput { attackers: order, ..battle };
+    let before = battle_state(&best, *limits::MAX_OPTIM_ATTACKERS)?;
+    let mut after = before.clone();
+    calc::battle_many(&mut after);
+    let mut embed = battle_embed(&before, &after);
+    embed["description"] = json!("The best order of attack.");
+    Ok(embed)
+}
+
+
+/// The `units` option of a command.
+fn units_option(interaction: &Value) -> Option<&str> {
+    interaction["data"]["options"].as_array()?
+        .iter()
+        .find(|option| option["name"] == "units")?
+        ["value"].as_str()
+}
+
+
+/// Respond to an interaction: pings are answered so Discord can check the
+/// endpoint, and commands with the result of the battle, or with an error
+/// only the user can see.
+#[post("/discord", data = "<interaction>")]
+pub fn interaction(interaction: Interaction) -> Value {
+    let interaction = interaction.0;
+    match interaction["type"].as_u64() {
+        Option::Some(PING) => return json!({"type": PONG}),
+        Option::Some(APPLICATION_COMMAND) => (),
+        _ => return message("This interaction isn't supported.")
+    }
+    let text = match units_option(&interaction) {
+        Option::Some(text) => text,
+        Option::None => return message("No 'units' option was given.")
+    };
+    let result = match interaction["data"]["name"].as_str() {
+        Option::Some("battle") => run_battle(text),
+        Option::Some("optim") => run_optim(text),
+        _ => return message("This command isn't supported.")
+    };
+    match result {
+        Ok(embed) => json!({
+            "type": CHANNEL_MESSAGE,
+            "data": {"embeds": [embed]}
+        }),
+        Err(error) => message(&error.message)
+    }
+}
+
+
+/// A message only the user who ran the command can see.
+fn message(content: &str) -> Value {
+    json!({
+        "type": CHANNEL_MESSAGE,
+        "data": {"content": content, "flags": EPHEMERAL}
+    })
+}
@@ -0,0 +1,158 @@
+//! The errors the API responds with. Each has a short, stable code, so that
+//! clients can tell kinds of error apart without reading the message, and
+//! is sent as `{"error": {"code": ..., "status": ..., "message": ...}}`,
+//! with any details of the error alongside the message.
+use rocket::Request;
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use serde_json::Value;
+
+use crate::{calc, jobs, limits, logging, rules};
+
+
+/// An error to respond to a request with.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub code: &'static str,
+    pub message: String,
+    // Anything else a client might want to know about the error, such as
+    // the unit ID which wasn't known.
+    details: Vec<(String, Value)>
+}
+
+impl ApiError {
+    pub fn new(
+        status: Status, code: &'static str, message: impl Into<String>
+    ) -> ApiError {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            details: vec![]
+        }
+    }
+
+    /// Build an error from the JSON an error type describes itself with,
+    /// where `error` is the message and the other fields are details.
+    fn from_json(
+        status: Status, code: &'static str, json: Value
+    ) -> ApiError {
+        let mut error = ApiError::new(status, code, "");
+        if let Option::Some(fields) = json.as_object() {
+            for (key, value) in fields.iter() {
+                if key == "error" {
+                    error.message = value.as_str().unwrap_or_default().into();
+                } else {
+                    error.details.push((key.clone(), value.clone()));
+                }
+            }
+        }
+        error
+    }
+
+    /// Add a detail to the error.
+    pub fn with(mut self, key: &str, value: Value) -> ApiError {
+        self.details.push((String::from(key), value));
+        self
+    }
+
+    /// The same error, with another status.
+    pub fn with_status(mut self, status: Status) -> ApiError {
+        self.status = status;
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut error = json!({
+            "code": self.code,
+            "status": self.status.code,
+            "message": self.message
+        });
+        for (key, value) in self.details.iter() {
+            error[key.as_str()] = value.clone();
+        }
+        json!({"error": error})
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request) -> response::Result<'static> {
+        logging::record_error(request, self.code);
+        Response::build_from(self.to_json().respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+
+impl From<calc::UnknownUnit> for ApiError {
+    fn from(error: calc::UnknownUnit) -> ApiError {
+        ApiError::from_json(
+            Status::BadRequest, "unknown_unit", error.to_json()
+        )
+    }
+}
+
+impl From<calc::MisplacedFlag> for ApiError {
+    fn from(error: calc::MisplacedFlag) -> ApiError {
+        ApiError::from_json(
+            Status::BadRequest, "invalid_flags", error.to_json()
+        )
+    }
+}
+
+impl From<calc::InvalidSpec> for ApiError {
+    fn from(error: calc::InvalidSpec) -> ApiError {
+        ApiError::from_json(
+            Status::BadRequest, "invalid_unit_spec", error.to_json()
+        )
+    }
+}
+
+impl From<rules::InvalidConstant> for ApiError {
+    fn from(error: rules::InvalidConstant) -> ApiError {
+        ApiError::from_json(
+            Status::BadRequest, "invalid_rules", error.to_json()
+        )
+    }
+}
+
+impl From<limits::TooManyAttackers> for ApiError {
+    fn from(error: limits::TooManyAttackers) -> ApiError {
+        ApiError::from_json(
+            Status::UnprocessableEntity, "too_many_attackers", error.to_json()
+        )
+    }
+}
+
+impl From<limits::BatchTooLarge> for ApiError {
+    fn from(error: limits::BatchTooLarge) -> ApiError {
+        ApiError::from_json(
+            Status::UnprocessableEntity, "batch_too_large", error.to_json()
+        )
+    }
+}
+
+impl From<jobs::SubmitError> for ApiError {
+    fn from(error: jobs::SubmitError) -> ApiError {
+        match error {
+            jobs::SubmitError::QueueFull(limit) => ApiError::new(
+                Status::ServiceUnavailable, "queue_full", format!(
+                    "{} jobs are already waiting; try again later.", limit
+                )
+            ).with("limit", json!(limit)),
+            jobs::SubmitError::Stopped => ApiError::new(
+                Status::ServiceUnavailable, "workers_stopped",
+                "The optimisation workers have stopped."
+            )
+        }
+    }
+}
+
+
+impl From<jobs::UnknownJob> for ApiError {
+    fn from(error: jobs::UnknownJob) -> ApiError {
+        ApiError::from_json(Status::NotFound, "unknown_job", error.to_json())
+    }
+}
@@ -0,0 +1,72 @@
+//! Entity tags for responses which rarely change, such as the unit list, so
+//! that clients and caches can check whether their copy is still current
+//! rather than downloading it again.
+use rocket::Request;
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+
+
+/// How long, in seconds, clients may use a tagged response without
+/// checking it again.
+const MAX_AGE: u32 = 60 * 60;
+
+
+/// A response with an entity tag. If the request's `If-None-Match` header
+/// has the tag, the response is 304 Not Modified, with no body.
+pub struct Tagged<R> {
+    tag: String,
+    response: R
+}
+
+impl<R> Tagged<R> {
+    /// Tag a response. The tag should change whenever the response would.
+    pub fn new(tag: &str, response: R) -> Tagged<R> {
+        Tagged { tag: String::from(tag), response }
+    }
+
+    /// Whether a request's `If-None-Match` header has the tag.
+    fn matches(&self, request: &Request) -> bool {
+        matches(&self.tag, request.headers().get("If-None-Match"))
+    }
+}
+
+
+/// A tag as sent in `ETag`. Tags are weak, since the body may be compressed
+/// differently for each client.
+pub fn header(tag: &str) -> String {
+    format!("W/\"{}\"", tag)
+}
+
+
+/// Whether any of a request's `If-None-Match` headers has a tag.
+pub fn matches<'a>(
+    tag: &str, if_none_match: impl Iterator<Item = &'a str>
+) -> bool {
+    if_none_match
+        .flat_map(|tags| tags.split(','))
+        .map(|tag| tag.trim())
+        .any(|sent| {
+            let sent = sent.strip_prefix("W/").unwrap_or(sent);
+            sent == "*" || sent.trim_matches('"') == tag
+        })
+}
+
+
+/// The value of `Cache-Control` for a tagged response.
+pub fn cache_control() -> String {
+    format!("public, max-age={}", MAX_AGE)
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for Tagged<R> {
+    fn respond_to(self, request: &'r Request) -> response::Result<'static> {
+        let tag = header(&self.tag);
+        let mut response = if self.matches(request) {
+            Response::build().status(Status::NotModified).finalize()
+        } else {
+            self.response.respond_to(request)?
+        };
+        response.set_raw_header("ETag", tag);
+        response.set_raw_header("Cache-Control", cache_control());
+        Ok(response)
+    }
+}
@@ -0,0 +1,322 @@
+//! A GraphQL endpoint, at `/graphql`, so that frontends can fetch exactly
+//! the fields they need, and several things at once, in one request. It is
+//! built with the `graphql` feature.
+//!
+//! Unit types are queried with `units` and `unit`, and battles with
+//! `battle`, which takes the same input as `POST /battle`. Optimisations
+//! are run with the `optimise` mutation, which takes the same input as
+//! `POST /optim`, and needs an API key if `/optim` does.
+use async_graphql::{
+    Context, EmptySubscription, Enum, ErrorExtensions, Json, Object,
+    Schema, SimpleObject
+};
+use rocket::State;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::{auth, cache, calc, limits, ratelimit, rules, stats, units};
+
+
+/// The deepest a query may nest fields.
+const MAX_DEPTH: usize = 10;
+
+
+/// The most work a query may ask for, where each field counts as one,
+/// battles as `BATTLE_COMPLEXITY` and optimisations as
+/// `OPTIM_COMPLEXITY`, so that a query can't hide a huge batch.
+const MAX_COMPLEXITY: usize = 1000;
+const BATTLE_COMPLEXITY: usize = 10;
+const OPTIM_COMPLEXITY: usize = 100;
+
+
+/// The schema served at `/graphql`.
+pub type ApiSchema = Schema<Query, Mutation, EmptySubscription>;
+
+
+/// Build the schema.
+pub fn schema() -> ApiSchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+
+/// An error as a GraphQL error, with its code, status and details as
+/// extensions.
+fn graphql_error(error: ApiError) -> async_graphql::Error {
+    let json = error.to_json();
+    async_graphql::Error::new(error.message).extend_with(|_, extensions| {
+        if let Option::Some(fields) = json["error"].as_object() {
+            for (key, value) in fields.iter() {
+                if key == "message" {
+                    continue;
+                }
+                if let Ok(value) = async_graphql::Value::from_json(
+                    value.clone()
+                ) {
+                    extensions.set(key.as_str(), value);
+                }
+            }
+        }
+    })
+}
+
+
+/// Read an input given as JSON, as the routes read their bodies.
+fn read_input<T: DeserializeOwned>(input: Value) -> Result<T, ApiError> {
+    serde_json::from_value(input).map_err(|error| ApiError::new(
+        rocket::http::Status::UnprocessableEntity, "invalid_body",
+        error.to_string()
+    ))
+}
+
+
+/// Read a result as the GraphQL object it is described by.
+fn read_result<T: DeserializeOwned>(
+    result: Value
+) -> async_graphql::Result<T> {
+    serde_json::from_value(result)
+        .map_err(|error| async_graphql::Error::new(error.to_string()))
+}
+
+
+/// A version of the game's combat rules.
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+pub enum Ruleset {
+    /// Before the Moonrise update.
+    Legacy,
+    Moonrise,
+    /// The current version of the game.
+    Latest
+}
+
+impl Ruleset {
+    fn to_query(self) -> rules::RulesQuery {
+        rules::RulesQuery::of_version(match self {
+            Ruleset::Legacy => rules::Version::Legacy,
+            Ruleset::Moonrise => rules::Version::Moonrise,
+            Ruleset::Latest => rules::Version::Latest
+        })
+    }
+}
+
+
+/// A unit type, as given by `GET /units/<id>`.
+#[derive(SimpleObject, Deserialize)]
+pub struct Unit {
+    id: String,
+    display_name: String,
+    aliases: Vec<String>,
+    hidden: bool,
+    health: i32,
+    attack: f32,
+    defence: f32,
+    range: i32,
+    // The value of the unit, in stars.
+    cost: i32,
+    abilities: Vec<String>,
+    // The special tribe the unit belongs to, if any.
+    tribe: Option<String>,
+    // The IDs of the unit types this one can be upgraded into.
+    upgrades: Vec<String>
+}
+
+impl Unit {
+    fn from_type(unit_type: &units::UnitType) -> async_graphql::Result<Unit> {
+        read_result(json!(unit_type))
+    }
+}
+
+
+/// What an attacker did in a battle.
+#[derive(SimpleObject, Deserialize)]
+pub struct Attack {
+    damage_dealt: i32,
+    retaliation_taken: i32,
+    survived: bool
+}
+
+
+/// The state of a defending unit after a battle.
+#[derive(SimpleObject, Deserialize)]
+pub struct DefendingUnit {
+    health: i32,
+    damage: i32,
+    frozen: bool,
+    poisoned: bool,
+    converted: bool
+}
+
+
+/// The outcome of a battle, as given by `POST /battle`.
+#[derive(SimpleObject, Deserialize)]
+pub struct Battle {
+    // The health of each attacker afterwards.
+    attackers: Vec<i32>,
+    drained: Vec<i32>,
+    attacks: Vec<Attack>,
+    promotions: Vec<bool>,
+    defender: DefendingUnit,
+    defender_killed: bool,
+    overkill: i32,
+    defenders: Vec<DefendingUnit>,
+    adjacent: Vec<DefendingUnit>,
+    follow_ups: Vec<Vec<DefendingUnit>>,
+    // Everything which happened, if the input asked to explain the battle.
+    #[serde(default)]
+    events: Option<Json<Value>>,
+    #[serde(default)]
+    warnings: Vec<String>
+}
+
+
+/// An attacker in an order of attack.
+#[derive(SimpleObject, Deserialize)]
+pub struct NamedAttacker {
+    index: i32,
+    id: String,
+    display_name: String
+}
+
+
+/// An order of attack, with the outcome of attacking in it.
+#[derive(SimpleObject, Deserialize)]
+pub struct Order {
+    order: Vec<i32>,
+    named_order: Vec<NamedAttacker>,
+    state: Battle
+}
+
+
+/// How much searching an optimisation did.
+#[derive(SimpleObject, Deserialize)]
+pub struct Search {
+    permutations_evaluated: u64,
+    attacks_simulated: u64,
+    branches_pruned: u64,
+    elapsed_ms: u64,
+    exhaustive: bool,
+    complete: bool
+}
+
+
+/// The result of an optimisation, as given by `POST /optim`. The order and
+/// state are null if no order meets the constraints.
+#[derive(SimpleObject, Deserialize)]
+pub struct Optimisation {
+    order: Option<Vec<i32>>,
+    named_order: Option<Vec<NamedAttacker>>,
+    state: Option<Battle>,
+    // The best orders found, if the input asked for more than one.
+    #[serde(default)]
+    orders: Option<Vec<Order>>,
+    search: Search,
+    proven_optimal: bool,
+    complete: bool,
+    cached: bool
+}
+
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// The unit types which meet every condition given.
+    #[allow(clippy::too_many_arguments)]
+    async fn units(
+        &self,
+        tribe: Option<String>,
+        ability: Option<String>,
+        ranged: Option<bool>,
+        hidden: Option<bool>,
+        min_attack: Option<f32>,
+        max_attack: Option<f32>,
+        min_defence: Option<f32>,
+        max_defence: Option<f32>
+    ) -> async_graphql::Result<Vec<Unit>> {
+        let filter = units::UnitFilter {
+            tribe, ability, ranged, hidden, min_attack, max_attack,
+            min_defence, max_defence
+        };
+        units::UNIT_LIST.units.iter()
+            .filter(|unit_type| unit_type.matches(&filter))
+            .map(Unit::from_type)
+            .collect()
+    }
+
+    /// A unit type, by ID or alias, or null if there is none.
+    async fn unit(&self, id: String) -> async_graphql::Result<Option<Unit>> {
+        units::UNIT_LIST.find_unit_type(&id).map(Unit::from_type).transpose()
+    }
+
+    /// Calculate a battle, given as the body of `POST /battle` would be.
+    #[graphql(complexity = "BATTLE_COMPLEXITY + child_complexity")]
+    async fn battle(
+        &self,
+        context: &Context<'_>,
+        input: Json<Value>,
+        ruleset: Option<Ruleset>
+    ) -> async_graphql::Result<Battle> {
+        let stats = context.data::<stats::MatchupStats>()?;
+        let run = || -> Result<Value, ApiError> {
+            let input: calc::ExplainInput = read_input(input.0)?;
+            let rules = ruleset.unwrap_or(Ruleset::Latest)
+                .to_query()
+                .to_ruleset()?;
+            crate::api::battle(
+                &input, Option::None, &Option::None, &rules, stats
+            )
+        };
+        read_result(run().map_err(graphql_error)?)
+    }
+}
+
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Find the best order of attack, or the fewest attackers, for a
+    /// battle given as the body of `POST /optim` would be.
+    #[graphql(complexity = "OPTIM_COMPLEXITY + child_complexity")]
+    async fn optimise(
+        &self,
+        context: &Context<'_>,
+        input: Json<Value>,
+        ruleset: Option<Ruleset>
+    ) -> async_graphql::Result<Optimisation> {
+        let stats = context.data::<stats::MatchupStats>()?.clone();
+        let cache = context.data::<cache::ResultCache>()?.clone();
+        let key = context.data::<auth::KeyCheck>()?.0;
+        let run = move || -> Result<Value, ApiError> {
+            key.map_err(auth::key_error)?;
+            let input: calc::OptimInput = read_input(input.0)?;
+            let rules = ruleset.unwrap_or(Ruleset::Latest).to_query();
+            crate::api::optimise(
+                &input, Option::None, &Option::None, &rules, &stats, &cache
+            )
+        };
+        read_result(crate::api::blocking(run).await.map_err(graphql_error)?)
+    }
+}
+
+
+/// Run a GraphQL query or mutation.
+#[post("/graphql", data = "<request>")]
+pub async fn graphql(
+    request: limits::JsonBody<async_graphql::Request>,
+    schema: &State<ApiSchema>,
+    stats: &State<stats::MatchupStats>,
+    cache: &State<cache::ResultCache>,
+    _limited: ratelimit::RateLimited,
+    key: auth::KeyCheck
+) -> Value {
+    let request = request.0
+        .data(stats.inner().clone())
+        .data(cache.inner().clone())
+        .data(key);
+    json!(schema.execute(request).await)
+}
@@ -0,0 +1,364 @@
+//! A gRPC service mirroring the REST API, for backends which would rather
+//! work from a protobuf contract, defined in `proto/polycalc.proto`. It is
+//! built with the `grpc` feature, and served if the `GRPC_ADDRESS`
+//! environment variable is set to the address to listen on, such as
+//! `0.0.0.0:50051`.
+//!
+//! The service runs on Rocket's runtime, alongside its server, and shares
+//! its result cache, matchup statistics and API keys. It isn't rate limited,
+//! so it should only be reachable by trusted backends.
+use std::env;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use rocket::http::Status as HttpStatus;
+use rocket::tokio;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio_stream::Stream;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+
+use crate::error::ApiError;
+use crate::{auth, cache, calc, rules, stats, units};
+
+use proto::calculator_server::{Calculator, CalculatorServer};
+
+
+/// The messages and service generated from `proto/polycalc.proto`.
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("polycalc.v1");
+}
+
+
+/// Where to serve the gRPC service.
+pub struct GrpcServer {
+    address: SocketAddr
+}
+
+impl GrpcServer {
+    /// Read the address to listen on from the `GRPC_ADDRESS` environment
+    /// variable. Returns `None` if it is not set, and panics if it is not a
+    /// valid address, so that a mistake isn't missed.
+    pub fn from_env() -> Option<GrpcServer> {
+        let address = env::var("GRPC_ADDRESS").ok()?;
+        let address = address.parse().unwrap_or_else(|_| panic!(
+            "GRPC_ADDRESS must be an address such as 0.0.0.0:50051, not \
+            '{}'.",
+            address
+        ));
+        Option::Some(GrpcServer { address })
+    }
+
+    /// Start serving in a task of its own. This must be called from within
+    /// Rocket's runtime.
+    pub fn spawn(self, service: CalculatorService) {
+        tracing::info!(
+            address = %self.address, "Listening for gRPC requests."
+        );
+        tokio::spawn(async move {
+            let served = Server::builder()
+                .add_service(CalculatorServer::new(service))
+                .serve(self.address)
+                .await;
+            if let Err(error) = served {
+                tracing::error!(%error, "The gRPC service stopped.");
+            }
+        });
+    }
+}
+
+
+/// The calculator, as served over gRPC.
+#[derive(Clone)]
+pub struct CalculatorService {
+    stats: stats::MatchupStats,
+    cache: cache::ResultCache,
+    keys: auth::ApiKeys
+}
+
+impl CalculatorService {
+    pub fn new(
+        stats: stats::MatchupStats, cache: cache::ResultCache,
+        keys: auth::ApiKeys
+    ) -> CalculatorService {
+        CalculatorService { stats, cache, keys }
+    }
+}
+
+
+/// An error as a gRPC status, with its code in the `x-error-code`
+/// metadata.
+fn grpc_error(error: ApiError) -> Status {
+    let code = match error.status.code {
+        400 | 422 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        413 | 429 => Code::ResourceExhausted,
+        503 => Code::Unavailable,
+        _ => Code::Internal
+    };
+    let mut status = Status::new(code, error.message);
+    if let Ok(value) = error.code.parse() {
+        status.metadata_mut().insert("x-error-code", value);
+    }
+    status
+}
+
+
+/// The API key a request was sent with, if any, as in the `X-API-Key` or
+/// `Authorization` headers of the REST API.
+fn request_key(metadata: &MetadataMap) -> Option<&str> {
+    if let Option::Some(key) = metadata.get("x-api-key") {
+        return key.to_str().ok().map(str::trim);
+    }
+    metadata.get("authorization")?
+        .to_str().ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+
+/// Read an input built as JSON, as the routes read their bodies.
+fn read_input<T: DeserializeOwned>(input: Value) -> Result<T, ApiError> {
+    serde_json::from_value(input).map_err(|error| ApiError::new(
+        HttpStatus::UnprocessableEntity, "invalid_body", error.to_string()
+    ))
+}
+
+
+/// Read a result as the message it is described by.
+fn read_result<T: DeserializeOwned>(result: Value) -> Result<T, Status> {
+    serde_json::from_value(result)
+        .map_err(|error| Status::internal(error.to_string()))
+}
+
+
+/// The rules of a version of the game.
+fn rules_query(ruleset: proto::Ruleset) -> rules::RulesQuery {
+    rules::RulesQuery::of_version(match ruleset {
+        proto::Ruleset::Latest => rules::Version::Latest,
+        proto::Ruleset::Legacy => rules::Version::Legacy,
+        proto::Ruleset::Moonrise => rules::Version::Moonrise
+    })
+}
+
+
+/// A unit as the REST API would be given it.
+fn unit_json(unit: &proto::Unit) -> Result<Value, ApiError> {
+    let mut flags = units::UnitFlags::default();
+    for name in unit.flags.iter() {
+        if !flags.set(name) {
+            return Err(ApiError::new(
+                HttpStatus::BadRequest, "unknown_flag",
+                format!("There is no flag named '{}'.", name)
+            ).with("flag", json!(name)));
+        }
+    }
+    let city = match unit.city() {
+        proto::City::Outside => "outside",
+        proto::City::Unwalled => "unwalled",
+        proto::City::Walled => "walled"
+    };
+    let terrain = match unit.terrain() {
+        proto::Terrain::Field => "field",
+        proto::Terrain::Forest => "forest",
+        proto::Terrain::Mountain => "mountain",
+        proto::Terrain::Water => "water",
+        proto::Terrain::City => "city"
+    };
+    let follow_up = unit.follow_up.iter()
+        .map(unit_json)
+        .collect::<Result<Vec<Value>, ApiError>>()?;
+    Ok(json!({
+        "unit": unit.unit,
+        "health": unit.health,
+        "flags": flags,
+        "follow_up": follow_up,
+        "target": unit.target,
+        "heal": unit.heal,
+        "explode": unit.explode,
+        "boost": unit.boost,
+        "city": city,
+        "terrain": terrain,
+        "techs": unit.techs,
+        "carrying": unit.carrying,
+        "kills": unit.kills
+    }))
+}
+
+
+/// A list of units as the REST API would be given it.
+fn units_json(units: &[proto::Unit]) -> Result<Vec<Value>, ApiError> {
+    units.iter().map(unit_json).collect()
+}
+
+
+/// A battle as the body of `POST /battle` would give it.
+fn battle_json(battle: &proto::BattleRequest) -> Result<Value, ApiError> {
+    let defender = battle.defender.as_ref().ok_or_else(|| ApiError::new(
+        HttpStatus::BadRequest, "missing_defender", "No defender was given."
+    ))?;
+    Ok(json!({
+        "attackers": units_json(&battle.attackers)?,
+        "defender": unit_json(defender)?,
+        "defenders": units_json(&battle.defenders)?,
+        "adjacent": units_json(&battle.adjacent)?
+    }))
+}
+
+
+/// An optimisation as the body of `POST /optim` would give it.
+fn optim_json(optim: &proto::OptimiseRequest) -> Result<Value, ApiError> {
+    let default_battle = proto::BattleRequest::default();
+    let mut input = battle_json(
+        optim.battle.as_ref().unwrap_or(&default_battle)
+    )?;
+    let perspective = match optim.perspective() {
+        proto::Perspective::Attacker => "attacker",
+        proto::Perspective::Defender => "defender"
+    };
+    let objective = optim.objective.unwrap_or_default();
+    let objective = match objective.kind() {
+        proto::objective::Kind::Overall => json!("overall"),
+        proto::objective::Kind::KillDefender => json!("kill_defender"),
+        proto::objective::Kind::MinAttackerDeaths => {
+            json!("min_attacker_deaths")
+        },
+        proto::objective::Kind::MaxDamage => json!("max_damage"),
+        proto::objective::Kind::PreserveUnit => {
+            json!({"preserve_unit": objective.attacker})
+        }
+    };
+    let constraints: Vec<Value> = optim.constraints.iter().map(
+        |constraint| match constraint.kind() {
+            proto::constraint::Kind::First => {
+                json!({"first": constraint.attacker})
+            },
+            proto::constraint::Kind::Last => {
+                json!({"last": constraint.attacker})
+            },
+            proto::constraint::Kind::Before => json!({
+                "before": [constraint.attacker, constraint.other]
+            }),
+            proto::constraint::Kind::Survives => {
+                json!({"survives": constraint.attacker})
+            }
+        }
+    ).collect();
+    let mode = match optim.mode() {
+        proto::SearchMode::Exhaustive => "exhaustive",
+        proto::SearchMode::Heuristic => "heuristic"
+    };
+    input["perspective"] = json!(perspective);
+    input["objective"] = objective;
+    input["minimise"] = json!(optim.minimise);
+    input["top"] = json!(optim.top);
+    input["constraints"] = json!(constraints);
+    input["mode"] = json!(mode);
+    input["max_ms"] = json!(optim.max_ms);
+    Ok(input)
+}
+
+
+/// Reshape the state of a battle, as the REST API gives it, into a
+/// `BattleResult`, whose follow ups are nested in messages.
+fn battle_result(mut state: Value) -> Value {
+    if let Option::Some(follow_ups) = state["follow_ups"].as_array_mut() {
+        for units in follow_ups.iter_mut() {
+            *units = json!({"units": units.take()});
+        }
+    }
+    state
+}
+
+
+/// Reshape an order of attack as the REST API gives it into an `Order`.
+fn order_result(mut order: Value) -> Value {
+    order["state"] = battle_result(order["state"].take());
+    order
+}
+
+
+#[tonic::async_trait]
+impl Calculator for CalculatorService {
+    async fn battle(
+        &self, request: Request<proto::BattleRequest>
+    ) -> Result<Response<proto::BattleResult>, Status> {
+        let service = self.clone();
+        let request = request.into_inner();
+        let result = tokio::task::spawn_blocking(move || {
+            let input: calc::ExplainInput = read_input(
+                battle_json(&request)?
+            )?;
+            let rules = rules_query(request.ruleset()).to_ruleset()?;
+            crate::api::battle(
+                &input, Option::None, &Option::None, &rules, &service.stats
+            )
+        }).await.map_err(|error| Status::internal(error.to_string()))?;
+        let result = result.map_err(grpc_error)?;
+        Ok(Response::new(read_result(battle_result(result))?))
+    }
+
+    async fn optimise(
+        &self, request: Request<proto::OptimiseRequest>
+    ) -> Result<Response<proto::OptimiseResult>, Status> {
+        self.keys.check(request_key(request.metadata()))
+            .map_err(|status| grpc_error(auth::key_error(status)))?;
+        let service = self.clone();
+        let request = request.into_inner();
+        let result = tokio::task::spawn_blocking(move || {
+            let input: calc::OptimInput = read_input(optim_json(&request)?)?;
+            let ruleset = request.battle.as_ref()
+                .map(|battle| battle.ruleset())
+                .unwrap_or_default();
+            crate::api::optimise(
+                &input, Option::None, &Option::None, &rules_query(ruleset),
+                &service.stats, &service.cache
+            )
+        }).await.map_err(|error| Status::internal(error.to_string()))?;
+        let mut result = result.map_err(grpc_error)?;
+        if !result["order"].is_null() {
+            result["best"] = order_result(json!({
+                "order": result["order"].take(),
+                "named_order": result["named_order"].take(),
+                "state": result["state"].take()
+            }));
+        }
+        if let Option::Some(orders) = result["orders"].as_array_mut() {
+            for order in orders.iter_mut() {
+                *order = order_result(order.take());
+            }
+        }
+        Ok(Response::new(read_result(result)?))
+    }
+
+    type ListUnitsStream = Pin<Box<
+        dyn Stream<Item = Result<proto::UnitType, Status>> + Send
+    >>;
+
+    async fn list_units(
+        &self, request: Request<proto::ListUnitsRequest>
+    ) -> Result<Response<Self::ListUnitsStream>, Status> {
+        let request = request.into_inner();
+        let filter = units::UnitFilter {
+            tribe: request.tribe,
+            ability: request.ability,
+            ranged: request.ranged,
+            hidden: request.hidden,
+            min_attack: request.min_attack,
+            max_attack: request.max_attack,
+            min_defence: request.min_defence,
+            max_defence: request.max_defence
+        };
+        let unit_types: Vec<Result<proto::UnitType, Status>> =
+            units::UNIT_LIST.units.iter()
+                .filter(|unit_type| unit_type.matches(&filter))
+                .map(|unit_type| read_result(json!(unit_type)))
+                .collect();
+        Ok(Response::new(Box::pin(tokio_stream::iter(unit_types))))
+    }
+}
@@ -0,0 +1,336 @@
+//! Optimisations run in the background, so that big searches don't tie up
+//! the threads which handle requests. Jobs are queued for a fixed pool of
+//! workers, and clients poll for the result or follow the job as it runs
+//! with server-sent events, or a WebSocket (see `websocket`). A job can be
+//! cancelled, which stops its search as soon as the worker next checks.
+use std::collections::HashMap;
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{calc, metrics};
+use crate::error::ApiError;
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::time;
+use serde_json::Value;
+
+
+/// How many workers to run if the `OPTIM_WORKERS` environment variable is
+/// not set.
+const DEFAULT_WORKERS: usize = 2;
+
+
+/// How long to keep the result of a job after it finishes.
+const RESULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+
+/// How often to check a job for an event to send.
+pub const EVENT_INTERVAL: Duration = Duration::from_millis(500);
+
+
+/// How long to go without sending an event before sending a comment, or a
+/// ping over a WebSocket, so that proxies keep the connection open.
+pub const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+
+/// Get the number of workers to run, from the `OPTIM_WORKERS` environment
+/// variable.
+pub fn worker_count() -> usize {
+    match env::var("OPTIM_WORKERS") {
+        Ok(value) => match value.parse() {
+            Ok(count) if count > 0 => count,
+            _ => panic!(
+                "OPTIM_WORKERS must be a positive integer, not '{}'.", value
+            )
+        },
+        Err(_) => DEFAULT_WORKERS
+    }
+}
+
+
+/// An error for a job ID which does not match any job.
+#[derive(Debug)]
+pub struct UnknownJob(pub u64);
+
+impl UnknownJob {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": format!("No job with ID {}.", self.0),
+            "id": self.0
+        })
+    }
+}
+
+
+/// Why a job couldn't be queued.
+#[derive(Debug)]
+pub enum SubmitError {
+    /// As many jobs as allowed are already waiting for a worker. Holds the
+    /// limit.
+    QueueFull(usize),
+    /// The workers have stopped.
+    Stopped
+}
+
+
+/// An optimisation waiting for a worker.
+struct Job {
+    id: u64,
+    input: calc::OptimInput,
+    state: calc::BattleState,
+    // The index in the request of each attacker in the input.
+    attackers: Vec<usize>,
+    cancelled: Arc<AtomicBool>,
+    progress: Arc<calc::SearchProgress>
+}
+
+
+/// How far through a job is.
+enum JobStatus {
+    Queued,
+    Running,
+    Done { result: Value, finished: Instant }
+}
+
+
+/// The status of a job, the flag to set to cancel it, and how far its
+/// search has got.
+struct JobEntry {
+    status: JobStatus,
+    cancelled: Arc<AtomicBool>,
+    progress: Arc<calc::SearchProgress>,
+    // As in `Job`, to give the best order so far in terms of the request.
+    attackers: Vec<usize>
+}
+
+
+impl JobEntry {
+    /// How many orders the search has tried, and the best so far.
+    fn progress_json(&self) -> Value {
+        let best = self.progress.best().map(|mut best| {
+            calc::restore_indices(&mut best, &self.attackers);
+            best
+        });
+        json!({
+            "permutations_evaluated": self.progress.permutations(),
+            "best": best
+        })
+    }
+}
+
+
+type Statuses = Arc<Mutex<HashMap<u64, JobEntry>>>;
+
+
+/// The queue of jobs and the status of each, shared between request
+/// threads and the workers. Clones share the same jobs.
+#[derive(Clone)]
+pub struct Jobs {
+    queue: Arc<Mutex<mpsc::Sender<Job>>>,
+    statuses: Statuses,
+    next_id: Arc<AtomicU64>,
+    // The most jobs which may wait for a worker at once.
+    max_queued: usize
+}
+
+impl Jobs {
+    /// Start a pool of workers, waiting for jobs, with at most `max_queued`
+    /// jobs waiting for them.
+    pub fn new(workers: usize, max_queued: usize) -> Jobs {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let statuses: Statuses = Arc::default();
+        for _ in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            let statuses = Arc::clone(&statuses);
+            thread::spawn(move || work(&receiver, &statuses));
+        }
+        Jobs {
+            queue: Arc::new(Mutex::new(sender)),
+            statuses,
+            next_id: Arc::new(AtomicU64::new(1)),
+            max_queued
+        }
+    }
+
+    /// Queue an optimisation, returning the ID of its job, or why it
+    /// couldn't be queued. `attackers` gives the index in the request of
+    /// each attacker in the input, as from `OptimInput::canonical`. Results
+    /// which have been kept long enough are forgotten.
+    pub fn submit(
+        &self, input: calc::OptimInput, state: calc::BattleState,
+        attackers: Vec<usize>
+    ) -> Result<u64, SubmitError> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(calc::SearchProgress::default());
+        let id;
+        {
+            let mut statuses = self.statuses.lock().unwrap();
+            statuses.retain(|_, entry| match entry.status {
+                JobStatus::Done { finished, .. } => {
+                    finished.elapsed() < RESULT_LIFETIME
+                },
+                _ => true
+            });
+            let queued = statuses.values().filter(
+                |entry| matches!(entry.status, JobStatus::Queued)
+            ).count();
+            if queued >= self.max_queued {
+                return Err(SubmitError::QueueFull(self.max_queued));
+            }
+            id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            statuses.insert(id, JobEntry {
+                status: JobStatus::Queued,
+                cancelled: Arc::clone(&cancelled),
+                progress: Arc::clone(&progress),
+                attackers: attackers.clone()
+            });
+        }
+        let job = Job { id, input, state, attackers, cancelled, progress };
+        if self.queue.lock().unwrap().send(job).is_err() {
+            self.statuses.lock().unwrap().remove(&id);
+            return Err(SubmitError::Stopped);
+        }
+        Ok(id)
+    }
+
+    /// Get the status of a job, with its result if it has finished, or how
+    /// far it has got if it is running.
+    pub fn status(&self, id: u64) -> Option<Value> {
+        let statuses = self.statuses.lock().unwrap();
+        statuses.get(&id).map(|entry| match &entry.status {
+            JobStatus::Queued => json!({"id": id, "status": "queued"}),
+            JobStatus::Running => json!({
+                "id": id,
+                "status": "running",
+                "progress": entry.progress_json()
+            }),
+            JobStatus::Done { result, .. } => json!({
+                "id": id,
+                "status": "done",
+                "result": result
+            })
+        })
+    }
+
+    /// How many jobs are waiting for a worker, and how many are running.
+    pub fn counts(&self) -> (usize, usize) {
+        let statuses = self.statuses.lock().unwrap();
+        let mut queued = 0;
+        let mut running = 0;
+        for entry in statuses.values() {
+            match entry.status {
+                JobStatus::Queued => queued += 1,
+                JobStatus::Running => running += 1,
+                JobStatus::Done { .. } => ()
+            }
+        }
+        (queued, running)
+    }
+
+    /// Cancel a job, stopping its search if it is running, and forget it.
+    /// Returns `false` if there is no such job.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.statuses.lock().unwrap().remove(&id) {
+            Option::Some(entry) => {
+                entry.cancelled.store(true, Ordering::Relaxed);
+                true
+            },
+            Option::None => false
+        }
+    }
+}
+
+
+/// Run jobs from the queue until it is closed. Jobs cancelled before they
+/// start are skipped, and the results of jobs cancelled while running are
+/// thrown away. A job whose search panics is done, with an error as its
+/// result, and the worker goes on to the next.
+fn work(receiver: &Mutex<mpsc::Receiver<Job>>, statuses: &Statuses) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return
+        };
+        match statuses.lock().unwrap().get_mut(&job.id) {
+            Option::Some(entry) => entry.status = JobStatus::Running,
+            Option::None => continue
+        }
+        let Job { id, input, state, attackers, cancelled, progress } = job;
+        let started = Instant::now();
+        let search = panic::catch_unwind(AssertUnwindSafe(|| input.optimise(
+            state, Option::Some(cancelled), Option::Some(progress)
+        )));
+        let result = match search {
+            Ok(mut result) => {
+                metrics::record_search(&result, started.elapsed());
+                calc::restore_indices(&mut result, &attackers);
+                result
+            },
+            Err(_) => ApiError::new(
+                Status::InternalServerError, "search_failed",
+                "The search failed."
+            ).to_json()
+        };
+        let mut statuses = statuses.lock().unwrap();
+        if let Option::Some(entry) = statuses.get_mut(&id) {
+            entry.status = JobStatus::Done {
+                result,
+                finished: Instant::now()
+            };
+        }
+    }
+}
+
+
+/// A job's progress as server-sent events, as long as it runs, or `None` if
+/// there is no such job. A `progress` event is sent whenever its status
+/// changes, up to every `EVENT_INTERVAL`, then a `done` event with the
+/// result, or `cancelled` if the job is cancelled first.
+pub fn events(jobs: Jobs, id: u64) -> Option<EventStream![]> {
+    jobs.status(id)?;
+    let events = EventStream! {
+        // The status sent last, so that it isn't sent again.
+        let mut last = Option::None;
+        loop {
+            if let Option::Some((name, status)) = next_event(
+                &jobs, id, &mut last
+            ) {
+                yield Event::json(&status).event(name);
+                if name != "progress" {
+                    break;
+                }
+            }
+            time::sleep(EVENT_INTERVAL).await;
+        }
+    };
+    Option::Some(events.heartbeat(KEEP_ALIVE))
+}
+
+
+/// The next event to send about a job, if its status has changed from
+/// `last`, which is updated: `progress` with the status, then `done` with
+/// the result, or `cancelled` if it is cancelled first. There are no events
+/// after `done` or `cancelled`.
+pub fn next_event(
+    jobs: &Jobs, id: u64, last: &mut Option<Value>
+) -> Option<(&'static str, Value)> {
+    let status = match jobs.status(id) {
+        Option::Some(status) => status,
+        Option::None => {
+            return Option::Some((
+                "cancelled", json!({"id": id, "status": "cancelled"})
+            ));
+        }
+    };
+    if last.as_ref() == Option::Some(&status) {
+        return Option::None;
+    }
+    let name = if status["status"] == "done" { "done" } else { "progress" };
+    *last = Option::Some(status.clone());
+    Option::Some((name, status))
+}
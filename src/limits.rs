@@ -0,0 +1,240 @@
+//! Limits on how big a request can be, so that no request can tie up the
+//! server for long. Each is set by an environment variable.
+use std::env;
+use std::ops::Deref;
+
+use rocket::{Data, Request};
+use rocket::data::{self, ByteUnit, FromData};
+use rocket::http::{ContentType, Status};
+use rocket::outcome::Outcome::{Error, Forward, Success};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{logging, msgpack};
+
+
+lazy_static! {
+    /// The most attackers in a battle to calculate, or to search the orders
+    /// of heuristically. Set by the `MAX_BATTLE_ATTACKERS` environment
+    /// variable.
+    pub static ref MAX_BATTLE_ATTACKERS: usize = env_limit(
+        "MAX_BATTLE_ATTACKERS", 100
+    );
+
+    /// The most attackers to search every order of. Set by the
+    /// `MAX_OPTIM_ATTACKERS` environment variable.
+    pub static ref MAX_OPTIM_ATTACKERS: usize = env_limit(
+        "MAX_OPTIM_ATTACKERS", 9
+    );
+
+    /// The longest time budget, in milliseconds, an optimisation may be
+    /// given. Set by the `MAX_OPTIM_MS` environment variable.
+    pub static ref MAX_OPTIM_MS: usize = env_limit("MAX_OPTIM_MS", 10_000);
+
+    /// The most optimisation jobs which may wait for a worker at once. Set
+    /// by the `MAX_QUEUED_JOBS` environment variable.
+    pub static ref MAX_QUEUED_JOBS: usize = env_limit("MAX_QUEUED_JOBS", 100);
+
+    /// The most battles to calculate in one batch. Set by the
+    /// `MAX_BATCH_SIZE` environment variable.
+    pub static ref MAX_BATCH_SIZE: usize = env_limit("MAX_BATCH_SIZE", 50);
+
+    /// The most bytes a JSON request body may have. Set by the
+    /// `MAX_BODY_BYTES` environment variable.
+    pub static ref MAX_BODY_BYTES: usize = env_limit(
+        "MAX_BODY_BYTES", 1024 * 1024
+    );
+}
+
+
+/// Read a limit from an environment variable, if it is set.
+fn env_limit(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(
+            |_| panic!("{} must be a whole number, not '{}'.", name, value)
+        ),
+        Err(_) => default
+    }
+}
+
+
+/// Read the configured limits now, so mistakes in them stop the server from
+/// starting.
+pub fn initialize() {
+    lazy_static::initialize(&MAX_BATTLE_ATTACKERS);
+    lazy_static::initialize(&MAX_OPTIM_ATTACKERS);
+    lazy_static::initialize(&MAX_OPTIM_MS);
+    lazy_static::initialize(&MAX_QUEUED_JOBS);
+    lazy_static::initialize(&MAX_BATCH_SIZE);
+    lazy_static::initialize(&MAX_BODY_BYTES);
+}
+
+
+/// Every limit, for clients to check their requests against.
+pub fn to_json() -> Value {
+    json!({
+        "max_body_bytes": *MAX_BODY_BYTES,
+        "max_battle_attackers": *MAX_BATTLE_ATTACKERS,
+        "max_optim_attackers": *MAX_OPTIM_ATTACKERS,
+        "max_optim_ms": *MAX_OPTIM_MS,
+        "max_queued_jobs": *MAX_QUEUED_JOBS,
+        "max_batch_size": *MAX_BATCH_SIZE
+    })
+}
+
+
+/// An error for a request with more attackers than allowed.
+#[derive(Debug)]
+pub struct TooManyAttackers {
+    pub count: usize,
+    pub limit: usize
+}
+
+impl TooManyAttackers {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": format!(
+                "Too many attackers: {} were given, but at most {} are \
+                allowed here.",
+                self.count, self.limit
+            ),
+            "count": self.count,
+            "limit": self.limit
+        })
+    }
+}
+
+
+/// Check that there are no more attackers than a limit allows.
+pub fn check_attackers(
+    count: usize, limit: usize
+) -> Result<(), TooManyAttackers> {
+    if count > limit {
+        Err(TooManyAttackers { count, limit })
+    } else {
+        Ok(())
+    }
+}
+
+
+/// An error for a batch with more battles than allowed.
+#[derive(Debug)]
+pub struct BatchTooLarge {
+    pub count: usize,
+    pub limit: usize
+}
+
+impl BatchTooLarge {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "error": format!(
+                "Too many battles: {} were given, but at most {} are allowed \
+                in one batch.",
+                self.count, self.limit
+            ),
+            "count": self.count,
+            "limit": self.limit
+        })
+    }
+}
+
+
+/// Check that a batch has no more battles than allowed.
+pub fn check_batch(count: usize) -> Result<(), BatchTooLarge> {
+    if count > *MAX_BATCH_SIZE {
+        Err(BatchTooLarge { count, limit: *MAX_BATCH_SIZE })
+    } else {
+        Ok(())
+    }
+}
+
+
+/// A JSON request body, which is rejected with 413 Payload Too Large
+/// before it is parsed if it is bigger than `MAX_BODY_BYTES`. Bodies which
+/// aren't JSON are rejected with 400 Bad Request, and JSON of the wrong
+/// shape with 422 Unprocessable Entity. Bodies sent as MessagePack are read
+/// as the same JSON would be, and requests with any other content type are
+/// forwarded.
+pub struct JsonBody<T>(pub T);
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for JsonBody<T> {
+    type Error = String;
+
+    async fn from_data(
+        request: &'r Request<'_>, data: Data<'r>
+    ) -> data::Outcome<'r, Self> {
+        let msgpack = match request.content_type().and_then(reads_msgpack) {
+            Option::Some(msgpack) => msgpack,
+            Option::None => return Forward((data, Status::NotFound))
+        };
+        let limit = *MAX_BODY_BYTES;
+        let too_large = || Error((
+            Status::PayloadTooLarge,
+            format!("The body is bigger than {} bytes.", limit)
+        ));
+        let length = request.headers().get_one("Content-Length")
+            .and_then(|length| length.parse::<usize>().ok());
+        if length.is_some_and(|length| length > limit) {
+            return too_large();
+        }
+        let body = match data.open(ByteUnit::from(limit)).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => return too_large(),
+            Err(error) => return Error((Status::BadRequest, error.to_string()))
+        };
+        logging::count_attackers(request, &body, msgpack);
+        #[cfg(feature = "sentry")]
+        crate::reporting::note_body(request, &body, msgpack);
+        match read_body(&body, msgpack) {
+            Ok(value) => Success(JsonBody(value)),
+            Err(error) => Error(error)
+        }
+    }
+}
+
+
+/// Whether a body of a content type is read as MessagePack rather than
+/// JSON, or `None` if it isn't read at all.
+pub fn reads_msgpack(kind: &ContentType) -> Option<bool> {
+    if kind.is_json() {
+        Option::Some(false)
+    } else if msgpack::is_msgpack(kind.media_type()) {
+        Option::Some(true)
+    } else {
+        Option::None
+    }
+}
+
+
+/// Read a request body which is within the size limit, failing with 400 Bad
+/// Request if it isn't JSON, or MessagePack if `msgpack` is set, and 422
+/// Unprocessable Entity if it is of the wrong shape.
+pub fn read_body<T: DeserializeOwned>(
+    body: &[u8], msgpack: bool
+) -> Result<T, (Status, String)> {
+    let _span = tracing::info_span!(
+        "read_body", bytes = body.len(), msgpack
+    ).entered();
+    if msgpack {
+        return match msgpack::decode(body) {
+            Ok(value) => serde_json::from_value(value).map_err(|error| {
+                (Status::UnprocessableEntity, error.to_string())
+            }),
+            Err(error) => Err((Status::BadRequest, error.to_string()))
+        };
+    }
+    serde_json::from_slice(body).map_err(|error| if error.is_data() {
+        (Status::UnprocessableEntity, error.to_string())
+    } else {
+        (Status::BadRequest, error.to_string())
+    })
+}
@@ -0,0 +1,298 @@
+//! Structured logs, in place of Rocket's own, so that they can be searched
+//! by a log collector. Each request is logged once it has been answered,
+//! with an ID which is also sent back in the `X-Request-Id` header, so that
+//! a client reporting a problem can say which request it was. A client may
+//! choose the ID by sending the header itself.
+//!
+//! Logs are written as JSON, one object on each line, or as text if the
+//! `LOG_FORMAT` environment variable is `text`. The `LOG_LEVEL` variable
+//! sets the least important level to log, such as `debug`, and defaults to
+//! `info`.
+use std::env;
+use std::time::{Duration, Instant};
+
+use rocket::{Data, Orbit, Request, Response, Rocket, Route};
+use rocket::config::{Config, LogLevel};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::route::{Handler, Outcome};
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+use tracing::{Instrument, Level, Span};
+use tracing_subscriber::{Layer, fmt};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use uuid::Uuid;
+
+use crate::msgpack;
+
+
+/// The longest request ID a client may choose.
+const MAX_ID_LENGTH: usize = 64;
+
+
+/// Start writing logs, as configured by the `LOG_FORMAT` and `LOG_LEVEL`
+/// environment variables, and exporting traces if built to. Panics if
+/// either variable is invalid.
+pub fn initialize() {
+    let level = match env::var("LOG_LEVEL") {
+        Ok(level) => level.parse::<Level>().unwrap_or_else(|_| panic!(
+            "LOG_LEVEL must be trace, debug, info, warn or error, not '{}'.",
+            level
+        )),
+        Err(_) => Level::INFO
+    };
+    let logs = match env::var("LOG_FORMAT").as_deref() {
+        // Spans are for traces, so they are left out of the logs.
+        Ok("json") | Err(_) => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .boxed(),
+        Ok("text") => fmt::layer().boxed(),
+        Ok(other) => {
+            panic!("LOG_FORMAT must be json or text, not '{}'.", other)
+        }
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::from_level(level))
+        .with(logs);
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(crate::telemetry::layer());
+    // Records from the `log` crate aren't taken in, so that Rocket's own
+    // logs are left out, as the request log replaces them.
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("Logging has already been started.");
+}
+
+
+/// Rocket's configuration, read from `Rocket.toml` and the environment as
+/// `rocket::build` would, but with its own logging turned off. Panics if
+/// the configuration is invalid.
+pub fn rocket_config() -> Figment {
+    let figment = Config::figment().merge(("log_level", LogLevel::Off));
+    if let Err(error) = figment.extract::<Config>() {
+        panic!("Invalid Rocket configuration: {}", error);
+    }
+    figment
+}
+
+
+/// The ID of a request, as sent in `X-Request-Id`.
+struct RequestId(String);
+
+
+/// When a request was received.
+struct Started(Instant);
+
+
+/// The span of a request, which is entered while its route handles it, so
+/// that spans started by the route are part of it.
+struct RequestSpan(Span);
+
+
+/// How many attackers were in a request's battle, if it had one.
+struct Attackers(Option<usize>);
+
+
+/// The code of the error a request was answered with, if any.
+struct ErrorCode(&'static str);
+
+
+/// A request body, read only as far as its attackers.
+#[derive(Deserialize)]
+struct AttackerList {
+    attackers: Vec<IgnoredAny>
+}
+
+
+/// How many attackers are in a request body, if it is a battle, where it
+/// is MessagePack if `msgpack` is set and JSON otherwise.
+pub fn attackers(body: &[u8], msgpack: bool) -> Option<usize> {
+    if msgpack {
+        let body = msgpack::decode(body).ok()?;
+        return body["attackers"].as_array().map(Vec::len);
+    }
+    serde_json::from_slice::<AttackerList>(body)
+        .ok()
+        .map(|battle| battle.attackers.len())
+}
+
+
+/// Note how many attackers are in a request's body, if it is a battle.
+pub fn count_attackers(request: &Request, body: &[u8], msgpack: bool) {
+    request.local_cache(|| Attackers(attackers(body, msgpack)));
+}
+
+
+/// Note the code of the error a request was answered with.
+pub fn record_error(request: &Request, code: &'static str) {
+    request.local_cache(|| ErrorCode(code));
+}
+
+
+/// The ID to give a request: the one it was sent with, if that is safe to
+/// log and send back, or a new one.
+pub fn request_id(sent: Option<&str>) -> String {
+    sent.filter(|id| valid_id(id))
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+
+/// The span of a request, or no span if it wasn't given one.
+fn request_span(request: &Request) -> Span {
+    request.local_cache(|| RequestSpan(Span::none())).0.clone()
+}
+
+
+/// Routes which handle each request in its span.
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter().map(|mut route| {
+        route.handler = Box::new(Traced(route.handler));
+        route
+    }).collect()
+}
+
+
+/// A route's handler, run in the span of the request.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(
+        &self, request: &'r Request<'_>, data: Data<'r>
+    ) -> Outcome<'r> {
+        let span = request_span(request);
+        self.0.handle(request, data).instrument(span).await
+    }
+}
+
+
+/// The span of a request, in which it is handled and to which its route and
+/// status are added once it is answered.
+pub fn span(id: &str, method: &str, path: &str) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %id,
+        method = %method,
+        path = %path,
+        route = tracing::field::Empty,
+        status = tracing::field::Empty
+    )
+}
+
+
+/// The ID of a request, as sent back in `X-Request-Id`.
+pub fn request_id_of<'r>(request: &'r Request) -> &'r str {
+    &request.local_cache(|| RequestId(Uuid::new_v4().to_string())).0
+}
+
+
+/// Whether a client's request ID is safe to log and send back.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LENGTH && id.chars().all(
+        |c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+    )
+}
+
+
+/// A request which has been answered, to log.
+pub struct Answered<'a> {
+    pub id: &'a str,
+    pub method: &'a str,
+    pub path: &'a str,
+    /// The route which handled the request, if any did.
+    pub route: Option<&'a str>,
+    pub status: Status,
+    pub elapsed: Duration,
+    pub attackers: Option<usize>,
+    /// The code of the error the request was answered with, if any.
+    pub error: Option<&'static str>
+}
+
+
+/// Log a request once it has been answered, and add its route and status
+/// to its span.
+pub fn log_answered(span: &Span, answered: &Answered) {
+    span.record("route", answered.route);
+    span.record("status", answered.status.code);
+    let outcome = match answered.error {
+        Option::Some(code) => code,
+        Option::None if answered.status.code < 400 => "ok",
+        Option::None => answered.status.reason_lossy()
+    };
+    tracing::info!(
+        request_id = %answered.id,
+        method = %answered.method,
+        path = %answered.path,
+        route = answered.route,
+        status = answered.status.code,
+        duration_ms = answered.elapsed.as_secs_f64() * 1000.0,
+        attackers = answered.attackers,
+        outcome,
+        "Answered a request."
+    );
+}
+
+
+/// Gives each request an ID, and logs each request once it is answered.
+pub struct RequestLog;
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logs",
+            kind: Kind::Liftoff | Kind::Request | Kind::Response
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let config = rocket.config();
+        tracing::info!(
+            address = %config.address,
+            port = config.port,
+            environment = %config.profile,
+            "Listening for requests."
+        );
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| Started(Instant::now()));
+        let id = request_id(request.headers().get_one("X-Request-Id"));
+        let span = span(
+            &id, request.method().as_str(), request.uri().path().as_str()
+        );
+        request.local_cache(|| RequestSpan(span));
+        request.local_cache(|| RequestId(id));
+    }
+
+    async fn on_response<'r>(
+        &self, request: &'r Request<'_>, response: &mut Response<'r>
+    ) {
+        let elapsed = request.local_cache(|| Started(Instant::now()))
+            .0.elapsed();
+        let id = request_id_of(request);
+        response.set_raw_header("X-Request-Id", id.to_string());
+        let route = request.route()
+            .map(|route| route.uri.path().to_string());
+        let error = match request.local_cache(|| ErrorCode("")).0 {
+            "" => Option::None,
+            code => Option::Some(code)
+        };
+        log_answered(&request_span(request), &Answered {
+            id,
+            method: request.method().as_str(),
+            path: request.uri().path().as_str(),
+            route: route.as_deref(),
+            status: response.status(),
+            elapsed,
+            attackers: request.local_cache(|| Attackers(Option::None)).0,
+            error
+        });
+    }
+}
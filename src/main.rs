@@ -1,43 +1,804 @@
 //! Defines the API routes.
-#![feature(proc_macro_hygiene, decl_macro)]
-
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate rocket;
-#[macro_use] extern crate rocket_contrib;
+#[macro_use] extern crate serde_json;
+
+use rocket::{Build, Request, Rocket, Route, State};
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Status};
+use rocket::http::uri::Origin;
+use rocket::response::content::RawHtml;
+use rocket::response::status::{Accepted, NoContent};
+use rocket::response::stream::EventStream;
+use serde_json::Value;
+
+use error::ApiError;
+use polycalc_core::{abilities, calc, engagement, rules, simulate, units};
+
+mod api;
+mod auth;
+mod cache;
+mod compress;
+mod cors;
+#[cfg(feature = "discord")]
+mod discord;
+mod error;
+mod etag;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod jobs;
+mod limits;
+mod logging;
+mod metrics;
+mod msgpack;
+mod openapi;
+mod ratelimit;
+#[cfg(feature = "sentry")]
+mod reporting;
+#[cfg(feature = "axum")]
+mod router;
+mod stats;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod versions;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+
+#[get("/units?<filter..>")]
+fn get_units(
+    filter: units::UnitFilter, _limited: ratelimit::RateLimited
+) -> etag::Tagged<Value> {
+    etag::Tagged::new(&units::UNIT_LIST.hash, api::units(&filter))
+}
+
+
+#[get("/units/search?<q>&<limit>")]
+fn search_units(
+    q: String, limit: Option<usize>, _limited: ratelimit::RateLimited
+) -> Value {
+    api::search_units(&q, limit)
+}
+
+
+// Ranked below the search, so that "search" isn't taken as a unit ID.
+#[get("/units/<id>", rank = 2)]
+fn get_unit(
+    id: String, _limited: ratelimit::RateLimited
+) -> Result<etag::Tagged<Value>, ApiError> {
+    let unit = api::unit(&id)?;
+    Ok(etag::Tagged::new(&units::UNIT_LIST.hash, unit))
+}
+
+
+#[get("/units/<id>/upgrades")]
+fn get_upgrades(
+    id: String, _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::upgrades(&id)
+}
+
+
+#[get("/limits")]
+fn get_limits(_limited: ratelimit::RateLimited) -> Value {
+    limits::to_json()
+}
+
+
+#[get("/abilities")]
+fn get_abilities(_limited: ratelimit::RateLimited) -> Value {
+    api::abilities()
+}
+
+
+#[post(
+    "/battle?<on_unknown>&<default_unit>&<rules..>",
+    data="<input>"
+)]
+fn calc_battle(
+    input: limits::JsonBody<calc::ExplainInput>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: rules::RulesQuery,
+    stats: &State<stats::MatchupStats>,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    let rules = rules.to_ruleset()?;
+    api::battle(&input, on_unknown, &default_unit, &rules, stats)
+}
+
+
+/// Calculate a battle given in the query string, so that it can be linked
+/// to, as by `api::link_battle`.
+#[get("/battle?<on_unknown>&<default_unit>&<rules..>")]
+fn link_battle(
+    uri: &Origin<'_>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: rules::RulesQuery,
+    stats: &State<stats::MatchupStats>,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    let params = uri.query().into_iter().flat_map(|query| query.segments());
+    let rules = rules.to_ruleset()?;
+    api::link_battle(params, on_unknown, &default_unit, &rules, stats)
+}
+
+
+#[post(
+    "/battle/batch?<on_unknown>&<default_unit>&<rules..>",
+    data="<inputs>"
+)]
+async fn calc_battles(
+    inputs: limits::JsonBody<Vec<calc::ExplainInput>>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: rules::RulesQuery,
+    stats: &State<stats::MatchupStats>,
+    _authorised: auth::Authorised
+) -> Result<Value, ApiError> {
+    api::batch(inputs.0, on_unknown, default_unit, &rules, stats).await
+}
+
+
+#[post(
+    "/optim?<on_unknown>&<default_unit>&<rules..>",
+    data="<input>"
+)]
+async fn optimise_battle(
+    input: limits::JsonBody<calc::OptimInput>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: rules::RulesQuery,
+    stats: &State<stats::MatchupStats>,
+    cache: &State<cache::ResultCache>,
+    _authorised: auth::Authorised
+) -> Result<Value, ApiError> {
+    let stats = stats.inner().clone();
+    let cache = cache.inner().clone();
+    api::blocking(move || api::optimise(
+        &input, on_unknown, &default_unit, &rules, &stats, &cache
+    )).await
+}
+
+
+#[post(
+    "/optim/jobs?<on_unknown>&<default_unit>&<rules..>",
+    data="<input>"
+)]
+fn start_optim_job(
+    input: limits::JsonBody<calc::OptimInput>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: rules::RulesQuery,
+    stats: &State<stats::MatchupStats>,
+    jobs: &State<jobs::Jobs>,
+    _authorised: auth::Authorised
+) -> Result<Accepted<Value>, ApiError> {
+    api::start_job(
+        &input, on_unknown, &default_unit, &rules, stats, jobs
+    ).map(Accepted)
+}
+
+
+#[get("/optim/jobs/<id>")]
+fn get_optim_job(
+    id: u64, jobs: &State<jobs::Jobs>, _authorised: auth::Authorised
+) -> Result<Value, ApiError> {
+    api::job_status(id, jobs)
+}
+
+
+#[get("/optim/jobs/<id>/events")]
+fn optim_job_events(
+    id: u64, jobs: &State<jobs::Jobs>, _authorised: auth::Authorised
+) -> Result<EventStream![], ApiError> {
+    jobs::events(jobs.inner().clone(), id)
+        .ok_or_else(|| ApiError::from(jobs::UnknownJob(id)))
+}
+
+
+#[delete("/optim/jobs/<id>")]
+fn cancel_optim_job(
+    id: u64, jobs: &State<jobs::Jobs>, _authorised: auth::Authorised
+) -> Result<NoContent, ApiError> {
+    api::cancel_job(id, jobs).map(|()| NoContent)
+}
+
+
+#[post(
+    "/assign?<on_unknown>&<default_unit>&<rules..>",
+    data="<units>"
+)]
+fn assign_battle(
+    units: limits::JsonBody<calc::BattleInput>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::assign(&units, on_unknown, &default_unit, &rules)
+}
+
+
+#[post(
+    "/simulate?<on_unknown>&<default_unit>&<rules..>",
+    data="<input>"
+)]
+fn simulate_battle(
+    input: limits::JsonBody<simulate::SimulateInput>,
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>,
+    rules: rules::RulesQuery,
+    stats: &State<stats::MatchupStats>,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::simulate(&input, on_unknown, &default_unit, &rules, stats)
+}
+
+
+#[post("/damage?<rules..>", data="<units>")]
+fn preview_damage(
+    units: limits::JsonBody<calc::DamageInput>,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::damage(&units, &rules)
+}
+
+
+#[post("/kill-threshold?<rules..>", data="<input>")]
+fn kill_threshold(
+    input: limits::JsonBody<calc::KillThresholdInput>,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::kill_threshold(&input, &rules)
+}
+
+
+#[post("/survive?<rules..>", data="<input>")]
+fn survival_thresholds(
+    input: limits::JsonBody<calc::BattleInput>,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::survive(&input, &rules)
+}
+
+
+#[post("/initiative?<rules..>", data="<units>")]
+fn calc_initiative(
+    units: limits::JsonBody<calc::InitiativeInput>,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::initiative(&units, &rules)
+}
+
+
+#[post("/engagement?<rules..>", data="<units>")]
+fn calc_engagement(
+    units: limits::JsonBody<engagement::EngagementInput>,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::engagement(&units, &rules)
+}
+
+
+#[get(
+    "/formula?<attack_force>&<defence_force>&<attack>&<defence>&<rules..>"
+)]
+fn damage_formula(
+    attack_force: f32, defence_force: f32, attack: f32, defence: f32,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::formula(attack_force, defence_force, attack, defence, &rules)
+}
+
+
+#[get("/matchup?<units>&<defence>&<rules..>")]
+fn matchup_table(
+    units: Option<String>,
+    defence: Option<calc::DefenceBonus>,
+    rules: rules::RulesQuery,
+    _limited: ratelimit::RateLimited
+) -> Result<Value, ApiError> {
+    api::matchup(units.as_deref(), defence, &rules)
+}
+
 
-use rocket_contrib::json::{Json, JsonValue};
+#[get("/stats/popular?<limit>")]
+fn popular_matchups(
+    limit: Option<usize>, stats: &State<stats::MatchupStats>,
+    _limited: ratelimit::RateLimited
+) -> Value {
+    api::popular(limit, stats)
+}
+
+
+#[delete("/admin/stats/popular")]
+fn reset_matchups(
+    stats: &State<stats::MatchupStats>, _authorised: auth::Authorised
+) -> NoContent {
+    stats.reset();
+    NoContent
+}
+
+
+#[catch(400)]
+fn bad_request() -> ApiError {
+    api::malformed_request()
+}
+
+
+#[catch(401)]
+fn unauthorised() -> ApiError {
+    auth::key_error(Status::Unauthorized)
+}
+
+
+#[catch(403)]
+fn forbidden() -> ApiError {
+    auth::key_error(Status::Forbidden)
+}
+
+
+#[catch(404)]
+fn not_found(request: &Request) -> ApiError {
+    api::not_found(request.method().as_str(), request.uri().path().as_str())
+}
+
+
+#[catch(422)]
+fn unprocessable_entity() -> ApiError {
+    api::invalid_body()
+}
+
+
+#[catch(429)]
+fn too_many_requests(request: &Request) -> ratelimit::LimitExceeded {
+    ratelimit::LimitExceeded::for_request(request)
+}
+
+
+#[catch(413)]
+fn payload_too_large() -> ApiError {
+    api::body_too_large()
+}
+
+
+#[catch(500)]
+fn internal_error() -> ApiError {
+    api::internal_error()
+}
+
+
+// For load balancers and orchestrators, so served outside the versions.
+#[get("/healthz")]
+fn health_check() -> Result<Value, ApiError> {
+    api::health()
+}
+
+
+// Served outside the versions, since it is for whoever runs the server
+// rather than for clients.
+#[get("/metrics")]
+fn get_metrics(jobs: &State<jobs::Jobs>) -> (ContentType, String) {
+    (ContentType::Plain, api::metrics(jobs))
+}
+
+
+// Which build of the server is running, and with which unit data and
+// rules, for whoever runs it to check.
+#[get("/version")]
+fn build_info() -> Value {
+    api::build_info(VERSIONS)
+}
 
-mod calc;
-mod units;
+
+// Served outside the versions, since it describes them.
+#[get("/openapi.json")]
+fn openapi_document() -> Value {
+    openapi::document()
+}
+
+
+// An explorer for the API, using the OpenAPI document. Swagger UI itself is
+// loaded from a CDN rather than served from here.
+#[get("/docs")]
+fn api_docs() -> RawHtml<&'static str> {
+    RawHtml(include_str!("docs.html"))
+}
 
 
-#[get("/units")]
-fn get_units() -> JsonValue {
-    json!(units::UNIT_LIST.units)
+/// Routes which handle each request in its span, and with its hub if
+/// errors are reported.
+fn handled(routes: Vec<Route>) -> Vec<Route> {
+    #[cfg(feature = "sentry")]
+    let routes = reporting::bound(routes);
+    logging::traced(routes)
 }
 
 
-#[post("/battle", format="json", data="<units>")]
-fn calc_battle(units: Json<calc::BattleInput>) -> JsonValue {
-    let mut state = units.to_state();
-    calc::battle_many(&mut state);
-    state.to_json()
+/// The routes of the first version of the API.
+fn v1_routes() -> Vec<Route> {
+    #[cfg_attr(not(feature = "websocket"), allow(unused_mut))]
+    let mut routes = routes![
+        get_units, search_units, get_unit, get_upgrades, get_abilities,
+        get_limits, calc_battle, link_battle, calc_battles, optimise_battle,
+        start_optim_job, get_optim_job, optim_job_events, cancel_optim_job,
+        assign_battle, simulate_battle, preview_damage, kill_threshold,
+        survival_thresholds, calc_initiative, calc_engagement, damage_formula,
+        matchup_table, popular_matchups, reset_matchups
+    ];
+    #[cfg(feature = "websocket")]
+    routes.extend(routes![websocket::follow_job]);
+    handled(routes)
+}
+
+
+/// Every version of the API, oldest first. The last is also served without
+/// a version prefix.
+const VERSIONS: &[versions::Version] = &[
+    versions::Version { name: "v1", routes: v1_routes }
+];
+
+
+#[rocket::main]
+async fn main() {
+    logging::initialize();
+    // Kept until the server stops, so that reports are sent.
+    #[cfg(feature = "sentry")]
+    let _reporting = reporting::initialize();
+    lazy_static::initialize(&api::STARTED);
+    let config = logging::rocket_config();
+    if let Ok(path) = config.extract_inner::<String>("units_file") {
+        // This only fails if the unit data has been loaded, which it hasn't.
+        let _ = units::set_units_file(path);
+    }
+    // Read the configured constants and the unit data now, so mistakes in
+    // them stop the server from starting.
+    lazy_static::initialize(&rules::LATEST);
+    lazy_static::initialize(&units::UNIT_LIST);
+    limits::initialize();
+    metrics::initialize();
+    let stats = stats::MatchupStats::default();
+    let cache = cache::ResultCache::from_env();
+    let keys = auth::ApiKeys::from_env();
+    #[cfg(feature = "grpc")]
+    if let Option::Some(server) = grpc::GrpcServer::from_env() {
+        server.spawn(grpc::CalculatorService::new(
+            stats.clone(), cache.clone(), keys.clone()
+        ));
+    }
+    let jobs = jobs::Jobs::new(
+        jobs::worker_count(), *limits::MAX_QUEUED_JOBS
+    );
+    let limiter = ratelimit::RateLimiter::from_env();
+    #[cfg(feature = "axum")]
+    if let Option::Some(server) = router::AxumServer::from_env() {
+        server.spawn(router::Shared {
+            stats: stats.clone(),
+            cache: cache.clone(),
+            jobs: jobs.clone(),
+            keys: keys.clone(),
+            limiter: limiter.clone()
+        });
+    }
+    // An error starting the server is reported as it is dropped.
+    let _ = server(config, stats, jobs, cache, limiter, keys)
+        .launch()
+        .await;
 }
 
 
-#[post("/optim", format="json", data="<units>")]
-fn optimise_battle(units: Json<calc::BattleInput>) -> JsonValue {
-    let state = units.to_state();
-    let (best_order, best_state) = calc::optimise_battle(state);
-    json!({
-        "order": best_order,
-        "state": best_state.to_json()
-    })
+/// Build the server, with the state its routes share.
+fn server(
+    config: Figment,
+    stats: stats::MatchupStats,
+    jobs: jobs::Jobs,
+    cache: cache::ResultCache,
+    limiter: ratelimit::RateLimiter,
+    keys: auth::ApiKeys
+) -> Rocket<Build> {
+    let rocket = rocket::custom(config)
+        .manage(stats)
+        .manage(jobs)
+        .manage(cache)
+        .manage(limiter)
+        .manage(keys);
+    let rocket = match cors::Cors::from_env() {
+        Option::Some(cors) => rocket.attach(cors),
+        Option::None => rocket
+    };
+    #[cfg(feature = "discord")]
+    let rocket = match discord::DiscordApp::from_env() {
+        Option::Some(app) => {
+            rocket.manage(app).mount("/", handled(routes![
+                discord::interaction
+            ]))
+        },
+        Option::None => rocket
+    };
+    #[cfg(feature = "graphql")]
+    let rocket = rocket
+        .manage(graphql::schema())
+        .mount("/", handled(routes![graphql::graphql]));
+    // Compression is attached after the other fairings, so it sees
+    // responses as they will be sent, and requests are logged once they
+    // are complete.
+    let rocket = versions::mount(rocket, VERSIONS)
+        .attach(metrics::Metrics)
+        .attach(msgpack::MsgPack)
+        .attach(compress::Compress)
+        .attach(logging::RequestLog);
+    // Attached after the logs, so that the request ID has been chosen.
+    #[cfg(feature = "sentry")]
+    let rocket = rocket.attach(reporting::Reporting);
+    rocket
+        .mount("/", handled(routes![
+            health_check, get_metrics, build_info, openapi_document,
+            api_docs
+        ]))
+        .register("/", catchers![
+            bad_request, unauthorised, forbidden, not_found,
+            payload_too_large, unprocessable_entity, too_many_requests,
+            internal_error
+        ])
 }
 
 
-fn main() {
-    rocket::ignite()
-        .mount("/", routes![get_units, calc_battle, optimise_battle])
-        .launch();
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    fn client() -> Client {
+        keyed_client(&[])
+    }
+
+    fn keyed_client(keys: &[&str]) -> Client {
+        client_with(keys, jobs::Jobs::new(1, *limits::MAX_QUEUED_JOBS))
+    }
+
+    fn client_with(keys: &[&str], jobs: jobs::Jobs) -> Client {
+        let keys: HashSet<String> = keys.iter().map(|key| key.to_string())
+            .collect();
+        let rocket = server(
+            logging::rocket_config(),
+            stats::MatchupStats::default(),
+            jobs,
+            cache::ResultCache::from_env(),
+            ratelimit::RateLimiter::from_env(),
+            auth::ApiKeys::new(keys)
+        );
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn post(client: &Client, uri: &str, body: Value) -> (Status, Value) {
+        let response = client.post(uri)
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch();
+        (response.status(), response.into_json().unwrap())
+    }
+
+    fn unknown_attacker() -> Value {
+        json!({
+            "attackers": [{"unit": "nope"}, {"unit": "warrior"}],
+            "defender": {"unit": "warrior"}
+        })
+    }
+
+    #[test]
+    fn unknown_units_are_rejected_by_default() {
+        let client = client();
+        let (status, body) = post(&client, "/v1/battle", unknown_attacker());
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["data"]["error"]["code"], "unknown_unit");
+        assert_eq!(body["data"]["error"]["unit"], "nope");
+    }
+
+    #[test]
+    fn unknown_units_can_be_skipped() {
+        let client = client();
+        let (status, body) = post(
+            &client, "/v1/battle?on_unknown=skip", unknown_attacker()
+        );
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["data"]["attackers"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unknown_units_can_be_replaced() {
+        let client = client();
+        let (status, body) = post(
+            &client, "/v1/battle?on_unknown=default&default_unit=rider",
+            unknown_attacker()
+        );
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["data"]["attackers"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn skipped_attackers_keep_their_indices() {
+        let client = client();
+        let (status, body) = post(&client, "/v1/optim?on_unknown=skip", json!({
+            "attackers": [
+                {"unit": "nope"}, {"unit": "catapult"}, {"unit": "warrior"}
+            ],
+            "defender": {"unit": "defender"}
+        }));
+        assert_eq!(status, Status::Ok);
+        let order = body["data"]["order"].as_array().unwrap();
+        let mut order: Vec<u64> = order.iter()
+            .map(|idx| idx.as_u64().unwrap())
+            .collect();
+        order.sort();
+        assert_eq!(order, vec![1, 2]);
+    }
+
+    #[test]
+    fn resetting_stats_needs_a_key() {
+        let client = keyed_client(&["secret"]);
+        let response = client.delete("/v1/admin/stats/popular").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client.delete("/v1/admin/stats/popular")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+    }
+
+    #[test]
+    fn stats_count_resolved_units() {
+        let client = client();
+        post(&client, "/v1/battle?on_unknown=skip", unknown_attacker());
+        post(
+            &client, "/v1/battle?on_unknown=default&default_unit=rider",
+            unknown_attacker()
+        );
+        let response = client.get("/v1/stats/popular").dispatch();
+        let body: Value = response.into_json().unwrap();
+        assert_eq!(body["data"], json!([
+            {"attacker": "warrior", "defender": "warrior", "count": 2},
+            {"attacker": "rider", "defender": "warrior", "count": 1}
+        ]));
+    }
+
+    fn boosted() -> Value {
+        json!({"unit": "warrior", "flags": {"boosted": true}})
+    }
+
+    fn assert_misplaced(client: &Client, uri: &str, body: Value) {
+        let (status, body) = post(client, uri, body);
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["data"]["error"]["code"], "invalid_flags");
+    }
+
+    #[test]
+    fn damage_rejects_misplaced_flags() {
+        let client = client();
+        assert_misplaced(&client, "/v1/damage", json!({
+            "attacker": {"unit": "warrior"}, "defender": boosted()
+        }));
+    }
+
+    #[test]
+    fn initiative_rejects_misplaced_flags() {
+        let client = client();
+        assert_misplaced(&client, "/v1/initiative", json!({
+            "unit": boosted(), "enemy": {"unit": "warrior"}
+        }));
+    }
+
+    #[test]
+    fn engagement_rejects_misplaced_flags() {
+        let client = client();
+        assert_misplaced(&client, "/v1/engagement", json!({
+            "attackers": [{"unit": "warrior"}], "defenders": [boosted()]
+        }));
+    }
+
+    #[test]
+    fn assign_rejects_unknown_adjacent_units() {
+        let client = client();
+        let (status, body) = post(&client, "/v1/assign", json!({
+            "attackers": [{"unit": "warrior"}],
+            "defender": {"unit": "warrior", "adjacent": [3]},
+            "adjacent": [{"unit": "warrior"}]
+        }));
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["data"]["error"]["code"], "invalid_adjacent");
+    }
+
+    #[test]
+    fn negative_healing_is_rejected() {
+        let client = client();
+        let (status, body) = post(&client, "/v1/simulate", json!({
+            "attackers": [{"unit": "warrior"}],
+            "defender": {"unit": "warrior"},
+            "heal": -2
+        }));
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["data"]["error"]["code"], "invalid_heal");
+    }
+
+    /// A battle of warriors with many different healths, so that the
+    /// search can't skip most orders as interchangeable.
+    fn wounded_warriors(count: usize) -> Value {
+        let attackers: Vec<Value> = (0..count).map(
+            |idx| json!({"unit": "warrior", "health": 10 - idx % 10})
+        ).collect();
+        json!({"attackers": attackers, "defender": {"unit": "giant"}})
+    }
+
+    fn assert_order(body: &Value, count: usize) {
+        let mut order: Vec<u64> = body["data"]["order"].as_array().unwrap()
+            .iter().map(|idx| idx.as_u64().unwrap()).collect();
+        order.sort_unstable();
+        assert_eq!(order, (0..count as u64).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn a_time_budget_cuts_the_search_short() {
+        let client = client();
+        let mut battle = wounded_warriors(12);
+        battle["max_ms"] = json!(0);
+        let (status, body) = post(&client, "/v1/optim", battle);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["data"]["complete"], false);
+        assert_order(&body, 12);
+    }
+
+    #[test]
+    fn a_full_search_is_complete() {
+        let client = client();
+        let (status, body) = post(
+            &client, "/v1/optim", wounded_warriors(4)
+        );
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["data"]["complete"], true);
+        assert_order(&body, 4);
+    }
+
+    #[test]
+    fn a_long_time_budget_is_rejected() {
+        let client = client();
+        let mut battle = wounded_warriors(2);
+        battle["max_ms"] = json!(*limits::MAX_OPTIM_MS + 1);
+        let (status, body) = post(&client, "/v1/optim", battle);
+        assert_eq!(status, Status::UnprocessableEntity);
+        assert_eq!(body["data"]["error"]["code"], "invalid_max_ms");
+    }
+
+    #[test]
+    fn jobs_are_refused_when_the_queue_is_full() {
+        let client = client_with(&[], jobs::Jobs::new(1, 0));
+        let (status, body) = post(
+            &client, "/v1/optim/jobs", wounded_warriors(2)
+        );
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(body["data"]["error"]["code"], "queue_full");
+    }
+
+    #[test]
+    fn a_long_time_budget_does_not_allow_more_attackers() {
+        let client = client();
+        let mut battle = wounded_warriors(*limits::MAX_OPTIM_ATTACKERS + 3);
+        battle["max_ms"] = json!(u64::MAX);
+        for uri in ["/v1/optim", "/v1/optim/jobs"].iter() {
+            let (status, body) = post(&client, uri, battle.clone());
+            assert_eq!(status, Status::UnprocessableEntity);
+            assert_eq!(body["data"]["error"]["code"], "invalid_max_ms");
+        }
+    }
 }
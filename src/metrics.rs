@@ -0,0 +1,178 @@
+//! Metrics for whoever runs the server, served at `/metrics` in the
+//! Prometheus text format: how many requests each route gets and how long
+//! they take, how fast optimisations search, how often the result cache is
+//! used, and how many background jobs there are.
+//!
+//! The optimiser's speed is given as two counters, so that the rate over
+//! any window is `rate(polycalc_optim_permutations_total[5m]) /
+//! rate(polycalc_optim_search_seconds_total[5m])`.
+use std::time::{Duration, Instant};
+
+use prometheus::{
+    Counter, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder
+};
+use rocket::{Data, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use serde_json::Value;
+
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+
+    static ref REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "polycalc_http_requests_total",
+            "Requests handled, by method, route and status."
+        ),
+        &["method", "route", "status"]
+    ));
+
+    static ref REQUEST_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "polycalc_http_request_duration_seconds",
+            "How long requests took to handle, by method and route."
+        ),
+        &["method", "route"]
+    ));
+
+    static ref PERMUTATIONS: IntCounter = register(IntCounter::new(
+        "polycalc_optim_permutations_total",
+        "Orders of attack tried by optimisations."
+    ));
+
+    static ref SEARCH_SECONDS: Counter = register(Counter::new(
+        "polycalc_optim_search_seconds_total",
+        "Time spent searching for the best order of attack."
+    ));
+
+    static ref SEARCHES: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new(
+            "polycalc_optim_duration_seconds",
+            "How long each optimisation searched for."
+        )
+    ));
+
+    static ref CACHE_LOOKUPS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "polycalc_optim_cache_lookups_total",
+            "Optimisation results looked up in the cache, by whether they \
+            were found."
+        ),
+        &["result"]
+    ));
+
+    static ref JOBS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "polycalc_optim_jobs",
+            "Background optimisation jobs which haven't finished, by status."
+        ),
+        &["status"]
+    ));
+}
+
+
+/// Add a metric to the registry. Metrics are fixed, so a bad one is a bug.
+fn register<M>(metric: prometheus::Result<M>) -> M
+where
+    M: prometheus::core::Collector + Clone + 'static
+{
+    let metric = metric.expect("Invalid metric.");
+    REGISTRY.register(Box::new(metric.clone())).expect("Invalid metric.");
+    metric
+}
+
+
+/// Register every metric now, so that each is listed from the start rather
+/// than from when it is first used.
+pub fn initialize() {
+    lazy_static::initialize(&REQUESTS);
+    lazy_static::initialize(&REQUEST_SECONDS);
+    lazy_static::initialize(&PERMUTATIONS);
+    lazy_static::initialize(&SEARCH_SECONDS);
+    lazy_static::initialize(&SEARCHES);
+    lazy_static::initialize(&CACHE_LOOKUPS);
+    lazy_static::initialize(&JOBS);
+}
+
+
+/// Count the work done by an optimisation, from the `search` field of its
+/// result, and how long it took. The result's own time is only to the
+/// millisecond, which is too coarse for most searches.
+pub fn record_search(result: &Value, elapsed: Duration) {
+    if let Option::Some(permutations) = result["search"]
+        ["permutations_evaluated"].as_u64()
+    {
+        PERMUTATIONS.inc_by(permutations);
+    }
+    SEARCH_SECONDS.inc_by(elapsed.as_secs_f64());
+    SEARCHES.observe(elapsed.as_secs_f64());
+}
+
+
+/// Count a lookup in the result cache.
+pub fn record_cache_lookup(found: bool) {
+    let result = if found { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[result]).inc();
+}
+
+
+/// Count and time a request to a route, such as `/v1/units/<id>`, or
+/// `unmatched` if none handled it.
+pub fn record_request(
+    method: &str, route: &str, status: u16, elapsed: Duration
+) {
+    let status = status.to_string();
+    REQUESTS.with_label_values(&[method, route, &status]).inc();
+    REQUEST_SECONDS.with_label_values(&[method, route])
+        .observe(elapsed.as_secs_f64());
+}
+
+
+/// Every metric in the Prometheus text format, with the numbers of queued
+/// and running jobs as given.
+pub fn render(queued: usize, running: usize) -> String {
+    JOBS.with_label_values(&["queued"]).set(queued as i64);
+    JOBS.with_label_values(&["running"]).set(running as i64);
+    let mut text = vec![];
+    // Writing to a vector can't fail, and the metrics are all valid.
+    let _ = TextEncoder::new().encode(&REGISTRY.gather(), &mut text);
+    String::from_utf8(text).unwrap_or_default()
+}
+
+
+/// When a request was received.
+struct Started(Instant);
+
+
+/// Counts and times requests, by the route which handled them, so that
+/// requests for different units count as one route.
+pub struct Metrics;
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| Started(Instant::now()));
+    }
+
+    async fn on_response<'r>(
+        &self, request: &'r Request<'_>, response: &mut Response<'r>
+    ) {
+        let elapsed = request.local_cache(|| Started(Instant::now()))
+            .0.elapsed();
+        let route = request.route()
+            .map(|route| route.uri.path().to_string())
+            .unwrap_or_else(|| String::from("unmatched"));
+        record_request(
+            request.method().as_str(), &route, response.status().code,
+            elapsed
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! MessagePack bodies, for clients such as bots which make many requests
+//! and would rather send and parse something smaller and faster than JSON.
+//! Request bodies sent as `application/msgpack` are read as if they were
+//! the same JSON, and JSON responses are sent as MessagePack to clients
+//! which prefer it in their `Accept` header.
+//!
+//! Only the parts of MessagePack which JSON can describe are supported, so
+//! binary and extension types are rejected.
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Cursor;
+
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, MediaType};
+use serde_json::{Map, Number, Value};
+
+
+/// How deeply arrays and maps may be nested in a request body, so that a
+/// small body can't use up the stack.
+const MAX_DEPTH: usize = 64;
+
+
+/// Whether a media type is MessagePack. There is no registered type for
+/// it, so both names in use are accepted.
+pub fn is_msgpack(media_type: &MediaType) -> bool {
+    media_type.top() == "application"
+        && (media_type.sub() == "msgpack" || media_type.sub() == "x-msgpack")
+}
+
+
+/// A reason a MessagePack body couldn't be read.
+#[derive(Debug)]
+pub struct InvalidMsgPack(&'static str);
+
+impl fmt::Display for InvalidMsgPack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid MessagePack: {}.", self.0)
+    }
+}
+
+
+/// Write a JSON value as MessagePack.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = vec![];
+    write_value(&mut out, value);
+    out
+}
+
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => write_number(out, number),
+        Value::String(string) => {
+            write_length(out, string.len(), 0xa0, 32, [0xd9, 0xda, 0xdb]);
+            out.extend_from_slice(string.as_bytes());
+        },
+        Value::Array(items) => {
+            write_length(out, items.len(), 0x90, 16, [0, 0xdc, 0xdd]);
+            for item in items.iter() {
+                write_value(out, item);
+            }
+        },
+        Value::Object(fields) => {
+            write_length(out, fields.len(), 0x80, 16, [0, 0xde, 0xdf]);
+            for (key, field) in fields.iter() {
+                write_value(out, &Value::String(key.clone()));
+                write_value(out, field);
+            }
+        }
+    }
+}
+
+
+/// Write the smallest form of a number.
+fn write_number(out: &mut Vec<u8>, number: &Number) {
+    if let Option::Some(int) = number.as_u64() {
+        if int < 0x80 {
+            out.push(int as u8);
+        } else if let Ok(int) = u8::try_from(int) {
+            out.push(0xcc);
+            out.push(int);
+        } else if let Ok(int) = u16::try_from(int) {
+            out.push(0xcd);
+            out.extend_from_slice(&int.to_be_bytes());
+        } else if let Ok(int) = u32::try_from(int) {
+            out.push(0xce);
+            out.extend_from_slice(&int.to_be_bytes());
+        } else {
+            out.push(0xcf);
+            out.extend_from_slice(&int.to_be_bytes());
+        }
+    } else if let Option::Some(int) = number.as_i64() {
+        if int >= -32 {
+            out.push(int as u8);
+        } else if let Ok(int) = i8::try_from(int) {
+            out.push(0xd0);
+            out.extend_from_slice(&int.to_be_bytes());
+        } else if let Ok(int) = i16::try_from(int) {
+            out.push(0xd1);
+            out.extend_from_slice(&int.to_be_bytes());
+        } else if let Ok(int) = i32::try_from(int) {
+            out.push(0xd2);
+            out.extend_from_slice(&int.to_be_bytes());
+        } else {
+            out.push(0xd3);
+            out.extend_from_slice(&int.to_be_bytes());
+        }
+    } else {
+        out.push(0xcb);
+        let float = number.as_f64().unwrap_or_default();
+        out.extend_from_slice(&float.to_be_bytes());
+    }
+}
+
+
+/// Write the length of a string, array or map, in its short form if it is
+/// below `short_limit`, or else with the first marker it fits in of those
+/// for 8, 16 and 32 bit lengths. A marker of 0 means there is no such
+/// form.
+fn write_length(
+    out: &mut Vec<u8>, length: usize, short: u8, short_limit: usize,
+    markers: [u8; 3]
+) {
+    if length < short_limit {
+        out.push(short | length as u8);
+    } else if markers[0] != 0 && length <= 0xff {
+        out.push(markers[0]);
+        out.push(length as u8);
+    } else if length <= 0xffff {
+        out.push(markers[1]);
+        out.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        out.push(markers[2]);
+        out.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+}
+
+
+/// Read MessagePack as a JSON value.
+pub fn decode(data: &[u8]) -> Result<Value, InvalidMsgPack> {
+    let mut reader = Reader { data, position: 0 };
+    let value = reader.value(0)?;
+    if reader.position != data.len() {
+        return Err(InvalidMsgPack("there is more after the value"));
+    }
+    Ok(value)
+}
+
+
+/// Reads values from MessagePack, one after another.
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], InvalidMsgPack> {
+        let end = self.position.checked_add(count)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(InvalidMsgPack("it ends too soon"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, InvalidMsgPack> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Read a big-endian unsigned integer of some number of bytes.
+    fn uint(&mut self, size: usize) -> Result<u64, InvalidMsgPack> {
+        Ok(self.bytes(size)?.iter()
+            .fold(0, |int, byte| (int << 8) | u64::from(*byte)))
+    }
+
+    /// Read a big-endian signed integer of some number of bytes.
+    fn int(&mut self, size: usize) -> Result<i64, InvalidMsgPack> {
+        let unsigned = self.uint(size)?;
+        let shift = 64 - 8 * size as u32;
+        Ok(((unsigned << shift) as i64) >> shift)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, InvalidMsgPack> {
+        if depth > MAX_DEPTH {
+            return Err(InvalidMsgPack("it is nested too deeply"));
+        }
+        let marker = self.byte()?;
+        let value = match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.map(usize::from(marker & 0x0f), depth)?,
+            0x90..=0x9f => self.array(usize::from(marker & 0x0f), depth)?,
+            0xa0..=0xbf => self.string(usize::from(marker & 0x1f))?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => {
+                let bits = self.uint(4)? as u32;
+                float(f64::from(f32::from_bits(bits)))?
+            },
+            0xcb => float(f64::from_bits(self.uint(8)?))?,
+            0xcc => Value::from(self.uint(1)?),
+            0xcd => Value::from(self.uint(2)?),
+            0xce => Value::from(self.uint(4)?),
+            0xcf => Value::from(self.uint(8)?),
+            0xd0 => Value::from(self.int(1)?),
+            0xd1 => Value::from(self.int(2)?),
+            0xd2 => Value::from(self.int(4)?),
+            0xd3 => Value::from(self.int(8)?),
+            0xd9 => {
+                let length = self.uint(1)? as usize;
+                self.string(length)?
+            },
+            0xda => {
+                let length = self.uint(2)? as usize;
+                self.string(length)?
+            },
+            0xdb => {
+                let length = self.uint(4)? as usize;
+                self.string(length)?
+            },
+            0xdc => {
+                let length = self.uint(2)? as usize;
+                self.array(length, depth)?
+            },
+            0xdd => {
+                let length = self.uint(4)? as usize;
+                self.array(length, depth)?
+            },
+            0xde => {
+                let length = self.uint(2)? as usize;
+                self.map(length, depth)?
+            },
+            0xdf => {
+                let length = self.uint(4)? as usize;
+                self.map(length, depth)?
+            },
+            0xe0..=0xff => Value::from(marker as i8),
+            _ => return Err(InvalidMsgPack(
+                "binary and extension types are not supported"
+            ))
+        };
+        Ok(value)
+    }
+
+    fn string(&mut self, length: usize) -> Result<Value, InvalidMsgPack> {
+        let bytes = self.bytes(length)?;
+        std::str::from_utf8(bytes)
+            .map(Value::from)
+            .map_err(|_| InvalidMsgPack("a string is not UTF-8"))
+    }
+
+    fn array(
+        &mut self, length: usize, depth: usize
+    ) -> Result<Value, InvalidMsgPack> {
+        // The length isn't trusted to allocate for, since every item takes
+        // at least a byte.
+        let mut items = Vec::with_capacity(length.min(self.remaining()));
+        for _ in 0..length {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn map(
+        &mut self, length: usize, depth: usize
+    ) -> Result<Value, InvalidMsgPack> {
+        let mut fields = Map::new();
+        for _ in 0..length {
+            let key = match self.value(depth + 1)? {
+                Value::String(key) => key,
+                _ => return Err(InvalidMsgPack("a map key is not a string"))
+            };
+            let field = self.value(depth + 1)?;
+            fields.insert(key, field);
+        }
+        Ok(Value::Object(fields))
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+}
+
+
+/// A float as a JSON value, which can't be infinite or NaN.
+fn float(float: f64) -> Result<Value, InvalidMsgPack> {
+    Number::from_f64(float)
+        .map(Value::Number)
+        .ok_or(InvalidMsgPack("a float is not finite"))
+}
+
+
+/// Sends JSON responses as MessagePack to clients which prefer it.
+pub struct MsgPack;
+
+#[rocket::async_trait]
+impl Fairing for MsgPack {
+    fn info(&self) -> Info {
+        Info {
+            name: "MessagePack responses",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(
+        &self, request: &'r Request<'_>, response: &mut Response<'r>
+    ) {
+        if response.content_type() != Option::Some(ContentType::JSON) {
+            return;
+        }
+        response.adjoin_raw_header("Vary", "Accept");
+        let wanted = request.accept().is_some_and(|accept| {
+            is_msgpack(accept.preferred().media_type())
+        });
+        if !wanted {
+            return;
+        }
+        let body = match response.body_mut().to_string().await {
+            Ok(body) => body,
+            Err(_) => return
+        };
+        match serde_json::from_str::<Value>(&body) {
+            Ok(value) => {
+                let encoded = encode(&value);
+                response.set_sized_body(encoded.len(), Cursor::new(encoded));
+                response.set_raw_header("Content-Type", "application/msgpack");
+            },
+            Err(_) => response.set_sized_body(body.len(), Cursor::new(body))
+        }
+    }
+}
@@ -0,0 +1,774 @@
+//! An OpenAPI document describing the API, so that clients can be generated
+//! for it. It is written out by hand, so it must be kept in line with the
+//! routes and the types they read.
+use serde_json::Value;
+
+
+/// A reference to a schema in the document's components.
+fn schema_ref(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{}", name)})
+}
+
+
+/// A parameter in the query string.
+fn query(name: &str, schema: Value, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "schema": schema,
+        "description": description
+    })
+}
+
+
+/// A parameter in the path, such as a unit ID.
+fn path(name: &str, schema: Value, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": schema,
+        "description": description
+    })
+}
+
+
+/// The parameters which choose the rules of a battle.
+fn rules_params() -> Vec<Value> {
+    let number = json!({"type": "number"});
+    vec![
+        query(
+            "ruleset",
+            json!({
+                "type": "string",
+                "enum": ["legacy", "moonrise", "latest"]
+            }),
+            "The version of the game's combat rules. Defaults to the latest."
+        ),
+        query(
+            "total_force", number.clone(),
+            "Replace the damage formula's constant."
+        ),
+        query(
+            "splash_damage", number.clone(),
+            "Replace the proportion of damage dealt by splash."
+        ),
+        query(
+            "explode_damage", number.clone(),
+            "Replace the proportion of damage dealt by explosions."
+        ),
+        query(
+            "defence_bonus", number.clone(),
+            "Replace the defence multiplier for a defence bonus."
+        ),
+        query(
+            "wall_bonus", number,
+            "Replace the defence multiplier for city walls."
+        )
+    ]
+}
+
+
+/// The parameters for a battle with unit IDs which may be unknown, and the
+/// rules to fight it with.
+fn battle_params() -> Vec<Value> {
+    let mut params = vec![
+        query(
+            "on_unknown",
+            json!({"type": "string", "enum": ["error", "skip", "default"]}),
+            "What to do with unit IDs which don't match a unit type."
+        ),
+        query(
+            "default_unit", json!({"type": "string"}),
+            "The unit type to use in place of unknown units, with \
+            `on_unknown=default`."
+        )
+    ];
+    params.extend(rules_params());
+    params
+}
+
+
+/// A request body matching a schema, as JSON or MessagePack.
+fn body(schema: Value) -> Value {
+    json!({
+        "required": true,
+        "content": {
+            "application/json": {"schema": schema.clone()},
+            "application/msgpack": {"schema": schema}
+        }
+    })
+}
+
+
+/// The responses of a route, given the schema of a successful response and
+/// the statuses it may fail with.
+fn responses(ok: Value, errors: &[u16]) -> Value {
+    let mut responses = json!({
+        "200": {
+            "description": "Success.",
+            "content": {
+                "application/json": {"schema": ok.clone()},
+                "application/msgpack": {"schema": ok}
+            }
+        }
+    });
+    for status in errors.iter() {
+        let description = match status {
+            400 => "The request makes no sense, such as using an unknown \
+                unit.",
+            404 => "There is nothing with the given ID.",
+            503 => "The server is too busy; try again later.",
+            _ => "The request asks for more than the server allows."
+        };
+        responses[status.to_string()] = json!({
+            "description": description,
+            "content": {"application/json": {"schema": schema_ref("Error")}}
+        });
+    }
+    responses["429"] = json!({
+        "description": "The client has made too many requests recently, \
+            and should wait for as many seconds as the Retry-After header \
+            says.",
+        "content": {"application/json": {"schema": schema_ref("Error")}}
+    });
+    responses
+}
+
+
+/// The responses of a route which takes a body, which may also fail if the
+/// body is too big.
+fn body_responses(ok: Value, errors: &[u16]) -> Value {
+    let mut responses = responses(ok, errors);
+    responses["413"] = json!({
+        "description": "The body is bigger than the server allows.",
+        "content": {"application/json": {"schema": schema_ref("Error")}}
+    });
+    responses
+}
+
+
+/// The responses of a route with an entity tag, which may also say that
+/// the client's copy is still current.
+fn tagged_responses(ok: Value, errors: &[u16]) -> Value {
+    let mut responses = responses(ok, errors);
+    responses["304"] = json!({
+        "description": "The response has not changed since the client got \
+            the copy named in the If-None-Match header."
+    });
+    responses
+}
+
+
+/// A route taking a battle as its body.
+fn battle_route(
+    summary: &str, schema: &str, ok: Value, errors: &[u16]
+) -> Value {
+    json!({
+        "post": {
+            "summary": summary,
+            "parameters": battle_params(),
+            "requestBody": body(schema_ref(schema)),
+            "responses": body_responses(ok, errors)
+        }
+    })
+}
+
+
+/// A route taking units as its body, with the rules to use.
+fn rules_route(
+    summary: &str, schema: &str, ok: Value, errors: &[u16]
+) -> Value {
+    json!({
+        "post": {
+            "summary": summary,
+            "parameters": rules_params(),
+            "requestBody": body(schema_ref(schema)),
+            "responses": body_responses(ok, errors)
+        }
+    })
+}
+
+
+/// The types read and written by the routes.
+fn schemas() -> Value {
+    let index = json!({"type": "integer", "minimum": 0});
+    let flag = json!({"type": "boolean"});
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "description": "Any details of the error are given \
+                        alongside the message, such as `unit` for an \
+                        unknown unit.",
+                    "required": ["code", "status", "message"],
+                    "properties": {
+                        "code": {
+                            "type": "string",
+                            "description": "What kind of error it is, such \
+                                as `unknown_unit` or `too_many_attackers`."
+                        },
+                        "status": {"type": "integer"},
+                        "message": {"type": "string"}
+                    }
+                }
+            }
+        },
+        "UnitFlags": {
+            "description": "Flags for a unit, either as named booleans or \
+                as a bit field, from the lowest bit: poisoned, bonus, \
+                walled, boosted, veteran, forced_retaliation, \
+                no_retaliation, frozen.",
+            "oneOf": [
+                {"type": "integer", "minimum": 0, "maximum": 255},
+                {
+                    "type": "object",
+                    "properties": {
+                        "poisoned": flag,
+                        "bonus": flag,
+                        "walled": flag,
+                        "boosted": flag,
+                        "veteran": flag,
+                        "forced_retaliation": flag,
+                        "no_retaliation": flag,
+                        "frozen": flag
+                    },
+                    "additionalProperties": false
+                }
+            ]
+        },
+        "UnitInput": {
+            "type": "object",
+            "required": ["unit"],
+            "properties": {
+                "unit": {"type": "string", "description": "A unit ID."},
+                "health": {
+                    "type": "number",
+                    "description": "Defaults to the unit's maximum."
+                },
+                "flags": schema_ref("UnitFlags"),
+                "follow_up": {
+                    "type": "array",
+                    "items": schema_ref("UnitInput"),
+                    "description": "For an attacker with persist: the units \
+                        to attack after each kill."
+                },
+                "target": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "description": "For an attacker: the index of the \
+                        defender it attacks."
+                },
+                "heal": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "description": "For an attacker with heal: the index \
+                        of the attacker to heal instead of attacking."
+                },
+                "explode": {
+                    "type": "boolean",
+                    "description": "For an attacker with explode: whether to \
+                        explode instead of attacking."
+                },
+                "boost": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "description": "For an attacker with boost: the index \
+                        of the attacker to boost instead of attacking."
+                },
+                "city": {
+                    "type": "string",
+                    "enum": ["outside", "unwalled", "walled"]
+                },
+                "adjacent": {
+                    "type": "array",
+                    "items": {"type": "integer", "minimum": 0},
+                    "description": "For a defender: the indices of the \
+                        adjacent units next to it. The first defender is \
+                        next to all of them by default, and the others to \
+                        none."
+                },
+                "carrying": {
+                    "type": "string",
+                    "description": "For a vessel: the ID of the unit it \
+                        carries."
+                },
+                "kills": {"type": "integer", "minimum": 0},
+                "terrain": {
+                    "type": "string",
+                    "enum": ["field", "forest", "mountain", "water", "city"]
+                },
+                "techs": {"type": "array", "items": {"type": "string"}}
+            }
+        },
+        "BattleInput": {
+            "type": "object",
+            "required": ["attackers", "defender"],
+            "properties": {
+                "attackers": {
+                    "type": "array", "items": schema_ref("UnitInput")
+                },
+                "defender": schema_ref("UnitInput"),
+                "defenders": {
+                    "type": "array",
+                    "items": schema_ref("UnitInput"),
+                    "description": "Further defenders attackers can target, \
+                        from index 1."
+                },
+                "adjacent": {
+                    "type": "array",
+                    "items": schema_ref("UnitInput"),
+                    "description": "Units next to the defenders, hit by \
+                        splash damage."
+                }
+            }
+        },
+        "ExplainInput": {
+            "allOf": [
+                schema_ref("BattleInput"),
+                {
+                    "type": "object",
+                    "properties": {"explain": {"type": "boolean"}}
+                }
+            ]
+        },
+        "Constraint": {
+            "type": "object",
+            "description": "One of `first`, `last` or `survives` with an \
+                attacker index, or `before` with two.",
+            "minProperties": 1,
+            "maxProperties": 1,
+            "properties": {
+                "first": index,
+                "last": index,
+                "survives": index,
+                "before": {
+                    "type": "array",
+                    "items": index,
+                    "minItems": 2,
+                    "maxItems": 2
+                }
+            }
+        },
+        "OptimInput": {
+            "allOf": [
+                schema_ref("BattleInput"),
+                {
+                    "type": "object",
+                    "properties": {
+                        "perspective": {
+                            "type": "string",
+                            "enum": ["attacker", "defender"]
+                        },
+                        "objective": {
+                            "description": "A name, or `preserve_unit` \
+                                with an attacker index.",
+                            "oneOf": [
+                                {
+                                    "type": "string",
+                                    "enum": [
+                                        "overall", "kill_defender",
+                                        "min_attacker_deaths", "max_damage"
+                                    ]
+                                },
+                                {
+                                    "type": "object",
+                                    "required": ["preserve_unit"],
+                                    "properties": {"preserve_unit": index}
+                                }
+                            ]
+                        },
+                        "minimise": {"type": "boolean"},
+                        "top": {"type": "integer", "minimum": 1},
+                        "constraints": {
+                            "type": "array",
+                            "items": schema_ref("Constraint")
+                        },
+                        "mode": {
+                            "type": "string",
+                            "enum": ["exhaustive", "heuristic"]
+                        },
+                        "max_ms": {"type": "integer", "minimum": 0}
+                    }
+                }
+            ]
+        },
+        "SimulateInput": {
+            "allOf": [
+                schema_ref("BattleInput"),
+                {
+                    "type": "object",
+                    "properties": {
+                        "turns": {"type": "integer", "minimum": 0},
+                        "friendly_territory": {"type": "boolean"},
+                        "heal": {"type": "number", "minimum": 0}
+                    }
+                }
+            ]
+        },
+        "DamageInput": {
+            "type": "object",
+            "required": ["attacker", "defender"],
+            "properties": {
+                "attacker": schema_ref("UnitInput"),
+                "defender": schema_ref("UnitInput")
+            }
+        },
+        "KillThresholdInput": {
+            "type": "object",
+            "required": ["attacker", "defender"],
+            "properties": {
+                "attacker": schema_ref("UnitInput"),
+                "defender": schema_ref("UnitInput"),
+                "max": {"type": "integer", "minimum": 0}
+            }
+        },
+        "InitiativeInput": {
+            "type": "object",
+            "required": ["unit", "enemy"],
+            "properties": {
+                "unit": schema_ref("UnitInput"),
+                "enemy": schema_ref("UnitInput")
+            }
+        },
+        "EngagementInput": {
+            "type": "object",
+            "required": ["attackers", "defenders"],
+            "properties": {
+                "attackers": {
+                    "type": "array", "items": schema_ref("UnitInput")
+                },
+                "defenders": {
+                    "type": "array", "items": schema_ref("UnitInput")
+                }
+            }
+        },
+        "DamagePreview": {
+            "type": "object",
+            "properties": {
+                "damage": {"type": "integer"},
+                "retaliation": {"type": "integer"},
+                "attacker_health": {"type": "integer"},
+                "defender_health": {"type": "integer"},
+                "defender_killed": {"type": "boolean"},
+                "attacker_killed": {"type": "boolean"}
+            }
+        },
+        "UnitType": {"type": "object"},
+        "BattleResult": {
+            "type": "object",
+            "description": "The state of the battle once every attacker \
+                has acted."
+        }
+    })
+}
+
+
+/// Every route, by path.
+fn paths() -> Value {
+    let object = json!({"type": "object"});
+    let units = json!({"type": "array", "items": schema_ref("UnitType")});
+    let unit_id = path(
+        "id", json!({"type": "string"}), "A unit ID, or for a single unit, \
+            an alias."
+    );
+    let job_id = path(
+        "id", json!({"type": "integer", "minimum": 0}), "A job ID."
+    );
+    let number = json!({"type": "number"});
+    let mut formula_params = vec![
+        query("attack_force", number.clone(), "The attacker's force."),
+        query("defence_force", number.clone(), "The defender's force."),
+        query("attack", number.clone(), "The attacker's attack."),
+        query("defence", number, "The defender's defence.")
+    ];
+    for param in formula_params.iter_mut() {
+        param["required"] = json!(true);
+    }
+    formula_params.extend(rules_params());
+    let mut matchup_params = vec![
+        query(
+            "units", json!({"type": "string"}),
+            "Unit IDs or aliases, separated by commas. Defaults to all."
+        ),
+        query(
+            "defence",
+            json!({"type": "string", "enum": ["none", "bonus", "wall"]}),
+            "The defence bonus every defender has."
+        )
+    ];
+    matchup_params.extend(rules_params());
+    let mut link_params = vec![
+        query(
+            "a", json!({"type": "array", "items": {"type": "string"}}),
+            "An attacker, as `unit[:health][:flag]...`."
+        ),
+        query(
+            "d", json!({"type": "string"}),
+            "The defender, as `unit[:health][:flag]...`."
+        )
+    ];
+    link_params[1]["required"] = json!(true);
+    link_params.extend(battle_params());
+    json!({
+        "/units": {
+            "get": {
+                "summary": "List the unit types which meet every condition \
+                    given.",
+                "parameters": [
+                    query("tribe", json!({"type": "string"}), "A tribe."),
+                    query("ability", json!({"type": "string"}), "An ability."),
+                    query("ranged", json!({"type": "boolean"}), ""),
+                    query("hidden", json!({"type": "boolean"}), ""),
+                    query("min_attack", json!({"type": "number"}), ""),
+                    query("max_attack", json!({"type": "number"}), ""),
+                    query("min_defence", json!({"type": "number"}), ""),
+                    query("max_defence", json!({"type": "number"}), "")
+                ],
+                "responses": tagged_responses(units.clone(), &[])
+            }
+        },
+        "/units/search": {
+            "get": {
+                "summary": "Search unit types by ID, name and alias, best \
+                    match first.",
+                "parameters": [
+                    {
+                        "name": "q",
+                        "in": "query",
+                        "required": true,
+                        "schema": {"type": "string"}
+                    },
+                    query(
+                        "limit", json!({"type": "integer", "minimum": 0}),
+                        "The most matches to list. Defaults to 10."
+                    )
+                ],
+                "responses": responses(
+                    json!({"type": "array", "items": object}), &[]
+                )
+            }
+        },
+        "/units/{id}": {
+            "get": {
+                "summary": "Get a unit type.",
+                "parameters": [unit_id],
+                "responses": tagged_responses(schema_ref("UnitType"), &[404])
+            }
+        },
+        "/units/{id}/upgrades": {
+            "get": {
+                "summary": "List the unit types a unit type can become.",
+                "parameters": [unit_id],
+                "responses": responses(object.clone(), &[404])
+            }
+        },
+        "/limits": {
+            "get": {
+                "summary": "Get the limits on how big requests may be.",
+                "responses": responses(json!({
+                    "type": "object",
+                    "properties": {
+                        "max_body_bytes": {"type": "integer"},
+                        "max_battle_attackers": {"type": "integer"},
+                        "max_optim_attackers": {"type": "integer"},
+                        "max_optim_ms": {"type": "integer"},
+                        "max_queued_jobs": {"type": "integer"},
+                        "max_batch_size": {"type": "integer"}
+                    }
+                }), &[])
+            }
+        },
+        "/abilities": {
+            "get": {
+                "summary": "List every ability, with how the calculator \
+                    interprets it.",
+                "responses": responses(
+                    json!({"type": "array", "items": object}), &[]
+                )
+            }
+        },
+        "/battle": {
+            "get": {
+                "summary": "Calculate a battle given in the query string.",
+                "parameters": link_params,
+                "responses": responses(schema_ref("BattleResult"), &[
+                    400, 422
+                ])
+            },
+            "post": battle_route(
+                "Calculate a battle.", "ExplainInput",
+                schema_ref("BattleResult"), &[400, 422]
+            )["post"]
+        },
+        "/battle/batch": battle_route(
+            "Calculate several battles. Each result is the battle or the \
+                error it was rejected with.",
+            "ExplainInput", json!({"type": "array", "items": object}),
+            &[400, 422]
+        ),
+        "/optim": battle_route(
+            "Find the best order of attack.", "OptimInput", object.clone(),
+            &[400, 422]
+        ),
+        "/optim/jobs": battle_route(
+            "Queue a search for the best order of attack.", "OptimInput",
+            object.clone(), &[400, 422, 503]
+        ),
+        "/optim/jobs/{id}": {
+            "get": {
+                "summary": "Get the status of a job, with how far its \
+                    search has got while it runs, and its result once it is \
+                    done.",
+                "parameters": [job_id],
+                "responses": responses(object.clone(), &[404])
+            },
+            "delete": {
+                "summary": "Cancel a job.",
+                "parameters": [job_id],
+                "responses": {
+                    "204": {"description": "The job was cancelled."},
+                    "404": responses(object.clone(), &[404])["404"]
+                }
+            }
+        },
+        "/optim/jobs/{id}/events": {
+            "get": {
+                "summary": "Follow a job as server-sent events: progress \
+                    events with its status while it runs, then a done event \
+                    with its result, or a cancelled event.",
+                "parameters": [job_id],
+                "responses": {
+                    "200": {
+                        "description": "The job's events, as they happen.",
+                        "content": {
+                            "text/event-stream": {
+                                "schema": {"type": "string"}
+                            }
+                        }
+                    },
+                    "404": responses(object.clone(), &[404])["404"]
+                }
+            }
+        },
+        "/assign": battle_route(
+            "Assign attackers to defenders.", "BattleInput", object.clone(),
+            &[400, 422]
+        ),
+        "/simulate": battle_route(
+            "Simulate a battle over several turns.", "SimulateInput",
+            object.clone(), &[400, 422]
+        ),
+        "/damage": rules_route(
+            "Calculate a single attack.", "DamageInput",
+            schema_ref("DamagePreview"), &[400]
+        ),
+        "/kill-threshold": rules_route(
+            "Find how many copies of a unit kill a defender.",
+            "KillThresholdInput", object.clone(), &[400, 422]
+        ),
+        "/survive": rules_route(
+            "Find the least health a defender needs to survive, with each \
+                defence bonus.",
+            "BattleInput", json!({"type": "array", "items": object}),
+            &[400, 422]
+        ),
+        "/initiative": rules_route(
+            "Find which of two units should attack first.",
+            "InitiativeInput", object.clone(), &[400]
+        ),
+        "/engagement": rules_route(
+            "Find the best attacks for several attackers on several \
+                defenders.",
+            "EngagementInput", object.clone(), &[400, 422]
+        ),
+        "/formula": {
+            "get": {
+                "summary": "Break down the damage formula.",
+                "parameters": formula_params,
+                "responses": responses(object.clone(), &[400])
+            }
+        },
+        "/matchup": {
+            "get": {
+                "summary": "Tabulate single attacks between unit types.",
+                "parameters": matchup_params,
+                "responses": responses(object.clone(), &[400])
+            }
+        },
+        "/stats/popular": {
+            "get": {
+                "summary": "List the most requested matchups.",
+                "parameters": [query(
+                    "limit", json!({"type": "integer", "minimum": 0}), ""
+                )],
+                "responses": responses(
+                    json!({"type": "array", "items": object}), &[]
+                )
+            }
+        },
+        "/admin/stats/popular": {
+            "delete": {
+                "summary": "Forget the requested matchups.",
+                "responses": {"204": {"description": "Forgotten."}}
+            }
+        }
+    })
+}
+
+
+/// The routes which need an API key, if the server has any, by path and
+/// method.
+const KEYED_ROUTES: &[(&str, &str)] = &[
+    ("/battle/batch", "post"),
+    ("/optim", "post"),
+    ("/optim/jobs", "post"),
+    ("/optim/jobs/{id}", "get"),
+    ("/optim/jobs/{id}/events", "get"),
+    ("/optim/jobs/{id}", "delete"),
+    ("/admin/stats/popular", "delete")
+];
+
+
+/// The OpenAPI document for the API. Routes are given without a version
+/// prefix; under `/v1`, each response is wrapped as
+/// `{"version": "v1", "data": ...}`.
+pub fn document() -> Value {
+    let mut paths = paths();
+    for (path, method) in KEYED_ROUTES.iter() {
+        let route = &mut paths[*path][*method];
+        route["security"] = json!([{"api_key": []}]);
+        route["responses"]["401"] = json!({
+            "description": "No API key was given.",
+            "content": {"application/json": {"schema": schema_ref("Error")}}
+        });
+        route["responses"]["403"] = json!({
+            "description": "The API key is not known.",
+            "content": {"application/json": {"schema": schema_ref("Error")}}
+        });
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Polycalc",
+            "description": "Calculates battles in The Battle of Polytopia.",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "api_key": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-API-Key",
+                    "description": "Only needed if the server is configured \
+                        with keys."
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,272 @@
+//! Limits on how often each client may make requests, since some routes,
+//! such as `/optim`, are expensive enough to be abused. Each client IP has
+//! a token bucket for each route: a request takes a token, and tokens come
+//! back at a steady rate up to the limit.
+//!
+//! The limit for every route is set by the `RATE_LIMIT` environment
+//! variable, as `requests/seconds`, or `off`. Limits for particular routes
+//! are set by `RATE_LIMITS`, as `path=requests/seconds` separated by
+//! commas, where the path is as in the route, without a version prefix,
+//! such as `/optim=10/60,/units/<id>=100/60`.
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rocket::Request;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+
+use crate::error::ApiError;
+
+
+/// The limit for every route, if the `RATE_LIMIT` environment variable is
+/// not set.
+const DEFAULT_LIMIT: Limit = Limit { requests: 300, per: 60 };
+
+
+/// The limits for routes which are slower than most, used unless set by the
+/// `RATE_LIMITS` environment variable.
+const DEFAULT_ROUTE_LIMITS: &[(&str, Limit)] = &[
+    ("/optim", Limit { requests: 30, per: 60 }),
+    ("/optim/jobs", Limit { requests: 30, per: 60 }),
+    ("/battle/batch", Limit { requests: 30, per: 60 })
+];
+
+
+/// How many buckets to keep before forgetting those which are full.
+const MAX_BUCKETS: usize = 10_000;
+
+
+/// How many requests a client may make in a number of seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub requests: u32,
+    pub per: u64
+}
+
+impl Limit {
+    /// Read a limit written as `requests/seconds`.
+    fn parse(limit: &str) -> Option<Limit> {
+        let (requests, per) = limit.trim().split_once('/')?;
+        let limit = Limit {
+            requests: requests.trim().parse().ok()?,
+            per: per.trim().parse().ok()?
+        };
+        if limit.requests == 0 || limit.per == 0 {
+            return Option::None;
+        }
+        Option::Some(limit)
+    }
+
+    /// How many tokens come back each second.
+    fn rate(self) -> f64 {
+        f64::from(self.requests) / self.per as f64
+    }
+}
+
+
+/// The tokens a client has left for a route.
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    /// Add the tokens which have come back since the bucket was updated.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let capacity = f64::from(self.limit.requests);
+        self.tokens = (self.tokens + elapsed * self.limit.rate())
+            .min(capacity);
+        self.updated = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= f64::from(self.limit.requests)
+    }
+}
+
+
+/// The limits, and the buckets of every client which has made a request
+/// recently. Clones share the same buckets.
+#[derive(Clone)]
+pub struct RateLimiter {
+    default: Option<Limit>,
+    routes: HashMap<String, Limit>,
+    buckets: Arc<Mutex<HashMap<(IpAddr, String), Bucket>>>
+}
+
+impl RateLimiter {
+    pub fn new(
+        default: Option<Limit>, routes: HashMap<String, Limit>
+    ) -> RateLimiter {
+        RateLimiter {
+            default,
+            routes,
+            buckets: Arc::default()
+        }
+    }
+
+    /// Read the limits from the `RATE_LIMIT` and `RATE_LIMITS` environment
+    /// variables. Panics if they can't be read, so mistakes in them stop
+    /// the server from starting.
+    pub fn from_env() -> RateLimiter {
+        let default = match env::var("RATE_LIMIT") {
+            Ok(limit) if limit.trim() == "off" => Option::None,
+            Ok(limit) => Option::Some(Limit::parse(&limit).unwrap_or_else(
+                || panic!(
+                    "RATE_LIMIT must be 'requests/seconds' or 'off', not \
+                    '{}'.", limit
+                )
+            )),
+            Err(_) => Option::Some(DEFAULT_LIMIT)
+        };
+        let mut routes: HashMap<String, Limit> = DEFAULT_ROUTE_LIMITS.iter()
+            .map(|(path, limit)| (String::from(*path), *limit))
+            .collect();
+        if let Ok(limits) = env::var("RATE_LIMITS") {
+            let entries = limits.split(',').filter(|entry| !entry.is_empty());
+            for entry in entries {
+                let parsed = entry.split_once('=').and_then(|(path, limit)| {
+                    Option::Some((path, Limit::parse(limit)?))
+                });
+                match parsed {
+                    Option::Some((path, limit)) => {
+                        routes.insert(String::from(path.trim()), limit);
+                    },
+                    Option::None => panic!(
+                        "RATE_LIMITS entries must be \
+                        'path=requests/seconds', not '{}'.", entry
+                    )
+                }
+            }
+        }
+        RateLimiter::new(default, routes)
+    }
+
+    /// The limit for a route, by its path.
+    fn limit_for(&self, path: &str) -> Option<Limit> {
+        self.routes.get(path).copied().or(self.default)
+    }
+
+    /// Take a token from a client's bucket for a route. If there are none
+    /// left, returns how long until there will be one.
+    pub fn take(&self, client: IpAddr, path: &str) -> Result<(), Duration> {
+        let limit = match self.limit_for(path) {
+            Option::Some(limit) => limit,
+            Option::None => return Ok(())
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+        }
+        let bucket = buckets.entry((client, String::from(path)))
+            .or_insert(Bucket {
+                limit,
+                tokens: f64::from(limit.requests),
+                updated: now
+            });
+        bucket.refill(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / limit.rate();
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+
+/// How long a client which has made too many requests must wait, kept for
+/// the catcher.
+struct RetryAfter(u64);
+
+
+/// A request guard which takes a token from the client's bucket for the
+/// route, failing with 429 Too Many Requests if there are none left.
+/// Requests are not limited if no `RateLimiter` is managed, or if the
+/// client's IP is unknown.
+pub struct RateLimited;
+
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimited {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r Request<'_>
+    ) -> request::Outcome<Self, ()> {
+        let limiter = match request.rocket().state::<RateLimiter>() {
+            Option::Some(limiter) => limiter,
+            Option::None => return Outcome::Success(RateLimited)
+        };
+        let (client, route) = match (request.client_ip(), request.route()) {
+            (Option::Some(client), Option::Some(route)) => (client, route),
+            _ => return Outcome::Success(RateLimited)
+        };
+        let path = route.uri.path();
+        let path = match route.uri.base() {
+            "/" => path,
+            base => path.strip_prefix(base).unwrap_or(path)
+        };
+        match limiter.take(client, path) {
+            Ok(()) => Outcome::Success(RateLimited),
+            Err(wait) => {
+                request.local_cache(|| RetryAfter(retry_after(wait)));
+                Outcome::Error((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+
+/// How many whole seconds a client should wait, from how long until it has
+/// a token again.
+pub fn retry_after(wait: Duration) -> u64 {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    secs.max(1)
+}
+
+
+/// The error for a client which has made too many requests, and must wait
+/// this many seconds. It should be sent with a `Retry-After` header.
+pub fn limit_error(retry_after: u64) -> ApiError {
+    ApiError::new(
+        Status::TooManyRequests, "rate_limited",
+        format!("Too many requests. Try again in {} seconds.", retry_after)
+    ).with("retry_after", json!(retry_after))
+}
+
+
+/// The response to a client which has made too many requests, telling it
+/// how long to wait.
+pub struct LimitExceeded {
+    retry_after: u64
+}
+
+impl LimitExceeded {
+    pub fn for_request(request: &Request) -> LimitExceeded {
+        LimitExceeded {
+            retry_after: request.local_cache(|| RetryAfter(1)).0
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for LimitExceeded {
+    fn respond_to(self, request: &'r Request) -> response::Result<'static> {
+        let error = limit_error(self.retry_after);
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("Retry-After", self.retry_after.to_string())
+            .ok()
+    }
+}
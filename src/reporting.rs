@@ -0,0 +1,276 @@
+//! Reports of panics and server errors to Sentry, so that whoever runs the
+//! server hears of them without searching the logs. It is built with the
+//! `sentry` feature, and reports are sent if the `SENTRY_DSN` environment
+//! variable is set. `SENTRY_ENVIRONMENT` and `SENTRY_RELEASE` are read too.
+//!
+//! Each request is handled with a Sentry hub of its own, so that a report
+//! has the request being handled, with its body, and the problem can be
+//! reproduced. Only harmless headers are sent, and anything in the body
+//! which looks like a secret is redacted.
+use std::borrow::Cow;
+use std::env;
+use std::sync::Arc;
+
+use rocket::{Data, Request, Response, Route};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::route::{Handler, Outcome};
+use sentry::protocol::{Event, Map, Url};
+use sentry::{ClientInitGuard, ClientOptions, Hub, Level, SentryFutureExt};
+use serde_json::Value;
+
+use crate::{logging, msgpack};
+
+
+/// The headers which are sent with a report. Others, such as API keys and
+/// cookies, may be secret.
+const SAFE_HEADERS: &[&str] = &[
+    "Accept", "Accept-Encoding", "Content-Length", "Content-Type", "Host",
+    "User-Agent"
+];
+
+
+/// Parts of the names of body fields whose values are redacted.
+const SECRET_NAMES: &[&str] = &[
+    "auth", "cookie", "key", "password", "secret", "token"
+];
+
+
+/// The most characters of a body sent with a report.
+const MAX_BODY_CHARS: usize = 8192;
+
+
+/// The hub of a request, if reports are being sent.
+struct RequestHub(Option<Arc<Hub>>);
+
+
+/// Start reporting panics and server errors, if `SENTRY_DSN` is set. The
+/// guard must be kept until the server stops, so that reports are sent.
+/// Panics if the DSN is invalid.
+pub fn initialize() -> Option<ClientInitGuard> {
+    let dsn = env::var("SENTRY_DSN").ok()?;
+    let dsn = dsn.parse().unwrap_or_else(
+        |error| panic!("SENTRY_DSN is not a valid DSN: {}", error)
+    );
+    let release = env::var("SENTRY_RELEASE")
+        .map(Cow::Owned)
+        .unwrap_or(Cow::Borrowed(env!("CARGO_PKG_VERSION")));
+    Option::Some(sentry::init(ClientOptions {
+        dsn: Option::Some(dsn),
+        release: Option::Some(release),
+        environment: env::var("SENTRY_ENVIRONMENT").ok().map(Cow::Owned),
+        send_default_pii: false,
+        ..Default::default()
+    }))
+}
+
+
+/// Whether reports are being sent, so that work is only done for them if
+/// so.
+fn enabled() -> bool {
+    Hub::current().client().is_some()
+}
+
+
+/// A hub for a request, with the request and its ID in its scope, or none
+/// if reports aren't being sent.
+pub fn hub(
+    described: sentry::protocol::Request, request_id: &str
+) -> Option<Arc<Hub>> {
+    if !enabled() {
+        return Option::None;
+    }
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("request_id", request_id);
+        scope.add_event_processor(move |mut event: Event<'static>| {
+            event.request = Option::Some(described.clone());
+            Option::Some(event)
+        });
+    });
+    Option::Some(hub)
+}
+
+
+/// The hub a request is handled with, or none if reports aren't being
+/// sent.
+fn request_hub(request: &Request) -> Option<Arc<Hub>> {
+    request.local_cache(|| RequestHub(Option::None)).0.clone()
+}
+
+
+/// Note a request's body, to be reported with any panic or error while
+/// handling it. It is MessagePack if `msgpack` is set, and JSON otherwise.
+pub fn note_body(request: &Request, body: &[u8], msgpack: bool) {
+    if let Option::Some(hub) = request_hub(request) {
+        note_body_on(&hub, body, msgpack);
+    }
+}
+
+
+/// Note the body of the request whose hub is current, for the axum router,
+/// which has no request to keep the hub with. This must only be called
+/// while handling a request with its own hub.
+#[cfg(feature = "axum")]
+pub fn note_current_body(body: &[u8], msgpack: bool) {
+    if enabled() {
+        note_body_on(&Hub::current(), body, msgpack);
+    }
+}
+
+
+/// Note a request's body on its hub.
+fn note_body_on(hub: &Hub, body: &[u8], msgpack: bool) {
+    let body = if msgpack {
+        msgpack::decode(body).ok()
+    } else {
+        serde_json::from_slice::<Value>(body).ok()
+    };
+    let mut body = match body {
+        Option::Some(body) => body,
+        Option::None => return
+    };
+    redact(&mut body);
+    let mut data = body.to_string();
+    if let Option::Some((end, _)) = data.char_indices().nth(MAX_BODY_CHARS) {
+        data.truncate(end);
+        data.push('…');
+    }
+    hub.configure_scope(|scope| {
+        scope.add_event_processor(move |mut event| {
+            if let Option::Some(request) = event.request.as_mut() {
+                request.data = Option::Some(data.clone());
+            }
+            Option::Some(event)
+        });
+    });
+}
+
+
+/// Replace the values of fields which may be secret.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                let name = name.to_lowercase();
+                if SECRET_NAMES.iter().any(|secret| name.contains(secret)) {
+                    *value = Value::from("[redacted]");
+                } else {
+                    redact(value);
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => ()
+    }
+}
+
+
+/// A request as it is reported, without its body, given a way to get each
+/// of its headers.
+pub fn describe<'a>(
+    method: &str,
+    path: &str,
+    query: Option<&str>,
+    header: impl Fn(&str) -> Option<&'a str>
+) -> sentry::protocol::Request {
+    let mut headers = Map::new();
+    for &name in SAFE_HEADERS {
+        if let Option::Some(value) = header(name) {
+            headers.insert(String::from(name), String::from(value));
+        }
+    }
+    let url = header("Host").and_then(|host| {
+        Url::parse(&format!("http://{}{}", host, path)).ok()
+    });
+    sentry::protocol::Request {
+        url,
+        method: Option::Some(method.to_string()),
+        query_string: query.map(String::from),
+        headers,
+        ..Default::default()
+    }
+}
+
+
+/// Report a response to a request, if it is a server error.
+pub fn report_response(hub: &Hub, status: Status, route: &str) {
+    if status.code < 500 {
+        return;
+    }
+    hub.configure_scope(|scope| {
+        scope.set_tag("route", route);
+        scope.set_tag("status", status.code);
+    });
+    hub.capture_message(
+        &format!("{} {} on {}", status.code, status.reason_lossy(), route),
+        Level::Error
+    );
+}
+
+
+/// Routes which handle each request with its hub, so that a panic while
+/// handling it is reported with it.
+pub fn bound(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter().map(|mut route| {
+        route.handler = Box::new(Bound(route.handler));
+        route
+    }).collect()
+}
+
+
+/// A route's handler, run with the hub of the request.
+#[derive(Clone)]
+struct Bound(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Bound {
+    async fn handle<'r>(
+        &self, request: &'r Request<'_>, data: Data<'r>
+    ) -> Outcome<'r> {
+        match request_hub(request) {
+            Option::Some(hub) => {
+                self.0.handle(request, data).bind_hub(hub).await
+            },
+            Option::None => self.0.handle(request, data).await
+        }
+    }
+}
+
+
+/// Gives each request a hub, with the request in its scope, and reports
+/// responses with server errors.
+pub struct Reporting;
+
+#[rocket::async_trait]
+impl Fairing for Reporting {
+    fn info(&self) -> Info {
+        Info {
+            name: "Error reports",
+            kind: Kind::Request | Kind::Response
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let uri = request.uri();
+        let described = describe(
+            request.method().as_str(),
+            uri.path().as_str(),
+            uri.query().map(|query| query.as_str()),
+            |name| request.headers().get_one(name)
+        );
+        let hub = hub(described, logging::request_id_of(request));
+        request.local_cache(|| RequestHub(hub));
+    }
+
+    async fn on_response<'r>(
+        &self, request: &'r Request<'_>, response: &mut Response<'r>
+    ) {
+        if let Option::Some(hub) = request_hub(request) {
+            let route = request.route()
+                .map(|route| route.uri.path().to_string())
+                .unwrap_or_else(|| String::from("unmatched"));
+            report_response(&hub, response.status(), &route);
+        }
+    }
+}
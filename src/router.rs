@@ -0,0 +1,873 @@
+//! The API as an axum router, for servers built on axum or tower rather
+//! than Rocket. It is built with the `axum` feature, and served if the
+//! `AXUM_ADDRESS` environment variable is set to the address to listen on,
+//! such as `0.0.0.0:8001`.
+//!
+//! Each route calls the same function in `api` as its Rocket route, with
+//! the same body and attacker limits, rate limits, API keys, errors,
+//! version prefixes, request logs and metrics, and shares Rocket's
+//! statistics, result cache, jobs and rate limits. GraphQL, WebSockets and
+//! Discord interactions are only served by Rocket, as are CORS, compression
+//! and MessagePack responses, which tower has layers for.
+use std::cell::RefCell;
+use std::env;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, MatchedPath, Path, Request, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use rocket::form::{Form, FromForm};
+use rocket::futures::{StreamExt, stream};
+use rocket::http::{ContentType, Status};
+use rocket::http::uri::Origin;
+use rocket::tokio::{self, net::TcpListener, time};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tracing::Instrument;
+
+use crate::error::ApiError;
+use crate::{
+    api, auth, cache, calc, engagement, etag, jobs, limits, logging,
+    metrics, openapi, ratelimit, rules, simulate, stats, units, versions
+};
+
+
+/// Where to serve the router.
+pub struct AxumServer {
+    address: SocketAddr
+}
+
+impl AxumServer {
+    /// Read the address to listen on from the `AXUM_ADDRESS` environment
+    /// variable. Returns `None` if it is not set, and panics if it is not a
+    /// valid address, so that a mistake isn't missed.
+    pub fn from_env() -> Option<AxumServer> {
+        let address = env::var("AXUM_ADDRESS").ok()?;
+        let address = address.parse().unwrap_or_else(|_| panic!(
+            "AXUM_ADDRESS must be an address such as 0.0.0.0:8001, not \
+            '{}'.",
+            address
+        ));
+        Option::Some(AxumServer { address })
+    }
+
+    /// Start serving in a task of its own. This must be called from within
+    /// Rocket's runtime.
+    pub fn spawn(self, shared: Shared) {
+        tracing::info!(
+            address = %self.address, "Listening for requests with axum."
+        );
+        tokio::spawn(async move {
+            let served = match TcpListener::bind(self.address).await {
+                Ok(listener) => axum::serve(
+                    listener,
+                    router(shared)
+                        .into_make_service_with_connect_info::<SocketAddr>()
+                ).await,
+                Err(error) => Err(error)
+            };
+            if let Err(error) = served {
+                tracing::error!(%error, "The axum server stopped.");
+            }
+        });
+    }
+}
+
+
+/// What the routes share with each other, and with Rocket. Clones share
+/// the same state.
+#[derive(Clone)]
+pub struct Shared {
+    pub stats: stats::MatchupStats,
+    pub cache: cache::ResultCache,
+    pub jobs: jobs::Jobs,
+    pub keys: auth::ApiKeys,
+    pub limiter: ratelimit::RateLimiter
+}
+
+
+/// Every route, as served by Rocket. Clients are only rate limited if the
+/// router is served with `ConnectInfo<SocketAddr>`, as `AxumServer` does.
+pub fn router(shared: Shared) -> Router {
+    let router = Router::new()
+        .nest("/v1", v1_routes())
+        .merge(v1_routes())
+        .route("/healthz", get(health_check))
+        .route("/metrics", get(get_metrics))
+        .route("/version", get(build_info))
+        .route("/openapi.json", get(openapi_document))
+        .route("/docs", get(api_docs))
+        .fallback(not_found)
+        .method_not_allowed_fallback(not_found)
+        .layer(middleware::from_fn(envelope))
+        .layer(middleware::from_fn(count));
+    // Within the logs, so that the request ID has been chosen.
+    #[cfg(feature = "sentry")]
+    let router = router.layer(middleware::from_fn(report));
+    router
+        .layer(middleware::from_fn(log))
+        .with_state(shared)
+}
+
+
+/// The routes of the first version of the API.
+fn v1_routes() -> Router<Shared> {
+    Router::new()
+        .route("/units", get(get_units))
+        .route("/units/search", get(search_units))
+        .route("/units/{id}", get(get_unit))
+        .route("/units/{id}/upgrades", get(get_upgrades))
+        .route("/abilities", get(get_abilities))
+        .route("/limits", get(get_limits))
+        .route("/battle", get(link_battle).post(calc_battle))
+        .route("/battle/batch", post(calc_battles))
+        .route("/optim", post(optimise_battle))
+        .route("/optim/jobs", post(start_optim_job))
+        .route(
+            "/optim/jobs/{id}", get(get_optim_job).delete(cancel_optim_job)
+        )
+        .route("/optim/jobs/{id}/events", get(optim_job_events))
+        .route("/assign", post(assign_battle))
+        .route("/simulate", post(simulate_battle))
+        .route("/damage", post(preview_damage))
+        .route("/kill-threshold", post(kill_threshold))
+        .route("/survive", post(survival_thresholds))
+        .route("/initiative", post(calc_initiative))
+        .route("/engagement", post(calc_engagement))
+        .route("/formula", get(damage_formula))
+        .route("/matchup", get(matchup_table))
+        .route("/stats/popular", get(popular_matchups))
+        .route("/admin/stats/popular", delete(reset_matchups))
+}
+
+
+/// Why a request was refused, and how many seconds to wait before trying
+/// again if it was rate limited.
+struct Refused {
+    error: ApiError,
+    retry_after: Option<u64>
+}
+
+impl From<ApiError> for Refused {
+    fn from(error: ApiError) -> Refused {
+        Refused { error, retry_after: Option::None }
+    }
+}
+
+impl IntoResponse for Refused {
+    fn into_response(self) -> Response {
+        let mut response = error_response(&self.error);
+        if let Option::Some(retry_after) = self.retry_after {
+            response.headers_mut().insert(
+                header::RETRY_AFTER, HeaderValue::from(retry_after)
+            );
+        }
+        response
+    }
+}
+
+
+type Handled = Result<Response, Refused>;
+
+
+fn error_response(error: &ApiError) -> Response {
+    note(|noted| {
+        noted.error.get_or_insert(error.code);
+    });
+    let status = StatusCode::from_u16(error.status.code)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = json_response(&error.to_json());
+    *response.status_mut() = status;
+    response
+}
+
+
+fn json_response(value: &Value) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        value.to_string()
+    ).into_response()
+}
+
+
+fn respond(result: Result<Value, ApiError>) -> Handled {
+    Ok(json_response(&result?))
+}
+
+
+/// Take a token from the client's bucket for a route, by its path as in
+/// the Rocket route, as `RateLimited` does.
+fn limit(shared: &Shared, parts: &Parts, route: &str) -> Result<(), Refused> {
+    let client = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        Option::Some(ConnectInfo(address)) => address.ip(),
+        Option::None => return Ok(())
+    };
+    shared.limiter.take(client, route).map_err(|wait| {
+        let retry_after = ratelimit::retry_after(wait);
+        Refused {
+            error: ratelimit::limit_error(retry_after),
+            retry_after: Option::Some(retry_after)
+        }
+    })
+}
+
+
+/// Rate limit a request, then check its API key, as `Authorised` does.
+fn authorise(
+    shared: &Shared, parts: &Parts, route: &str
+) -> Result<(), Refused> {
+    limit(shared, parts, route)?;
+    let header = |name| parts.headers.get(name)
+        .and_then(|value| value.to_str().ok());
+    let key = auth::header_key(
+        header("X-API-Key"), header(header::AUTHORIZATION.as_str())
+    );
+    shared.keys.check(key).map_err(|status| auth::key_error(status).into())
+}
+
+
+/// Read a request's query string as Rocket reads a route's query
+/// parameters. Queries which can't be read are rejected as Rocket rejects
+/// them, with 422 Unprocessable Entity.
+fn query<T: for<'a> FromForm<'a> + 'static>(
+    parts: &Parts
+) -> Result<T, ApiError> {
+    let query = parts.uri.query().unwrap_or_default();
+    Form::<T>::parse_encoded(query.into())
+        .map_err(|_| api::invalid_body())
+}
+
+
+/// Read a request body as `JsonBody` does.
+async fn body<T: DeserializeOwned>(
+    parts: &Parts, body: Body
+) -> Result<T, ApiError> {
+    let content_type = parts.headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ContentType::parse_flexible);
+    let msgpack = match content_type.as_ref().and_then(limits::reads_msgpack) {
+        Option::Some(msgpack) => msgpack,
+        Option::None => return Err(
+            api::not_found(parts.method.as_str(), parts.uri.path())
+        )
+    };
+    let limit = *limits::MAX_BODY_BYTES;
+    let length = parts.headers.get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return Err(api::body_too_large());
+    }
+    let mut chunks = body.into_data_stream();
+    let mut bytes = vec![];
+    while let Option::Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|_| api::malformed_request())?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(api::body_too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    note(|noted| noted.attackers = logging::attackers(&bytes, msgpack));
+    #[cfg(feature = "sentry")]
+    crate::reporting::note_current_body(&bytes, msgpack);
+    limits::read_body(&bytes, msgpack).map_err(|(status, _)| {
+        if status.code == StatusCode::UNPROCESSABLE_ENTITY.as_u16() {
+            api::invalid_body()
+        } else {
+            api::malformed_request()
+        }
+    })
+}
+
+
+/// Parse a job ID from a path, rejecting IDs which aren't numbers as
+/// Rocket does, with 422 Unprocessable Entity.
+fn job_id(id: &str) -> Result<u64, ApiError> {
+    id.parse().map_err(|_| api::invalid_body())
+}
+
+
+/// Respond with an entity tag for the unit list, as `etag::Tagged` does.
+fn tagged(parts: &Parts, value: Value) -> Response {
+    let tag = &units::UNIT_LIST.hash;
+    let sent = parts.headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok());
+    let mut response = if etag::matches(tag, sent) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        json_response(&value)
+    };
+    let headers = response.headers_mut();
+    if let Ok(tag) = HeaderValue::from_str(&etag::header(tag)) {
+        headers.insert(header::ETAG, tag);
+    }
+    if let Ok(cache_control) = HeaderValue::from_str(&etag::cache_control()) {
+        headers.insert(header::CACHE_CONTROL, cache_control);
+    }
+    response
+}
+
+
+/// Wrap JSON responses to requests under a version prefix, as Rocket's
+/// envelope does.
+async fn envelope(request: Request, next: Next) -> Response {
+    let prefix = request.uri().path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let version = crate::VERSIONS.iter()
+        .find(|version| version.name == prefix)
+        .map(|version| version.name);
+    let response = next.run(request).await;
+    let version = match version {
+        Option::Some(version) => version,
+        Option::None => return response
+    };
+    let is_json = response.headers().get(header::CONTENT_TYPE)
+        .is_some_and(|kind| kind == "application/json");
+    if !is_json {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(_) => return error_response(&api::internal_error())
+    };
+    let body = match String::from_utf8(body.to_vec()) {
+        Ok(body) => versions::wrap(version, body),
+        Err(error) => return Response::from_parts(
+            parts, Body::from(error.into_bytes())
+        )
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+
+/// The route which a request matched, written as Rocket writes it, such as
+/// `/v1/units/<id>`, so that both servers log and count it the same way.
+fn route_of(request: &Request) -> Option<String> {
+    request.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().replace('{', "<").replace('}', ">"))
+}
+
+
+/// Count and time requests by the route which handled them.
+async fn count(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let route = route_of(&request)
+        .unwrap_or_else(|| String::from("unmatched"));
+    let response = next.run(request).await;
+    metrics::record_request(
+        method.as_str(), &route, response.status().as_u16(),
+        started.elapsed()
+    );
+    response
+}
+
+
+/// What a route noted about a request while handling it, for its log.
+#[derive(Default)]
+struct Noted {
+    attackers: Option<usize>,
+    error: Option<&'static str>
+}
+
+
+tokio::task_local! {
+    /// What has been noted about the request being handled by this task.
+    static NOTED: RefCell<Noted>;
+}
+
+
+/// Note something about the request being handled, if it is being logged.
+fn note(noting: impl FnOnce(&mut Noted)) {
+    let _ = NOTED.try_with(|noted| noting(&mut noted.borrow_mut()));
+}
+
+
+/// The ID given to a request, for its reports.
+#[cfg(feature = "sentry")]
+#[derive(Clone)]
+struct RequestId(String);
+
+
+/// Give each request an ID, handle it in its span, and log it once it is
+/// answered, as `logging::RequestLog` does.
+#[cfg_attr(not(feature = "sentry"), allow(unused_mut))]
+async fn log(mut request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let id = logging::request_id(
+        request.headers().get("X-Request-Id")
+            .and_then(|id| id.to_str().ok())
+    );
+    #[cfg(feature = "sentry")]
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let route = route_of(&request);
+    let span = logging::span(&id, method.as_str(), &path);
+    let (mut response, noted) = NOTED.scope(
+        RefCell::new(Noted::default()),
+        async {
+            let response = next.run(request).await;
+            (response, NOTED.with(RefCell::take))
+        }.instrument(span.clone())
+    ).await;
+    if let Ok(header) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("X-Request-Id", header);
+    }
+    logging::log_answered(&span, &logging::Answered {
+        id: &id,
+        method: method.as_str(),
+        path: &path,
+        route: route.as_deref(),
+        status: Status::new(response.status().as_u16()),
+        elapsed: started.elapsed(),
+        attackers: noted.attackers,
+        error: noted.error
+    });
+    response
+}
+
+
+/// Handle each request with a hub of its own, and report responses with
+/// server errors, as `reporting::Reporting` does.
+#[cfg(feature = "sentry")]
+async fn report(request: Request, next: Next) -> Response {
+    use sentry::SentryFutureExt;
+
+    let header = |name: &str| request.headers().get(name)?.to_str().ok();
+    let described = crate::reporting::describe(
+        request.method().as_str(),
+        request.uri().path(),
+        request.uri().query(),
+        header
+    );
+    let id = request.extensions().get::<RequestId>()
+        .map_or("", |id| id.0.as_str());
+    let hub = match crate::reporting::hub(described, id) {
+        Option::Some(hub) => hub,
+        Option::None => return next.run(request).await
+    };
+    let route = route_of(&request)
+        .unwrap_or_else(|| String::from("unmatched"));
+    let response = next.run(request).bind_hub(hub.clone()).await;
+    crate::reporting::report_response(
+        &hub, Status::new(response.status().as_u16()), &route
+    );
+    response
+}
+
+
+/// How to handle unknown unit IDs, as given in the query.
+#[derive(FromForm)]
+struct UnknownQuery {
+    on_unknown: Option<calc::OnUnknown>,
+    default_unit: Option<String>
+}
+
+
+#[derive(FromForm)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>
+}
+
+
+#[derive(FromForm)]
+struct FormulaQuery {
+    attack_force: f32,
+    defence_force: f32,
+    attack: f32,
+    defence: f32
+}
+
+
+#[derive(FromForm)]
+struct MatchupQuery {
+    units: Option<String>,
+    defence: Option<calc::DefenceBonus>
+}
+
+
+#[derive(FromForm)]
+struct LimitQuery {
+    limit: Option<usize>
+}
+
+
+async fn get_units(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/units")?;
+    let filter: units::UnitFilter = query(&parts)?;
+    Ok(tagged(&parts, api::units(&filter)))
+}
+
+
+async fn search_units(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    // As in Rocket, a search without a query is taken as a unit ID.
+    let search: SearchQuery = match query(&parts) {
+        Ok(search) => search,
+        Err(_) => {
+            limit(&shared, &parts, "/units/<id>")?;
+            return Ok(tagged(&parts, api::unit("search")?));
+        }
+    };
+    limit(&shared, &parts, "/units/search")?;
+    respond(Ok(api::search_units(&search.q, search.limit)))
+}
+
+
+async fn get_unit(
+    State(shared): State<Shared>, Path(id): Path<String>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/units/<id>")?;
+    Ok(tagged(&parts, api::unit(&id)?))
+}
+
+
+async fn get_upgrades(
+    State(shared): State<Shared>, Path(id): Path<String>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/units/<id>/upgrades")?;
+    respond(api::upgrades(&id))
+}
+
+
+async fn get_limits(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/limits")?;
+    respond(Ok(limits::to_json()))
+}
+
+
+async fn get_abilities(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/abilities")?;
+    respond(Ok(api::abilities()))
+}
+
+
+async fn calc_battle(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/battle")?;
+    let unknown: UnknownQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let input: calc::ExplainInput = body(&parts, data).await?;
+    let rules = rules.to_ruleset().map_err(ApiError::from)?;
+    respond(api::battle(
+        &input, unknown.on_unknown, &unknown.default_unit, &rules,
+        &shared.stats
+    ))
+}
+
+
+async fn link_battle(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/battle")?;
+    let unknown: UnknownQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let rules = rules.to_ruleset().map_err(ApiError::from)?;
+    let uri = format!("/?{}", parts.uri.query().unwrap_or_default());
+    let uri = Origin::parse(&uri).map_err(|_| api::malformed_request())?;
+    let params = uri.query().into_iter().flat_map(|query| query.segments());
+    respond(api::link_battle(
+        params, unknown.on_unknown, &unknown.default_unit, &rules,
+        &shared.stats
+    ))
+}
+
+
+async fn calc_battles(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    authorise(&shared, &parts, "/battle/batch")?;
+    let unknown: UnknownQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let inputs: Vec<calc::ExplainInput> = body(&parts, data).await?;
+    respond(api::batch(
+        inputs, unknown.on_unknown, unknown.default_unit, &rules,
+        &shared.stats
+    ).await)
+}
+
+
+async fn optimise_battle(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    authorise(&shared, &parts, "/optim")?;
+    let unknown: UnknownQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let input: calc::OptimInput = body(&parts, data).await?;
+    respond(api::blocking(move || api::optimise(
+        &input, unknown.on_unknown, &unknown.default_unit, &rules,
+        &shared.stats, &shared.cache
+    )).await)
+}
+
+
+async fn start_optim_job(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    authorise(&shared, &parts, "/optim/jobs")?;
+    let unknown: UnknownQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let input: calc::OptimInput = body(&parts, data).await?;
+    let mut response = respond(api::start_job(
+        &input, unknown.on_unknown, &unknown.default_unit, &rules,
+        &shared.stats, &shared.jobs
+    ))?;
+    *response.status_mut() = StatusCode::ACCEPTED;
+    Ok(response)
+}
+
+
+async fn get_optim_job(
+    State(shared): State<Shared>, Path(id): Path<String>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    let id = job_id(&id)?;
+    authorise(&shared, &parts, "/optim/jobs/<id>")?;
+    respond(api::job_status(id, &shared.jobs))
+}
+
+
+/// A job's progress as server-sent events, as from `jobs::events`.
+async fn optim_job_events(
+    State(shared): State<Shared>, Path(id): Path<String>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    let id = job_id(&id)?;
+    authorise(&shared, &parts, "/optim/jobs/<id>/events")?;
+    api::job_status(id, &shared.jobs)?;
+    let events = stream::unfold(
+        (shared.jobs, Option::None, false),
+        move |(jobs, mut last, finished)| async move {
+            if finished {
+                return Option::None;
+            }
+            loop {
+                if let Option::Some((name, status)) = jobs::next_event(
+                    &jobs, id, &mut last
+                ) {
+                    let event = Event::default()
+                        .event(name)
+                        .data(status.to_string());
+                    let finished = name != "progress";
+                    return Option::Some((
+                        Ok::<_, std::convert::Infallible>(event),
+                        (jobs, last, finished)
+                    ));
+                }
+                time::sleep(jobs::EVENT_INTERVAL).await;
+            }
+        }
+    );
+    let keep_alive = KeepAlive::new().interval(jobs::KEEP_ALIVE);
+    Ok(Sse::new(events).keep_alive(keep_alive).into_response())
+}
+
+
+async fn cancel_optim_job(
+    State(shared): State<Shared>, Path(id): Path<String>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    let id = job_id(&id)?;
+    authorise(&shared, &parts, "/optim/jobs/<id>")?;
+    api::cancel_job(id, &shared.jobs)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+
+async fn assign_battle(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/assign")?;
+    let unknown: UnknownQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let units: calc::BattleInput = body(&parts, data).await?;
+    respond(api::assign(
+        &units, unknown.on_unknown, &unknown.default_unit, &rules
+    ))
+}
+
+
+async fn simulate_battle(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/simulate")?;
+    let unknown: UnknownQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let input: simulate::SimulateInput = body(&parts, data).await?;
+    respond(api::simulate(
+        &input, unknown.on_unknown, &unknown.default_unit, &rules,
+        &shared.stats
+    ))
+}
+
+
+async fn preview_damage(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/damage")?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let units: calc::DamageInput = body(&parts, data).await?;
+    respond(api::damage(&units, &rules))
+}
+
+
+async fn kill_threshold(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/kill-threshold")?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let input: calc::KillThresholdInput = body(&parts, data).await?;
+    respond(api::kill_threshold(&input, &rules))
+}
+
+
+async fn survival_thresholds(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/survive")?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let input: calc::BattleInput = body(&parts, data).await?;
+    respond(api::survive(&input, &rules))
+}
+
+
+async fn calc_initiative(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/initiative")?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let units: calc::InitiativeInput = body(&parts, data).await?;
+    respond(api::initiative(&units, &rules))
+}
+
+
+async fn calc_engagement(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, data) = request.into_parts();
+    limit(&shared, &parts, "/engagement")?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    let units: engagement::EngagementInput = body(&parts, data).await?;
+    respond(api::engagement(&units, &rules))
+}
+
+
+async fn damage_formula(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/formula")?;
+    let forces: FormulaQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    respond(api::formula(
+        forces.attack_force, forces.defence_force, forces.attack,
+        forces.defence, &rules
+    ))
+}
+
+
+async fn matchup_table(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/matchup")?;
+    let matchup: MatchupQuery = query(&parts)?;
+    let rules: rules::RulesQuery = query(&parts)?;
+    respond(api::matchup(matchup.units.as_deref(), matchup.defence, &rules))
+}
+
+
+async fn popular_matchups(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    limit(&shared, &parts, "/stats/popular")?;
+    let limited: LimitQuery = query(&parts)?;
+    respond(Ok(api::popular(limited.limit, &shared.stats)))
+}
+
+
+async fn reset_matchups(
+    State(shared): State<Shared>, request: Request
+) -> Handled {
+    let (parts, _) = request.into_parts();
+    authorise(&shared, &parts, "/admin/stats/popular")?;
+    shared.stats.reset();
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+
+async fn health_check() -> Handled {
+    respond(api::health())
+}
+
+
+async fn get_metrics(State(shared): State<Shared>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        api::metrics(&shared.jobs)
+    ).into_response()
+}
+
+
+async fn build_info() -> Handled {
+    respond(Ok(api::build_info(crate::VERSIONS)))
+}
+
+
+async fn openapi_document() -> Handled {
+    respond(Ok(openapi::document()))
+}
+
+
+async fn api_docs() -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        include_str!("docs.html")
+    ).into_response()
+}
+
+
+async fn not_found(request: Request) -> Response {
+    error_response(&api::not_found(
+        request.method().as_str(), request.uri().path()
+    ))
+}
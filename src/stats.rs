@@ -0,0 +1,75 @@
+//! In-memory statistics about how the API is used.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::calc;
+use serde::Serialize;
+
+
+/// The number of times an attacker has been requested against a defender.
+#[derive(Serialize)]
+pub struct MatchupCount {
+    pub attacker: String,
+    pub defender: String,
+    pub count: u64
+}
+
+
+/// Counters for how often each attacker/defender matchup is requested.
+/// Shared between request threads, so all access goes through a lock.
+/// Clones share the same counters.
+#[derive(Clone, Default)]
+pub struct MatchupStats {
+    counts: Arc<Mutex<HashMap<(String, String), u64>>>
+}
+
+impl MatchupStats {
+    /// Count each distinct attacker in a battle against the defender it
+    /// targets. The units are counted by the type they were resolved to,
+    /// so unknown units which were skipped aren't counted, and those which
+    /// were replaced are counted as their replacement.
+    pub fn record(&self, state: &calc::BattleState) {
+        let mut counts = self.counts.lock().unwrap();
+        let mut seen: Vec<(&String, &String)> = vec![];
+        for attacker in state.attackers.iter() {
+            let defender = if attacker.target == 0 {
+                &state.defender.id
+            } else {
+                match state.defenders.get(attacker.target - 1) {
+                    Option::Some(defender) => &defender.id,
+                    Option::None => continue
+                }
+            };
+            if seen.contains(&(&attacker.id, defender)) {
+                continue;
+            }
+            seen.push((&attacker.id, defender));
+            let key = (attacker.id.clone(), defender.clone());
+            *counts.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    /// Get the most requested matchups, most popular first.
+    pub fn top(&self, limit: usize) -> Vec<MatchupCount> {
+        let counts = self.counts.lock().unwrap();
+        let mut matchups: Vec<MatchupCount> = counts.iter().map(
+            |((attacker, defender), count)| MatchupCount {
+                attacker: attacker.clone(),
+                defender: defender.clone(),
+                count: *count
+            }
+        ).collect();
+        matchups.sort_by(|a, b| {
+            b.count.cmp(&a.count)
+                .then_with(|| a.attacker.cmp(&b.attacker))
+                .then_with(|| a.defender.cmp(&b.defender))
+        });
+        matchups.truncate(limit);
+        matchups
+    }
+
+    /// Clear all the counters.
+    pub fn reset(&self) {
+        self.counts.lock().unwrap().clear();
+    }
+}
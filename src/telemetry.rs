@@ -0,0 +1,49 @@
+//! Traces of requests, exported by OTLP to a tracing backend, so that slow
+//! requests can be broken down into their parts: reading the body, each
+//! battle calculated, and each search for an order of attack. It is built
+//! with the `otel` feature, and traces are exported if the
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` environment variable is set, as with
+//! other OpenTelemetry services. They are sent over HTTP, and the other
+//! standard `OTEL_` variables, such as `OTEL_SERVICE_NAME`, are read too.
+use std::env;
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::Subscriber;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+
+/// The service traces are from if `OTEL_SERVICE_NAME` is not set.
+const DEFAULT_SERVICE_NAME: &str = "polycalc-api";
+
+
+/// A layer sending spans to the OTLP endpoint, if one is set. Panics if
+/// the exporter can't be set up, so that a mistake isn't missed.
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>
+{
+    env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .unwrap_or_else(
+            |error| panic!("Could not set up trace exports: {}", error)
+        );
+    let mut resource = Resource::builder();
+    if env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name(DEFAULT_SERVICE_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer(DEFAULT_SERVICE_NAME);
+    // Kept globally, so that spans are exported for as long as the server
+    // runs.
+    opentelemetry::global::set_tracer_provider(provider);
+    Option::Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
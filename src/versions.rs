@@ -0,0 +1,94 @@
+//! Versions of the API. Each version is mounted under its own prefix, such
+//! as `/v1`, and the current one is also mounted without a prefix for
+//! clients from before versioning. Responses under a prefix are wrapped in
+//! an envelope which gives the version, so that clients can tell which one
+//! answered them.
+use std::io::Cursor;
+
+use rocket::{Build, Request, Response, Rocket, Route};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+
+
+/// A version of the API, and the routes it is made of.
+pub struct Version {
+    pub name: &'static str,
+    pub routes: fn() -> Vec<Route>
+}
+
+
+/// Mount every version under its prefix, with the last also mounted without
+/// one, and wrap the responses of the versioned routes.
+pub fn mount(
+    rocket: Rocket<Build>, versions: &'static [Version]
+) -> Rocket<Build> {
+    let mut rocket = rocket.attach(Envelope { versions });
+    for version in versions.iter() {
+        rocket = rocket.mount(
+            format!("/{}", version.name), (version.routes)()
+        );
+    }
+    match versions.last() {
+        Option::Some(current) => rocket.mount("/", (current.routes)()),
+        Option::None => rocket
+    }
+}
+
+
+/// Wraps JSON responses to requests under a version prefix as
+/// `{"version": ..., "data": ...}`.
+struct Envelope {
+    versions: &'static [Version]
+}
+
+impl Envelope {
+    /// The version a request path is under, if any.
+    fn version_of(&self, path: &str) -> Option<&'static str> {
+        let prefix = path.trim_start_matches('/').split('/').next()?;
+        self.versions.iter()
+            .find(|version| version.name == prefix)
+            .map(|version| version.name)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Envelope {
+    fn info(&self) -> Info {
+        Info {
+            name: "API version envelope",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(
+        &self, request: &'r Request<'_>, response: &mut Response<'r>
+    ) {
+        let path = request.uri().path();
+        let version = match self.version_of(path.as_str()) {
+            Option::Some(version) => version,
+            Option::None => return
+        };
+        if response.content_type() != Option::Some(ContentType::JSON) {
+            return;
+        }
+        let body = match response.body_mut().to_string().await {
+            Ok(body) => body,
+            Err(_) => return
+        };
+        let wrapped = wrap(version, body);
+        response.set_sized_body(wrapped.len(), Cursor::new(wrapped));
+    }
+}
+
+
+/// Wrap a JSON response body in the envelope for a version. Bodies which
+/// aren't JSON are left as they are.
+pub fn wrap(version: &str, body: String) -> String {
+    match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(data) => json!({
+            "version": version,
+            "data": data
+        }).to_string(),
+        Err(_) => body
+    }
+}
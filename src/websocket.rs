@@ -0,0 +1,126 @@
+//! Background optimisations followed over WebSockets, so that a UI can show
+//! a live progress bar for a long search. It is built with the `websocket`
+//! feature.
+//!
+//! A job started with `POST /optim/jobs` is followed by connecting to
+//! `/optim/jobs/<id>/ws`. Its status, as from `GET /optim/jobs/<id>`, is
+//! sent as a text message whenever it changes, with the permutations tried
+//! and best order found so far while it runs. The last message has the
+//! result, or says that the job was cancelled, and the connection is then
+//! closed. The job's API key may be given in the `key` query parameter, as
+//! browsers can't send headers with WebSockets.
+use std::io;
+use std::pin::Pin;
+use std::time::Instant;
+
+use rocket::{Request, State};
+use rocket::data::{IoHandler, IoStream};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::time;
+use serde_json::Value;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use crate::error::ApiError;
+use crate::{auth, jobs, ratelimit};
+
+
+/// A job to follow, once the connection has been upgraded.
+pub struct JobSocket {
+    jobs: jobs::Jobs,
+    id: u64
+}
+
+impl<'r> Responder<'r, 'static> for JobSocket {
+    fn respond_to(self, request: &'r Request) -> response::Result<'static> {
+        let key = match request.headers().get_one("Sec-WebSocket-Key") {
+            Option::Some(key) => key,
+            Option::None => return ApiError::new(
+                Status::UpgradeRequired, "upgrade_required",
+                "Connect to this route with a WebSocket."
+            ).respond_to(request)
+        };
+        Response::build()
+            .raw_header("Sec-WebSocket-Accept", derive_accept_key(
+                key.as_bytes()
+            ))
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for JobSocket {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let mut socket = WebSocketStream::from_raw_socket(
+            io, Role::Server, Option::None
+        ).await;
+        // The client may have gone away, which there is no one to tell
+        // about.
+        let _ = send_statuses(&mut socket, &self.jobs, self.id).await;
+        Ok(())
+    }
+}
+
+
+/// Send the status of a job whenever it changes, and a ping if nothing has
+/// been sent for a while, until the job is over or the client closes the
+/// connection.
+async fn send_statuses(
+    socket: &mut WebSocketStream<IoStream>, jobs: &jobs::Jobs, id: u64
+) -> Result<(), Error> {
+    let mut last: Option<Value> = Option::None;
+    let mut last_sent = Instant::now();
+    loop {
+        let status = jobs.status(id)
+            .unwrap_or_else(|| json!({"id": id, "status": "cancelled"}));
+        if last.as_ref() != Option::Some(&status) {
+            socket.send(Message::text(status.to_string())).await?;
+            last_sent = Instant::now();
+            if status["status"] == "done" || status["status"] == "cancelled" {
+                return socket.close(Option::None).await;
+            }
+            last = Option::Some(status);
+        } else if last_sent.elapsed() >= jobs::KEEP_ALIVE {
+            socket.send(Message::Ping(Default::default())).await?;
+            last_sent = Instant::now();
+        }
+        // Reading times out, so that the job is checked between messages.
+        match time::timeout(jobs::EVENT_INTERVAL, socket.next()).await {
+            Ok(Option::None) | Ok(Option::Some(Ok(Message::Close(_)))) => {
+                return Ok(());
+            },
+            Ok(Option::Some(Err(error))) => return Err(error),
+            Ok(Option::Some(Ok(_))) | Err(_) => ()
+        }
+    }
+}
+
+
+/// Follow a job over a WebSocket. As for the other job routes, an API key
+/// is needed if any are configured, but it may also be given as `key` in
+/// the query.
+#[get("/optim/jobs/<id>/ws?<key>")]
+pub fn follow_job(
+    id: u64,
+    key: Option<&str>,
+    checked: auth::KeyCheck,
+    keys: &State<auth::ApiKeys>,
+    jobs: &State<jobs::Jobs>,
+    _limited: ratelimit::RateLimited
+) -> Result<JobSocket, ApiError> {
+    checked.0
+        .or_else(|status| match key {
+            Option::Some(key) => keys.check(Option::Some(key.trim())),
+            Option::None => Err(status)
+        })
+        .map_err(auth::key_error)?;
+    match jobs.status(id) {
+        Option::Some(_) => Ok(JobSocket { jobs: jobs.inner().clone(), id }),
+        Option::None => Err(ApiError::from(jobs::UnknownJob(id)))
+    }
+}
@@ -0,0 +1,58 @@
+//! WebAssembly bindings for the calculator, so that web calculators can
+//! run battles in the browser rather than over HTTP. Build them with
+//! `wasm-pack build wasm --target web`.
+//!
+//! Inputs are objects as in the API's request bodies, and results are
+//! objects as in its responses. Errors are thrown with the error as the API
+//! gives it, as JSON, in their message, such as
+//! `{"error":"Unknown unit ID 'foo'."}`. The unit data built in is used
+//! unless `loadUnits` is called first.
+use js_sys::{Error, JSON};
+use polycalc_core::{bindings, units};
+use wasm_bindgen::prelude::*;
+
+
+/// Turn an object into JSON for the bindings.
+fn input(value: &JsValue) -> Result<String, JsValue> {
+    JSON::stringify(value)?
+        .as_string()
+        .ok_or_else(|| Error::new("The input can't be given as JSON.").into())
+}
+
+
+/// Turn a result from the bindings into a JavaScript value, throwing
+/// errors.
+fn output(result: Result<String, String>) -> Result<JsValue, JsValue> {
+    let raw = result.map_err(|error| JsValue::from(Error::new(&error)))?;
+    JSON::parse(&raw)
+}
+
+
+/// Calculate a battle, as `POST /battle`.
+#[wasm_bindgen]
+pub fn battle(value: JsValue) -> Result<JsValue, JsValue> {
+    output(bindings::battle(&input(&value)?))
+}
+
+
+/// Find the best order of attack, or the fewest attackers, as
+/// `POST /optim`.
+#[wasm_bindgen]
+pub fn optimise(value: JsValue) -> Result<JsValue, JsValue> {
+    output(bindings::optimise(&input(&value)?))
+}
+
+
+/// Look up a unit type by ID or alias, as `GET /units/<id>`.
+#[wasm_bindgen]
+pub fn unit(id: &str) -> Result<JsValue, JsValue> {
+    output(bindings::unit(id))
+}
+
+
+/// Use this unit data, as JSON, rather than the copy built in. This must
+/// be called before anything else, and only once.
+#[wasm_bindgen(js_name = loadUnits)]
+pub fn load_units(data: &str) -> Result<(), JsValue> {
+    units::provide_units(data).map_err(|error| Error::new(&error).into())
+}